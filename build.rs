@@ -0,0 +1,31 @@
+use std::env;
+use std::process::Command;
+
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git").args(args).output().ok()
+        .and_then(|out| {
+            if out.status.success() { String::from_utf8(out.stdout).ok() } else { None }
+        })
+        .map(|s| s.trim().to_owned())
+}
+
+// Exposes the git sha and enabled features to `src/build_info.rs`.
+fn main() {
+    let sha = git(&["rev-parse", "--short", "HEAD"]).unwrap_or("unknown".to_owned());
+
+    let mut features: Vec<String> = env::vars()
+        .filter(|&(ref k, _)| k.starts_with("CARGO_FEATURE_"))
+        .map(|(k, _)| k["CARGO_FEATURE_".len()..].to_lowercase().replace('_', "-"))
+        .collect();
+    features.sort();
+
+    println!("cargo:rustc-env=BUILD_GIT_SHA={}", sha);
+    println!("cargo:rustc-env=BUILD_FEATURES={}", features.join(","));
+    // HEAD only changes on a branch switch, a commit moves the branch it
+    // points at.
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    if let Some(branch) = git(&["symbolic-ref", "-q", "HEAD"]) {
+        println!("cargo:rerun-if-changed=.git/{}", branch);
+    }
+    println!("cargo:rerun-if-changed=.git/packed-refs");
+}
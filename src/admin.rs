@@ -0,0 +1,698 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use build_info;
+use build_nodes;
+use config::{millis, Config, ListenerConfig};
+use hash::{self, ConsistentHash, Node, ServerNode};
+use stats::{Stats, CHECK_LATENCY_BUCKETS, KEY_LENGTH_BUCKETS, LATENCY_BUCKETS};
+
+// `stats` holds each listener's counters, keyed by listener name.
+// `shutdown` is the flag SIGTERM sets.
+pub struct Admin {
+    config: Arc<RwLock<Config>>,
+    stats: Vec<(String, Arc<Stats>)>,
+    shutdown: &'static AtomicBool,
+}
+
+// `config` is swapped on reload so /config always shows what is running.
+impl Admin {
+    pub fn new(config: Arc<RwLock<Config>>, stats: Vec<(String, Arc<Stats>)>,
+               shutdown: &'static AtomicBool) -> Admin {
+        Admin {
+            config: config,
+            stats: stats,
+            shutdown: shutdown,
+        }
+    }
+}
+
+pub fn spawn(host: &str, port: u16, admin: Admin) -> io::Result<thread::JoinHandle<()>> {
+    let listener = try!(TcpListener::bind((host, port)));
+    log!(Info, "serving stats at {}:{}", host, port);
+
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = handle(stream, &admin) {
+                        log!(Warn, "stats request failed: {}", e);
+                    }
+                }
+                Err(e) => log!(Warn, "stats accept failed: {}", e),
+            }
+        }
+    }))
+}
+
+fn handle(mut stream: TcpStream, admin: &Admin) -> io::Result<()> {
+    try!(stream.set_read_timeout(Some(Duration::from_secs(5))));
+
+    let mut buf = [0; 4096];
+    let n = try!(stream.read(&mut buf));
+    let request = String::from_utf8_lossy(&buf[0..n]);
+
+    let mut parts = request.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let (path, query) = match parts.next() {
+        Some(p) => match p.find('?') {
+            Some(i) => (&p[0..i], &p[i + 1..]),
+            None => (p, ""),
+        },
+        None => ("", ""),
+    };
+
+    match (method, path) {
+        ("GET", "/config") => respond(&mut stream, "200 OK", &config_json(&admin.config.read().unwrap())),
+        ("GET", "/stats") => respond(&mut stream, "200 OK", &stats_json(&admin.stats)),
+        ("GET", "/route") => match query_param(query, "key") {
+            Some(ref key) if !key.is_empty() => {
+                let body = route_json(&admin.config.read().unwrap(), &admin.stats, key);
+                respond(&mut stream, "200 OK", &body)
+            }
+            _ => respond(&mut stream, "400 Bad Request", "{\"error\":\"missing key\"}"),
+        },
+        ("GET", "/events") => respond(&mut stream, "200 OK", &events_json(&admin.stats)),
+        ("GET", "/unrouted") => respond(&mut stream, "200 OK", &unrouted_json(&admin.stats)),
+        ("GET", "/metrics") => {
+            respond_as(&mut stream, "200 OK", "text/plain; version=0.0.4", &metrics(&admin.stats))
+        }
+        ("GET", "/topkeys") => {
+            let n = query_param(query, "n").and_then(|n| n.parse().ok()).unwrap_or(20);
+            match top_keys_json(&admin.config.read().unwrap(), &admin.stats, n) {
+                Some(body) => respond(&mut stream, "200 OK", &body),
+                None => respond(&mut stream, "404 Not Found",
+                                "{\"error\":\"track_top_keys is off\"}"),
+            }
+        }
+        ("GET", "/healthz") => {
+            // Paused is still healthy, it only tells an operator why nothing arrives.
+            let paused = admin.stats.iter().any(|&(_, ref s)| s.paused.load(Ordering::Relaxed));
+            let up = admin.stats.iter().all(|&(_, ref s)| s.workers.load(Ordering::Relaxed) > 0);
+            let body = match (up, paused) {
+                (true, false) => "{\"status\":\"ok\",\"paused\":false}",
+                (true, true) => "{\"status\":\"ok\",\"paused\":true}",
+                (false, false) => "{\"status\":\"down\",\"paused\":false}",
+                (false, true) => "{\"status\":\"down\",\"paused\":true}",
+            };
+            respond(&mut stream, if up { "200 OK" } else { "503 Service Unavailable" }, body)
+        }
+        ("GET", "/readyz") => {
+            let min = admin.config.read().unwrap().ready_min_nodes;
+            let ready = admin.stats.iter().all(|&(_, ref s)| {
+                s.ring_nodes.load(Ordering::Relaxed) >= min &&
+                    !s.below_min_healthy.load(Ordering::Relaxed)
+            });
+            if ready {
+                respond(&mut stream, "200 OK", "{\"status\":\"ready\"}")
+            } else {
+                respond(&mut stream, "503 Service Unavailable", "{\"status\":\"not ready\"}")
+            }
+        }
+        ("POST", "/shutdown") => {
+            let (enabled, authorized) = {
+                let config = admin.config.read().unwrap();
+                (config.admin_shutdown, authorized(&request, config.admin_token.as_ref()))
+            };
+            if !enabled {
+                respond(&mut stream, "404 Not Found", "{\"error\":\"not found\"}")
+            } else if !authorized {
+                respond(&mut stream, "403 Forbidden", "{\"error\":\"forbidden\"}")
+            } else {
+                log!(Info, "shutdown requested on the stats port");
+                admin.shutdown.store(true, Ordering::SeqCst);
+                respond(&mut stream, "202 Accepted", "{\"status\":\"shutting down\"}")
+            }
+        }
+        ("POST", "/pause") | ("POST", "/resume") => {
+            let (enabled, authorized) = {
+                let config = admin.config.read().unwrap();
+                (config.admin_pause, authorized(&request, config.admin_token.as_ref()))
+            };
+            if !enabled {
+                respond(&mut stream, "404 Not Found", "{\"error\":\"not found\"}")
+            } else if !authorized {
+                respond(&mut stream, "403 Forbidden", "{\"error\":\"forbidden\"}")
+            } else {
+                let paused = path == "/pause";
+                for &(_, ref s) in admin.stats.iter() {
+                    s.paused.store(paused, Ordering::SeqCst);
+                }
+                let state = if paused { "paused" } else { "resumed" };
+                log!(Info, "forwarding {} on the stats port", state);
+                respond(&mut stream, "200 OK", &format!("{{\"paused\":{}}}", paused))
+            }
+        }
+        ("GET", _) => respond(&mut stream, "404 Not Found", "{\"error\":\"not found\"}"),
+        _ => respond(&mut stream, "405 Method Not Allowed",
+                     "{\"error\":\"method not allowed\"}"),
+    }
+}
+
+// Compares every byte so the time taken doesn't tell how much of a guess
+// was right.
+fn authorized(request: &str, token: Option<&String>) -> bool {
+    let token = match token {
+        Some(token) => token.as_bytes(),
+        None => return false,
+    };
+    let given = request.lines()
+        .take_while(|l| !l.is_empty())
+        .filter_map(|l| {
+            let mut parts = l.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if name.eq_ignore_ascii_case("authorization") => {
+                    Some(value.trim())
+                }
+                _ => None,
+            }
+        })
+        .next();
+    let given = match given {
+        Some(v) if v.starts_with("Bearer ") => v["Bearer ".len()..].as_bytes(),
+        _ => return false,
+    };
+
+    given.len() == token.len() &&
+        given.iter().zip(token.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+// The value of `name` in a query string, with `+` and %XX decoded.
+fn query_param(query: &str, name: &str) -> Option<String> {
+    for pair in query.split('&') {
+        let mut kv = pair.splitn(2, '=');
+        if kv.next() != Some(name) {
+            continue;
+        }
+
+        let raw = kv.next().unwrap_or("").as_bytes();
+        let mut out = Vec::with_capacity(raw.len());
+        let mut i = 0;
+        while i < raw.len() {
+            let hex = if raw[i] == b'%' && i + 2 < raw.len() {
+                String::from_utf8(raw[i + 1..i + 3].to_vec()).ok()
+                    .and_then(|h| u8::from_str_radix(&h, 16).ok())
+            } else {
+                None
+            };
+            match (raw[i], hex) {
+                (_, Some(b)) => {
+                    out.push(b);
+                    i += 3;
+                    continue;
+                }
+                (b'+', None) => out.push(b' '),
+                (b, None) => out.push(b),
+            }
+            i += 1;
+        }
+        return Some(String::from_utf8_lossy(&out).into_owned());
+    }
+    None
+}
+
+// The `n` hottest routing keys of every listener tracking them, None when
+// none does. Counts are scaled up by the sample rate, so both they and the
+// error bound are estimates.
+pub fn top_keys_json(config: &Config, stats: &[(String, Arc<Stats>)], n: usize) -> Option<String> {
+    let listeners: Vec<String> = config.listeners.iter()
+        .filter(|l| l.route.track_top_keys)
+        .filter_map(|l| {
+            let stats = match stats.iter().find(|&&(ref name, _)| *name == l.name) {
+                Some(&(_, ref stats)) => stats,
+                None => return None,
+            };
+            let sample = l.route.top_keys_sample;
+            let keys: Vec<String> = stats.top_keys.lock().unwrap().top(n).iter()
+                .map(|&(ref key, ref top)| {
+                    format!("{{\"key\":{},\"count\":{},\"error\":{},\"node\":{}}}",
+                            quote(&String::from_utf8_lossy(key)), top.count * sample,
+                            top.error * sample, quote(&top.node))
+                })
+                .collect();
+            Some(format!("{{\"listener\":{},\"sample\":{},\"keys\":[{}]}}",
+                         quote(&l.name), sample, keys.join(",")))
+        })
+        .collect();
+
+    if listeners.is_empty() {
+        return None;
+    }
+    Some(format!("{{\"listeners\":[{}]}}", listeners.join(",")))
+}
+
+// Which node each listener sends `key` (a metric name or statsd line) to,
+// and the failover nodes after it with `forward_retries`. The rings are
+// rebuilt from the nodes and vnode counts the workers last published, so
+// evictions and degraded or warming up nodes are taken into account.
+// Read-only: nothing is forwarded.
+pub fn route_json(config: &Config, stats: &[(String, Arc<Stats>)], key: &str) -> String {
+    let listeners: Vec<String> = config.listeners.iter().filter_map(|l| {
+        let stats = match stats.iter().find(|&&(ref name, _)| *name == l.name) {
+            Some(&(_, ref stats)) => stats,
+            None => return None,
+        };
+        let mut rings: Vec<ConsistentHash<ServerNode>> = l.route.pools.iter()
+            .map(|_| ConsistentHash::with_vnode_key(l.route.vnode_key.clone()))
+            .collect();
+        let nodes = build_nodes(l);
+        {
+            let published = stats.nodes.lock().unwrap();
+            for node in nodes.iter() {
+                let vnodes = published.get(&node.name())
+                    .map_or(0, |n| n.vnodes.load(Ordering::Relaxed));
+                if vnodes > 0 {
+                    rings[node.pool].add(node, vnodes);
+                }
+            }
+        }
+
+        let describe = |node: &ServerNode| {
+            let name = l.nodes.iter()
+                .find(|n| n.enabled && n.host == node.host && n.port == node.port)
+                .map_or("null".to_owned(), |n| quote(&n.name));
+            format!("{{\"name\":{},\"addr\":{}}}", name, quote(&node.name()))
+        };
+        let (hashed, pool) = match l.route.route_key(key.as_bytes()) {
+            Some(k) => k,
+            None => return None,
+        };
+        let broadcast = l.route.is_broadcast(key.split(l.route.value_delimiter as char)
+                                                 .next()
+                                                 .unwrap_or("")
+                                                 .as_bytes());
+        let owner = l.route.owner(&rings[pool], &hashed);
+        let mut failover = Vec::new();
+        if let Some(owner) = owner {
+            let mut tried = vec![owner.id];
+            while tried.len() <= l.forward.forward_retries {
+                match hash::failover(&rings[pool], &owner.name(), &tried) {
+                    Some(node) => {
+                        tried.push(node.id);
+                        failover.push(describe(node));
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        Some(format!("{{\"listener\":{},\"key\":{},\"pool\":{},\"broadcast\":{},\
+                      \"node\":{},\"failover\":[{}]}}",
+                     quote(&l.name), quote(&String::from_utf8_lossy(&hashed)),
+                     quote(&l.route.pools[pool]), broadcast,
+                     owner.map_or("null".to_owned(), |n| describe(n)), failover.join(",")))
+    }).collect();
+
+    format!("{{\"listeners\":[{}]}}", listeners.join(","))
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    respond_as(stream, status, "application/json", body)
+}
+
+fn respond_as(stream: &mut TcpStream, status: &str, content_type: &str,
+              body: &str) -> io::Result<()> {
+    write!(stream, "HTTP/1.0 {}\r\nContent-Type: {}\r\n\
+                    Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+           status, content_type, body.len(), body)
+}
+
+pub fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Fields are listed explicitly so anything secret added to `Config` later
+// stays out of the dump unless it is deliberately (and redacted) added here.
+fn config_json(config: &Config) -> String {
+    let listeners: Vec<String> = config.listeners.iter().map(listener_json).collect();
+    let token = if config.admin_token.is_some() { "\"<redacted>\"" } else { "null" };
+    let cpus: Vec<String> = config.cpu_affinity.iter().map(|c| c.to_string()).collect();
+    let capture = match config.capture {
+        Some(ref c) => format!("{{\"file\":{},\"max_bytes\":{},\"files\":{},\"max_rate\":{}}}",
+                               quote(&c.path), c.max_bytes, c.files, c.max_rate),
+        None => "null".to_owned(),
+    };
+    format!("{{\"threads\":{},\"cpu_affinity\":[{}],\"ready_min_nodes\":{},\
+             \"admin_shutdown\":{},\"admin_pause\":{},\"admin_token\":{},\"log_format\":{},\
+             \"log_level\":{},\"self_metrics_node\":{},\"self_metrics_prefix\":{},\
+             \"self_metrics_interval\":{},\
+             \"capture\":{},\"listeners\":[{}]}}",
+            config.threads, cpus.join(","), config.ready_min_nodes, config.admin_shutdown,
+            config.admin_pause, token,
+            quote(config.log_format.name()), quote(config.log_level.name()),
+            config.self_metrics_node.as_ref().map_or("null".to_owned(), |n| quote(n)),
+            quote(&config.self_metrics_prefix), millis(config.self_metrics_interval),
+            capture, listeners.join(","))
+}
+
+fn listener_json(config: &ListenerConfig) -> String {
+    let nodes: Vec<String> = config.nodes.iter().map(|n| {
+        let source = match n.source_addr {
+            Some(addr) => quote(&addr.to_string()),
+            None => "null".to_owned(),
+        };
+        let description = match n.description {
+            Some(ref d) => quote(d),
+            None => "null".to_owned(),
+        };
+        let tags: Vec<String> = n.tags.iter().map(|t| quote(t)).collect();
+        format!("{{\"name\":{},\"host\":{},\"port\":{},\"adminport\":{},\
+                 \"health_type\":{},\"weight\":{},\"replicas\":{},\"enabled\":{},\
+                 \"source_addr\":{},\"description\":{},\"pool\":{},\"tags\":[{}],\
+                 \"transport\":{}}}",
+                quote(&n.name), quote(&n.host), n.port, n.adminport,
+                quote(n.health_type.name()), n.weight, n.replicas, n.enabled, source,
+                description, quote(&config.route.pools[n.pool]), tags.join(","),
+                quote(n.transport.name()))
+    }).collect();
+
+    let key_segments = match config.route.key_segments {
+        Some(n) => n.to_string(),
+        None => "null".to_owned(),
+    };
+    let max_outstanding = match config.forward.max_outstanding_packets {
+        Some(n) => n.to_string(),
+        None => "null".to_owned(),
+    };
+
+    let dead_letter = match config.forward.dead_letter_node {
+        Some(ref addr) => quote(addr),
+        None => "null".to_owned(),
+    };
+
+    let source = match config.forward.source_addr {
+        Some(addr) => quote(&addr.to_string()),
+        None => "null".to_owned(),
+    };
+
+    let recv_buffer = match config.listen.recv_buffer {
+        Some(n) => n.to_string(),
+        None => "null".to_owned(),
+    };
+
+    let allow_sources: Vec<String> = config.forward.allow_sources.cidrs.iter()
+        .map(|c| quote(c))
+        .collect();
+
+    let broadcast: Vec<String> = config.route.broadcast_prefixes.iter()
+        .map(|p| quote(&String::from_utf8_lossy(p)))
+        .collect();
+
+    let sample_prefixes: Vec<String> = config.route.counter_sample_prefixes.iter()
+        .map(|&(ref p, factor)| format!("{}:{}", quote(&String::from_utf8_lossy(p)), factor))
+        .collect();
+
+    let rewrite_rules: Vec<String> = config.route.rewrite_rules.iter()
+        .map(|r| format!("{{\"match\":{},\"replace\":{}}}", quote(r.pattern.as_str()),
+                         quote(&String::from_utf8_lossy(&r.replacement))))
+        .collect();
+
+    let pools: Vec<String> = config.route.pools.iter().map(|p| quote(p)).collect();
+    let type_pools: Vec<String> = config.route.type_pools.iter()
+        .map(|&(ref kind, pool)| format!("{}:{}", quote(&String::from_utf8_lossy(kind)),
+                                         quote(&config.route.pools[pool])))
+        .collect();
+
+    let degraded = match config.health.degraded_response {
+        Some(ref p) => quote(&String::from_utf8_lossy(p)),
+        None => "null".to_owned(),
+    };
+
+    let failure_weights: Vec<String> = config.health.failure_weights.iter()
+        .map(|&(kind, weight)| format!("{}:{}", quote(kind.name()), weight))
+        .collect();
+
+    let max_checks = match config.health.max_concurrent_checks {
+        Some(n) => n.to_string(),
+        None => "null".to_owned(),
+    };
+
+    let unix_bind = match config.listen.unix_bind {
+        Some(ref path) => quote(path),
+        None => "null".to_owned(),
+    };
+
+    let (scoring, evict_below, readmit_at) = match config.health.score_eviction {
+        Some((below, at)) => ("ewma", below.to_string(), at.to_string()),
+        None => ("counts", "null".to_owned(), "null".to_owned()),
+    };
+
+    format!("{{\"name\":{},\"bind\":{},\"replicas\":{},\
+             \"listen\":{{\"unix_bind\":{},\"recv_buffer\":{},\"force_recv_buffer\":{},\
+             \"level_triggered\":{},\"reuse_port\":{}}},\
+             \"health\":{{\"enabled\":{},\"probe_interval\":{},\"probe_jitter\":{},\
+             \"eval_interval\":{},\
+             \"failure_threshold\":{},\"failure_window\":{},\"success_threshold\":{},\
+             \"degraded_response\":{},\"degraded_weight\":{},\
+             \"max_concurrent_health_checks\":{},\"failure_weights\":{{{}}},\
+             \"warmup_duration\":{},\"health_scoring\":{},\"ewma_alpha\":{},\
+             \"ewma_evict_below\":{},\"ewma_readmit_above\":{},\"min_healthy_fraction\":{}}},\
+             \"route\":{{\"route_mode\":{},\"route_key_segments\":{},\
+             \"route_key_delimiter\":{},\"metric_prefix\":{},\"broadcast_prefixes\":[{}],\
+             \"pools\":[{}],\"type_pools\":{{{}}},\"key_delimiter\":{},\"sanitize_keys\":{},\
+             \"sanitize_replacement\":{},\"binary_key_policy\":{},\
+             \"dirty_key_check\":{},\"dirty_key_sample\":{},\"hash_salt\":{},\"vnode_key\":{},\
+             \"counter_sample_factor\":{},\"counter_sample_prefixes\":{{{}}},\
+             \"rewrite_rules\":[{}],\"rewrite_first_match\":{},\"trace_timestamps\":{},\
+             \"track_top_keys\":{},\"top_keys_size\":{},\"top_keys_sample\":{}}},\
+             \"forward\":{{\"retry_queue_size\":{},\"overflow_policy\":{},\
+             \"max_outstanding_packets\":{},\"drain_timeout\":{},\"latency_sample\":{},\
+             \"forward_seq\":{},\"backend_mtu\":{},\"mtu_discover\":{},\"forward_retries\":{},\
+             \"pause_buffer\":{},\"forward_checksum\":{},\"transparent\":{},\
+             \"dead_letter_node\":{},\"source_addr\":{},\"allow_sources\":[{}]}},\
+             \"nodes\":[{}]}}",
+            quote(&config.name), config.bind, config.replicas,
+            unix_bind, recv_buffer, config.listen.force_recv_buffer, config.listen.level_triggered,
+            config.listen.reuse_port,
+            config.health.enabled, config.health.probe_interval,
+            config.health.probe_jitter, config.health.eval_interval,
+            config.health.failure_threshold, millis(config.health.failure_window),
+            config.health.success_threshold, degraded, config.health.degraded_weight,
+            max_checks, failure_weights.join(","), millis(config.health.warmup),
+            quote(scoring), config.health.score_alpha, evict_below, readmit_at,
+            config.health.min_healthy_fraction.map_or("null".to_owned(), |f| f.to_string()),
+            quote(config.route.mode.name()), key_segments,
+            quote(&(config.route.key_delimiter as char).to_string()),
+            quote(&String::from_utf8_lossy(&config.route.prefix)), broadcast.join(","),
+            pools.join(","), type_pools.join(","),
+            quote(&(config.route.value_delimiter as char).to_string()),
+            quote(config.route.sanitize.name()),
+            quote(&(config.route.sanitize_replacement as char).to_string()),
+            quote(config.route.binary_keys.name()),
+            config.route.dirty_key_check, config.route.dirty_key_sample,
+            quote(&String::from_utf8_lossy(&config.route.hash_salt)),
+            quote(config.route.vnode_key.format()),
+            config.route.counter_sample_factor, sample_prefixes.join(","),
+            rewrite_rules.join(","), config.route.rewrite_first_match,
+            config.route.trace_timestamps,
+            config.route.track_top_keys, config.route.top_keys_size, config.route.top_keys_sample,
+            config.forward.retry_queue_size,
+            quote(config.forward.overflow_policy.name()),
+            max_outstanding, millis(config.forward.drain_timeout), config.forward.latency_sample,
+            config.forward.forward_seq, config.forward.backend_mtu,
+            config.forward.mtu_discover.map_or("null".to_owned(), |m| quote(m.name())),
+            config.forward.forward_retries, config.forward.pause_buffer, config.forward.checksum,
+            config.forward.transparent,
+            dead_letter, source,
+            allow_sources.join(","),
+            nodes.join(","))
+}
+
+fn names<S: AsRef<str>>(names: &[S]) -> String {
+    names.iter().map(|n| quote(n.as_ref())).collect::<Vec<_>>().join(",")
+}
+
+// The ring membership changes each listener recorded, oldest first.
+pub fn events_json(stats: &[(String, Arc<Stats>)]) -> String {
+    let listeners: Vec<String> = stats.iter().map(|&(ref name, ref stats)| {
+        let events: Vec<String> = stats.ring_events.lock().unwrap().iter().map(|e| {
+            format!("{{\"ts\":{}.{:03},\"reason\":{},\"added\":[{}],\"removed\":[{}],\
+                     \"before\":[{}],\"after\":[{}]}}",
+                    e.ts / 1000, e.ts % 1000, quote(e.reason), names(&e.added()),
+                    names(&e.removed()), names(&e.before), names(&e.after))
+        }).collect();
+        format!("{{\"name\":{},\"events\":[{}]}}", quote(name), events.join(","))
+    }).collect();
+    format!("{{\"listeners\":[{}]}}", listeners.join(","))
+}
+
+// A Prometheus label value: backslashes, quotes and newlines escaped.
+fn label(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// The Prometheus text exposition of each node's health check latency
+// histogram, per listener.
+pub fn metrics(stats: &[(String, Arc<Stats>)]) -> String {
+    let name = "statsd_proxy_health_check_latency_seconds";
+    let mut out = format!("# HELP {} Time from writing a health check to reading its reply.\n\
+                           # TYPE {} histogram\n", name, name);
+    for &(ref listener, ref stats) in stats.iter() {
+        for (node, history) in stats.nodes.lock().unwrap().iter() {
+            let labels = format!("listener=\"{}\",node=\"{}\"", label(listener), label(node));
+            let mut count = 0;
+            for (i, n) in history.check_latency.iter().enumerate() {
+                count += n.load(Ordering::Relaxed);
+                let le = match CHECK_LATENCY_BUCKETS.get(i) {
+                    Some(us) => (*us as f64 / 1000000.0).to_string(),
+                    None => "+Inf".to_owned(),
+                };
+                out.push_str(&format!("{}_bucket{{{},le=\"{}\"}} {}\n", name, labels, le, count));
+            }
+            let sum = history.check_latency_sum.load(Ordering::Relaxed) as f64 / 1000000.0;
+            out.push_str(&format!("{}_sum{{{}}} {}\n", name, labels, sum));
+            out.push_str(&format!("{}_count{{{}}} {}\n", name, labels, count));
+        }
+    }
+    out
+}
+
+// Each listener's recently unrouted keys, most recent first.
+pub fn unrouted_json(stats: &[(String, Arc<Stats>)]) -> String {
+    let listeners: Vec<String> = stats.iter().map(|&(ref name, ref stats)| {
+        let keys: Vec<String> = stats.unrouted.lock().unwrap().recent().iter()
+            .map(|&(ref key, ref u)| {
+                format!("{{\"key\":{},\"reason\":{},\"count\":{},\"last_seen\":{}.{:03}}}",
+                        quote(&String::from_utf8_lossy(key)), quote(u.reason), u.count,
+                        u.last_seen / 1000, u.last_seen % 1000)
+            })
+            .collect();
+        format!("{{\"name\":{},\"keys\":[{}]}}", quote(name), keys.join(","))
+    }).collect();
+    format!("{{\"listeners\":[{}]}}", listeners.join(","))
+}
+
+fn stats_json(stats: &[(String, Arc<Stats>)]) -> String {
+    let listeners: Vec<String> = stats.iter()
+        .map(|&(ref name, ref stats)| listener_stats_json(name, stats))
+        .collect();
+    let features: Vec<String> = build_info::FEATURES.split(',')
+        .filter(|f| !f.is_empty())
+        .map(quote)
+        .collect();
+
+    format!("{{\"build\":{{\"version\":{},\"git_sha\":{},\"features\":[{}]}},\
+             \"listeners\":[{}]}}",
+            quote(build_info::VERSION), quote(build_info::GIT_SHA), features.join(","),
+            listeners.join(","))
+}
+
+fn listener_stats_json(name: &str, stats: &Stats) -> String {
+    let mut buckets: Vec<String> = KEY_LENGTH_BUCKETS.iter().enumerate().map(|(i, max)| {
+        format!("{{\"le\":{},\"count\":{}}}", max, stats.key_lengths[i].load(Ordering::Relaxed))
+    }).collect();
+    buckets.push(format!("{{\"le\":null,\"count\":{}}}",
+                         stats.key_lengths[KEY_LENGTH_BUCKETS.len()].load(Ordering::Relaxed)));
+
+    let mut latency: Vec<String> = LATENCY_BUCKETS.iter().enumerate().map(|(i, max)| {
+        format!("{{\"le\":{},\"count\":{}}}", max,
+                stats.forward_latency[i].load(Ordering::Relaxed))
+    }).collect();
+    latency.push(format!("{{\"le\":null,\"count\":{}}}",
+                         stats.forward_latency[LATENCY_BUCKETS.len()].load(Ordering::Relaxed)));
+    let percentile = |p| match stats.latency_percentile(p) {
+        Some(us) => us.to_string(),
+        None => "null".to_owned(),
+    };
+
+    let seqs: Vec<String> = stats.forward_seqs.lock().unwrap().iter()
+        .map(|(node, seq)| format!("{}:{}", quote(node), seq.load(Ordering::Relaxed)))
+        .collect();
+
+    let nodes: Vec<String> = stats.nodes.lock().unwrap().iter().map(|(node, history)| {
+        let last_success = match history.last_success.load(Ordering::Relaxed) {
+            0 => "null".to_owned(),
+            t => t.to_string(),
+        };
+        let warmup = match history.warmup.load(Ordering::Relaxed) {
+            0 => "null".to_owned(),
+            p => p.to_string(),
+        };
+        format!("{}:{{\"reconnects\":{},\"last_success\":{},\"warmup\":{},\"vnodes\":{},\
+                 \"score\":{:.3},\"forwarded\":{},\"forward_errors\":{},\"sent_checksum\":{},\
+                 \"queue_depth\":{},\"queue_depth_max\":{},\"forward_reconnects\":{}}}",
+                quote(node), history.reconnects.load(Ordering::Relaxed), last_success, warmup,
+                history.vnodes.load(Ordering::Relaxed),
+                history.score.load(Ordering::Relaxed) as f64 / 1000.0,
+                history.forwarded.load(Ordering::Relaxed),
+                history.forward_errors.load(Ordering::Relaxed),
+                history.sent_checksum.load(Ordering::Relaxed) as u32,
+                history.queue_depth.load(Ordering::Relaxed), history.take_queue_depth_max(),
+                history.forward_reconnects.load(Ordering::Relaxed))
+    }).collect();
+
+    let sockets: Vec<String> = stats.sockets.lock().unwrap().iter().map(|&(name, ref opts)| {
+        let tos = match opts.tos {
+            Some(tos) => tos.to_string(),
+            None => "null".to_owned(),
+        };
+        let mtu_discover = opts.mtu_discover.map_or("null".to_owned(), |m| quote(m.name()));
+        format!("{}:{{\"recv_buffer\":{},\"send_buffer\":{},\"reuse_port\":{},\"tos\":{},\
+                 \"mtu_discover\":{}}}",
+                quote(name), opts.recv_buffer, opts.send_buffer, opts.reuse_port, tos,
+                mtu_discover)
+    }).collect();
+
+    format!("{{\"name\":{},\"outstanding\":{},\"backpressure_active\":{},\"dropped_backpressure\":{},\
+             \"paused\":{},\"paused_drops\":{},\
+             \"below_min_healthy\":{},\"min_healthy_drops\":{},\
+             \"ingress\":{{\"udp\":{},\"unix\":{},\"empty\":{}}},\
+             \"denied_source_packets\":{},\"broadcasts\":{},\
+             \"ring_nodes\":{},\"degraded_nodes\":{},\"pending_health_checks\":{},\
+             \"forward_seq\":{{{}}},\"nodes\":{{{}}},\"sockets\":{{{}}},\
+             \"dead_lettered\":{},\"rejected_keys\":{},\"binary_keys\":{},\
+             \"dirty_key_packets\":{},\"downsampled_counters\":{},\"renamed_keys\":{},\
+             \"short_forwards\":{},\
+             \"oversize_forwards\":{},\"failover_forwards\":{},\"checksum_mismatches\":{},\
+             \"capture_drops\":{},\
+             \"kernel_rx_drops\":{},\"key_lengths\":[{}],\
+             \"forward_latency_us\":{{\"p50\":{},\"p90\":{},\"p99\":{},\"buckets\":[{}]}}}}",
+            quote(name), stats.outstanding.load(Ordering::Relaxed),
+            stats.backpressure_active.load(Ordering::Relaxed),
+            stats.dropped_backpressure.load(Ordering::Relaxed),
+            stats.paused.load(Ordering::Relaxed),
+            stats.paused_drops.load(Ordering::Relaxed),
+            stats.below_min_healthy.load(Ordering::Relaxed),
+            stats.min_healthy_drops.load(Ordering::Relaxed),
+            stats.udp_packets.load(Ordering::Relaxed),
+            stats.unix_packets.load(Ordering::Relaxed),
+            stats.empty_packets.load(Ordering::Relaxed),
+            stats.denied_source_packets.load(Ordering::Relaxed),
+            stats.broadcasts.load(Ordering::Relaxed),
+            stats.ring_nodes.load(Ordering::Relaxed),
+            stats.degraded_nodes.load(Ordering::Relaxed),
+            stats.pending_health_checks.load(Ordering::Relaxed),
+            seqs.join(","), nodes.join(","), sockets.join(","),
+            stats.dead_lettered.load(Ordering::Relaxed),
+            stats.rejected_keys.load(Ordering::Relaxed),
+            stats.binary_keys.load(Ordering::Relaxed),
+            stats.dirty_key_packets.load(Ordering::Relaxed),
+            stats.downsampled_counters.load(Ordering::Relaxed),
+            stats.renamed_keys.load(Ordering::Relaxed),
+            stats.short_forwards.load(Ordering::Relaxed),
+            stats.oversize_forwards.load(Ordering::Relaxed),
+            stats.failover_forwards.load(Ordering::Relaxed),
+            stats.checksum_mismatches.load(Ordering::Relaxed),
+            stats.capture_drops.load(Ordering::Relaxed),
+            stats.kernel_rx_drops.load(Ordering::Relaxed),
+            buckets.join(","),
+            percentile(50.0), percentile(90.0), percentile(99.0), latency.join(","))
+}
@@ -0,0 +1,9 @@
+pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+pub const GIT_SHA: &'static str = env!("BUILD_GIT_SHA");
+// Comma separated, empty without any features.
+pub const FEATURES: &'static str = env!("BUILD_FEATURES");
+
+pub fn describe() -> String {
+    let features = if FEATURES.is_empty() { "none" } else { FEATURES };
+    format!("statsd-proxy {} ({})\nfeatures: {}", VERSION, GIT_SHA, features)
+}
@@ -0,0 +1,219 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Records waiting for the writer thread; past that they are dropped rather
+// than slowing down forwarding.
+const QUEUE: usize = 10000;
+
+// One forwarded datagram. On disk, in order: the unix time in microseconds
+// (8 bytes), the node name's length (2 bytes) and the name, the datagram's
+// length (4 bytes) and the datagram, lengths and time big endian.
+#[derive(Debug, PartialEq)]
+pub struct Record {
+    pub ts: u64,
+    pub node: String,
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    pub fn write_to(&self, out: &mut Write) -> io::Result<()> {
+        let mut header = Vec::with_capacity(14 + self.node.len());
+        header.extend_from_slice(&be(self.ts, 8));
+        header.extend_from_slice(&be(self.node.len() as u64, 2));
+        header.extend_from_slice(self.node.as_bytes());
+        header.extend_from_slice(&be(self.data.len() as u64, 4));
+        try!(out.write_all(&header));
+        out.write_all(&self.data)
+    }
+
+    fn len(&self) -> usize {
+        14 + self.node.len() + self.data.len()
+    }
+
+    // None at a clean end of the file.
+    pub fn read_from(input: &mut Read) -> io::Result<Option<Record>> {
+        let mut ts = [0; 8];
+        match input.read(&mut ts[0..1]) {
+            Ok(0) => return Ok(None),
+            Ok(_) => try!(input.read_exact(&mut ts[1..])),
+            Err(e) => return Err(e),
+        }
+        let mut len = [0; 2];
+        try!(input.read_exact(&mut len));
+        let mut node = vec![0; from_be(&len) as usize];
+        try!(input.read_exact(&mut node));
+        let mut len = [0; 4];
+        try!(input.read_exact(&mut len));
+        let mut data = vec![0; from_be(&len) as usize];
+        try!(input.read_exact(&mut data));
+
+        let node = try!(String::from_utf8(node)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "node name isn't UTF-8")));
+        Ok(Some(Record {
+            ts: from_be(&ts),
+            node: node,
+            data: data,
+        }))
+    }
+}
+
+fn be(value: u64, bytes: usize) -> Vec<u8> {
+    (0..bytes).rev().map(|i| (value >> (i * 8)) as u8).collect()
+}
+
+fn from_be(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |v, &b| v << 8 | b as u64)
+}
+
+fn now_micros() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1000000 + (d.subsec_nanos() / 1000) as u64)
+        .unwrap_or(0)
+}
+
+// Size and rate bounds on the capture, see `capture_file`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CaptureConfig {
+    pub path: String,
+    // The file is rotated to `<path>.1` (and older ones up to
+    // `<path>.<files - 1>`) once it would grow past `max_bytes`.
+    pub max_bytes: usize,
+    pub files: usize,
+    // Records taken per second, over all listeners and workers.
+    pub max_rate: usize,
+}
+
+// The workers' end of the capture, one clone per connection. Recording
+// only copies the datagram onto a bounded channel; a thread does the writing.
+#[derive(Clone)]
+pub struct Capture {
+    tx: SyncSender<Record>,
+    max_rate: usize,
+    second: Arc<AtomicUsize>,
+    taken: Arc<AtomicUsize>,
+    start: Instant,
+}
+
+impl Capture {
+    pub fn start(config: &CaptureConfig) -> io::Result<Capture> {
+        let file = try!(open(&config.path));
+        let (tx, rx) = mpsc::sync_channel(QUEUE);
+        let writer = config.clone();
+        thread::spawn(move || run_writer(writer, file, rx));
+        Ok(Capture {
+            tx: tx,
+            max_rate: config.max_rate,
+            second: Arc::new(AtomicUsize::new(0)),
+            taken: Arc::new(AtomicUsize::new(0)),
+            start: Instant::now(),
+        })
+    }
+
+    // False when the datagram was left out, over the rate or with the
+    // writer behind.
+    pub fn record(&self, node: &str, data: &[u8]) -> bool {
+        let second = self.start.elapsed().as_secs() as usize;
+        if self.second.swap(second, Ordering::Relaxed) != second {
+            self.taken.store(0, Ordering::Relaxed);
+        }
+        if self.taken.fetch_add(1, Ordering::Relaxed) >= self.max_rate {
+            return false;
+        }
+
+        let record = Record {
+            ts: now_micros(),
+            node: node.to_owned(),
+            data: data.to_vec(),
+        };
+        self.tx.try_send(record).is_ok()
+    }
+}
+
+fn open(path: &str) -> io::Result<BufWriter<File>> {
+    fs::OpenOptions::new().create(true).append(true).open(path).map(BufWriter::new)
+}
+
+fn rotate(config: &CaptureConfig) -> io::Result<()> {
+    if config.files == 1 {
+        return fs::remove_file(&config.path);
+    }
+    for i in (1..config.files - 1).rev() {
+        let from = format!("{}.{}", config.path, i);
+        if fs::metadata(&from).is_ok() {
+            try!(fs::rename(&from, format!("{}.{}", config.path, i + 1)));
+        }
+    }
+    fs::rename(&config.path, format!("{}.1", config.path))
+}
+
+// The writer thread: exits with the last worker's sender. Buffered writes
+// are flushed at least once a second; up to that much is lost on exit.
+fn run_writer(config: CaptureConfig, mut out: BufWriter<File>, rx: Receiver<Record>) {
+    let mut size = fs::metadata(&config.path).map(|m| m.len() as usize).unwrap_or(0);
+    loop {
+        let record = match rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(record) => record,
+            Err(RecvTimeoutError::Timeout) => {
+                let _ = out.flush();
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        if size > 0 && size + record.len() > config.max_bytes {
+            let rotated = out.flush()
+                .and_then(|_| rotate(&config))
+                .and_then(|_| open(&config.path));
+            match rotated {
+                Ok(file) => {
+                    out = file;
+                    size = 0;
+                }
+                Err(e) => {
+                    log!(Error, "rotating capture file {} failed, capture stopped: {}",
+                         config.path, e);
+                    return;
+                }
+            }
+        }
+        if let Err(e) = record.write_to(&mut out) {
+            log!(Error, "writing capture file {} failed, capture stopped: {}", config.path, e);
+            return;
+        }
+        size += record.len();
+    }
+    let _ = out.flush();
+}
+
+// `--replay`: sends every datagram in the capture file at `path` to
+// `target` (say a staging proxy, which hashes them over its own ring) with
+// the gaps they were captured with, until `stop` is set.
+pub fn replay(path: &str, target: &str, stop: &AtomicBool) -> io::Result<usize> {
+    let mut input = io::BufReader::new(try!(File::open(path)));
+    let sock = try!(UdpSocket::bind("0.0.0.0:0"));
+    try!(sock.connect(target));
+
+    let start = Instant::now();
+    let mut first = None;
+    let mut sent = 0;
+    while let Some(record) = try!(Record::read_from(&mut input)) {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+        let offset = record.ts.saturating_sub(*first.get_or_insert(record.ts));
+        let due = Duration::from_millis(offset / 1000);
+        let elapsed = start.elapsed();
+        if due > elapsed {
+            thread::sleep(due - elapsed);
+        }
+        try!(sock.send(&record.data));
+        sent += 1;
+    }
+    Ok(sent)
+}
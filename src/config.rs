@@ -0,0 +1,1483 @@
+use std::collections::HashSet;
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use libc;
+use regex::bytes::Regex;
+use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
+use serde_yaml::{self, Value};
+
+use capture::CaptureConfig;
+use event_loop::{AllowList, ForwardConfig, HealthConfig, HealthType, OverflowPolicy, RewriteRule,
+                 RouteConfig, RouteMode, Sanitize, BinaryKeys, MAX_NODES};
+use forwarder::Transport;
+use hash::VnodeKey;
+use logging::{Level, LogFormat};
+use socket::MtuDiscover;
+use statsd;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Yaml(serde_yaml::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref e) => write!(f, "failed to read config: {}", e),
+            ConfigError::Yaml(ref e) => write!(f, "failed to parse config: {}", e),
+            ConfigError::Invalid(ref msg) => write!(f, "invalid config: {}", msg),
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(err: io::Error) -> ConfigError {
+        ConfigError::Io(err)
+    }
+}
+
+impl From<serde_yaml::Error> for ConfigError {
+    fn from(err: serde_yaml::Error) -> ConfigError {
+        ConfigError::Yaml(err)
+    }
+}
+
+fn invalid<T>(msg: String) -> Result<T, ConfigError> {
+    Err(ConfigError::Invalid(msg))
+}
+
+fn at_least(value: i64, key: &str, min: i64) -> Result<i64, ConfigError> {
+    if value < min {
+        return invalid(format!("`{}` must be at least {}", key, min));
+    }
+    Ok(value)
+}
+
+// A mapping in the order it was written, which serde's maps don't keep:
+// pools are numbered in this order and /config lists them so.
+struct Ordered<K, V>(Vec<(K, V)>);
+
+impl<K, V> Default for Ordered<K, V> {
+    fn default() -> Ordered<K, V> {
+        Ordered(Vec::new())
+    }
+}
+
+impl<'de, K: Deserialize<'de>, V: Deserialize<'de>> Deserialize<'de> for Ordered<K, V> {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Ordered<K, V>, D::Error> {
+        struct Entries<K, V>(PhantomData<(K, V)>);
+
+        impl<'de, K: Deserialize<'de>, V: Deserialize<'de>> Visitor<'de> for Entries<K, V> {
+            type Value = Ordered<K, V>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a mapping")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Ordered<K, V>, A::Error> {
+                let mut entries = Vec::new();
+                while let Some(entry) = try!(map.next_entry()) {
+                    entries.push(entry);
+                }
+                Ok(Ordered(entries))
+            }
+        }
+
+        d.deserialize_map(Entries(PhantomData))
+    }
+}
+
+// Online CPUs, the default worker count; 1 if the kernel won't say.
+pub fn available_cpus() -> usize {
+    let n = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    if n < 1 { 1 } else { n as usize }
+}
+
+pub fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + (d.subsec_nanos() / 1000000) as u64
+}
+
+// Expands `${VAR}` and `${VAR:-default}` in the raw config text, so that
+// typed values such as `port: ${STATSD_PORT}` parse as usual. Comments are
+// left alone. A value holding YAML syntax is quoted when the `${}` is the
+// whole scalar and rejected otherwise, so it can't change the document shape.
+fn expand_env(s: &str) -> Result<String, ConfigError> {
+    let mut out = String::with_capacity(s.len());
+
+    for line in s.lines() {
+        let (code, comment) = line.split_at(comment_start(line).unwrap_or(line.len()));
+
+        let mut rest = code;
+        while let Some(start) = rest.find("${") {
+            out.push_str(&rest[0..start]);
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => return invalid(format!("unterminated `${{` in `{}`", line)),
+            };
+
+            let expr = &rest[start + 2..end];
+            let (var, default) = match expr.find(":-") {
+                Some(i) => (&expr[0..i], Some(&expr[i + 2..])),
+                None => (expr, None),
+            };
+
+            let value = match (env::var(var), default) {
+                (Ok(value), _) => value,
+                (Err(_), Some(default)) => default.to_owned(),
+                (Err(_), None) => {
+                    return invalid(format!("environment variable `{}` is not set and has \
+                                            no default", var));
+                }
+            };
+
+            let prefix = &code[0..code.len() - rest.len() + start];
+            if plain_safe(&value) {
+                out.push_str(&value);
+            } else if starts_scalar(prefix) && rest[end + 1..].trim().is_empty() {
+                out.push_str(&quoted(&value));
+            } else {
+                return invalid(format!("environment variable `{}` holds YAML syntax and is \
+                                        only allowed as a whole value", var));
+            }
+            rest = &rest[end + 1..];
+        }
+        out.push_str(rest);
+        out.push_str(comment);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+// The offset of the comment on `line`: a `#` at the start or after
+// whitespace, outside quotes.
+fn comment_start(line: &str) -> Option<usize> {
+    let (mut single, mut double, mut escaped) = (false, false, false);
+    let mut prev = ' ';
+    for (i, c) in line.char_indices() {
+        match c {
+            '#' if !single && !double && prev.is_whitespace() => return Some(i),
+            '\'' if !double => single = !single,
+            '"' if !single && !escaped => double = !double,
+            _ => {}
+        }
+        escaped = double && c == '\\' && !escaped;
+        prev = c;
+    }
+    None
+}
+
+// Whether `value` reads back as the same plain YAML scalar wherever it is
+// pasted.
+fn plain_safe(value: &str) -> bool {
+    !value.contains(|c: char| "{}[],&*!|>'\"%@`\r\n\t".contains(c)) && !value.contains(": ") &&
+    !value.contains(" #") && !value.starts_with('#') && !value.starts_with("- ") &&
+    !value.ends_with(':') && value.trim() == value
+}
+
+// Whether a scalar begins right after `prefix`: at the start of a line, after
+// `key: ` or after a `- ` list marker.
+fn starts_scalar(prefix: &str) -> bool {
+    let t = prefix.trim_right();
+    t.is_empty() || t.len() < prefix.len() && (t.ends_with(':') || t.trim_left() == "-")
+}
+
+// `value` as a double-quoted YAML scalar.
+fn quoted(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// The YAML document in `s`, after `${VAR}` expansion.
+fn parse_doc(s: &str) -> Result<Value, ConfigError> {
+    let doc: Value = try!(serde_yaml::from_str(&try!(expand_env(s))));
+    if doc.is_null() {
+        return invalid("config is empty".to_owned());
+    }
+    Ok(doc)
+}
+
+// Reads `path` and the files it names under `include` (a path or a list,
+// relative to the including file). Included files are merged in order and
+// the including file goes last, so each file overrides what it includes.
+// `stack` holds the files being read, to catch include cycles.
+fn load_file(path: &Path, stack: &mut Vec<PathBuf>) -> Result<Value, ConfigError> {
+    let canonical = try!(fs::canonicalize(path));
+    if let Some(start) = stack.iter().position(|p| *p == canonical) {
+        let mut chain: Vec<String> = stack[start..].iter()
+            .map(|p| p.display().to_string())
+            .collect();
+        chain.push(canonical.display().to_string());
+        return invalid(format!("include cycle: {}", chain.join(" -> ")));
+    }
+
+    let mut s = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut s));
+    let mut doc = try!(parse_doc(&s));
+
+    let includes = match doc.get("include") {
+        None => None,
+        Some(&Value::String(ref p)) => Some(vec![p.clone()]),
+        Some(&Value::Sequence(ref items)) => {
+            let mut includes = Vec::new();
+            for item in items.iter() {
+                match item.as_str() {
+                    Some(p) => includes.push(p.to_owned()),
+                    None => return invalid("`include` entries must be paths".to_owned()),
+                }
+            }
+            Some(includes)
+        }
+        Some(_) => return invalid("`include` must be a path or a list of paths".to_owned()),
+    };
+    let includes = match includes {
+        Some(includes) => includes,
+        None => return Ok(doc),
+    };
+    if let Value::Mapping(ref mut m) = doc {
+        m.remove(&Value::String("include".to_owned()));
+    }
+
+    stack.push(canonical);
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut merged = None;
+    for include in includes.iter() {
+        let included = try!(load_file(&dir.join(include), stack));
+        merged = Some(match merged {
+            Some(base) => merge(base, included),
+            None => included,
+        });
+    }
+    stack.pop();
+
+    Ok(match merged {
+        Some(base) => merge(base, doc),
+        None => doc,
+    })
+}
+
+// Mappings merge key by key, so `nodes` (and `listeners`, `pools`) from
+// every file add up and a node named twice takes the later file's fields;
+// any other value in `over` replaces the one in `base`.
+fn merge(base: Value, over: Value) -> Value {
+    match (base, over) {
+        (Value::Mapping(mut base), Value::Mapping(over)) => {
+            for (k, v) in over.into_iter() {
+                let v = match base.remove(&k) {
+                    Some(b) => merge(b, v),
+                    None => v,
+                };
+                base.insert(k, v);
+            }
+            Value::Mapping(base)
+        }
+        (_, over) => over,
+    }
+}
+
+// Minimal HTTP/1.0 GET of `http://host[:port]/path`, returning the body.
+fn fetch(url: &str) -> Result<Vec<u8>, ConfigError> {
+    let rest = &url["http://".len()..];
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[0..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let addr = if authority.contains(':') {
+        authority.to_owned()
+    } else {
+        format!("{}:80", authority)
+    };
+
+    let mut stream = try!(TcpStream::connect(&addr[..]));
+    try!(stream.set_read_timeout(Some(Duration::from_secs(10))));
+    try!(write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, authority));
+
+    let mut response = Vec::new();
+    try!(stream.read_to_end(&mut response));
+
+    let split = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(i) => i,
+        None => return invalid(format!("malformed response fetching {}", url)),
+    };
+    let head = String::from_utf8_lossy(&response[0..split]).into_owned();
+    let status = head.lines().next().unwrap_or("");
+    if status.split_whitespace().nth(1) != Some("200") {
+        return invalid(format!("fetching {} failed: {}", url, status));
+    }
+    Ok(response[split + 4..].to_vec())
+}
+
+// A local address to send from; it has to be bindable on this host.
+fn source_addr(addr: &Option<String>, what: &str) -> Result<Option<IpAddr>, ConfigError> {
+    let addr = match *addr {
+        None => return Ok(None),
+        Some(ref s) => match s.parse::<IpAddr>() {
+            Ok(addr) => addr,
+            Err(_) => return invalid(format!("{} `source_addr` {} is not an IP address", what, s)),
+        },
+    };
+
+    if let Err(e) = UdpSocket::bind(SocketAddr::new(addr, 0)) {
+        return invalid(format!("{} `source_addr` {} is not usable here: {}", what, addr, e));
+    }
+    Ok(Some(addr))
+}
+
+// `a.b.c.d/len`, or a bare address for a single host, as an inclusive range.
+// The listener is IPv4 only, so IPv6 networks are rejected.
+fn cidr(s: &str) -> Result<(u32, u32), ConfigError> {
+    let mut parts = s.splitn(2, '/');
+    let addr = parts.next().unwrap_or("");
+    let len = match parts.next() {
+        Some(len) => match len.parse::<u32>() {
+            Ok(len) if len <= 32 => len,
+            _ => return invalid(format!("`allow_sources` {} has a bad prefix length", s)),
+        },
+        None => 32,
+    };
+    let ip = match addr.parse::<Ipv4Addr>() {
+        Ok(ip) => u32::from(ip),
+        Err(_) => return invalid(format!("`allow_sources` {} is not an IPv4 network", s)),
+    };
+
+    let mask = if len == 0 { 0 } else { !0u32 << (32 - len) };
+    Ok((ip & mask, (ip & mask) | !mask))
+}
+
+fn allow_sources(list: &[String]) -> Result<AllowList, ConfigError> {
+    let mut cidrs = Vec::new();
+    let mut ranges = Vec::new();
+    for s in list.iter() {
+        ranges.push(try!(cidr(s.trim())));
+        cidrs.push(s.trim().to_owned());
+    }
+    Ok(AllowList::new(cidrs, ranges))
+}
+
+fn read_nodes(spec: &Option<Ordered<String, RawNode>>, pool: usize, replicas: i64,
+              health_checks: bool, nodes: &mut Vec<NodeSpec>) -> Result<(), ConfigError> {
+    let spec = match *spec {
+        Some(ref spec) => spec,
+        None => return invalid("`nodes` is missing".to_owned()),
+    };
+    for &(ref name, ref node) in spec.0.iter() {
+        let mut node = try!(NodeSpec::from_raw(name, node, replicas, health_checks));
+        node.pool = pool;
+        nodes.push(node);
+    }
+    Ok(())
+}
+
+fn port(value: i64, what: &str) -> Result<u16, ConfigError> {
+    if value < 0 || value > 65535 {
+        return invalid(format!("{} {} is not a valid port", what, value));
+    }
+    Ok(value as u16)
+}
+
+#[derive(Clone)]
+pub struct NodeSpec {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    pub adminport: u16,
+    pub weight: i64,
+    pub replicas: usize,
+    pub health_type: HealthType,
+    pub enabled: bool,
+    pub source_addr: Option<IpAddr>,
+    pub description: Option<String>,
+    // Index into `RouteConfig::pools`, 0 for the top level `nodes`.
+    pub pool: usize,
+    pub tags: Vec<String>,
+    pub transport: Transport,
+}
+
+// A node as written under `nodes`. Keys not listed here (`team`,
+// annotations from config generators, ...) are ignored; `description` is kept
+// only to show it on /config.
+#[derive(Deserialize)]
+#[serde(default)]
+struct RawNode {
+    host: Option<String>,
+    port: Option<i64>,
+    adminport: Option<i64>,
+    weight: i64,
+    enabled: bool,
+    source_addr: Option<String>,
+    description: Option<String>,
+    tags: Vec<String>,
+    health_type: Option<HealthType>,
+    transport: Transport,
+}
+
+impl Default for RawNode {
+    fn default() -> RawNode {
+        RawNode {
+            host: None,
+            port: None,
+            adminport: None,
+            weight: 1,
+            enabled: true,
+            source_addr: None,
+            description: None,
+            tags: Vec::new(),
+            health_type: None,
+            transport: Transport::Udp,
+        }
+    }
+}
+
+impl NodeSpec {
+    // A node without `adminport` (or with 0) is never health checked and
+    // stays in the ring; it can't have a `health_type`.
+    fn from_raw(name: &str, spec: &RawNode, base_replicas: i64,
+                health_checks: bool) -> Result<NodeSpec, ConfigError> {
+        let host = match spec.host {
+            Some(ref h) => h.clone(),
+            None => return invalid(format!("node `{}` is missing `host`", name)),
+        };
+
+        let p = match spec.port {
+            Some(p) => try!(port(p, &format!("node `{}` port", name))),
+            None => return invalid(format!("node `{}` is missing `port`", name)),
+        };
+
+        let adminport = match spec.adminport {
+            Some(p) => try!(port(p, &format!("node `{}` adminport", name))),
+            None => 0,
+        };
+        if adminport == 0 && health_checks && spec.health_type.is_some() {
+            return invalid(format!("node `{}` has a health_type but no adminport to check",
+                                   name));
+        }
+
+        let weight = try!(at_least(spec.weight, "weight", 1));
+        let source = try!(source_addr(&spec.source_addr, &format!("node `{}`", name)));
+
+        for t in spec.tags.iter() {
+            if t.is_empty() || t.bytes().any(|b| b"|,#\n ".contains(&b)) {
+                return invalid(format!("node `{}` tags must be non-empty strings without `|`, \
+                                        `,`, `#` or spaces", name));
+            }
+        }
+
+        Ok(NodeSpec {
+            name: name.to_owned(),
+            host: host,
+            port: p,
+            adminport: adminport,
+            weight: weight,
+            replicas: (weight * base_replicas) as usize,
+            health_type: spec.health_type.unwrap_or(HealthType::StatsdAdmin),
+            enabled: spec.enabled,
+            source_addr: source,
+            description: spec.description.clone(),
+            pool: 0,
+            tags: spec.tags.clone(),
+            transport: spec.transport,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct ListenConfig {
+    pub recv_buffer: Option<usize>,
+    pub force_recv_buffer: bool,
+    // Path of a Unix datagram socket read alongside the UDP `bind` port.
+    pub unix_bind: Option<String>,
+    // Poll the listeners level triggered instead of edge triggered oneshot.
+    pub level_triggered: bool,
+    // SO_REUSEPORT on the bind port, which is how the workers share it.
+    pub reuse_port: bool,
+}
+
+// One proxy instance: a bind port with its own nodes, ring and routing.
+#[derive(Clone)]
+pub struct ListenerConfig {
+    pub name: String,
+    pub bind: u16,
+    pub replicas: i64,
+    pub listen: ListenConfig,
+    pub health: HealthConfig,
+    pub route: RouteConfig,
+    pub forward: ForwardConfig,
+    pub nodes: Vec<NodeSpec>,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Scoring {
+    Counts,
+    Ewma,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct RawPool {
+    replicas: Option<i64>,
+    nodes: Option<Ordered<String, RawNode>>,
+}
+
+#[derive(Deserialize)]
+struct RawRewrite {
+    #[serde(rename = "match")]
+    pattern: Option<String>,
+    replace: Option<String>,
+}
+
+// A listener as written, the top level of a config without `listeners`.
+// Missing keys take the defaults below; `ListenerConfig::from_raw` checks
+// the values.
+#[derive(Deserialize)]
+#[serde(default)]
+struct RawListener {
+    bind: i64,
+    recv_buffer: Option<i64>,
+    force_recv_buffer: bool,
+    level_triggered: bool,
+    reuse_port: bool,
+    unix_bind: Option<String>,
+    replicas: i64,
+    health_checks: bool,
+    check_interval: i64,
+    // Both default to `check_interval`.
+    probe_interval: Option<i64>,
+    eval_interval: Option<i64>,
+    probe_jitter: i64,
+    failure_threshold: i64,
+    failure_window: i64,
+    success_threshold: i64,
+    degraded_response: Option<String>,
+    degraded_weight: i64,
+    max_concurrent_health_checks: Option<i64>,
+    warmup_duration: i64,
+    failure_weights: Ordered<HealthType, i64>,
+    ewma_alpha: f64,
+    health_scoring: Scoring,
+    ewma_evict_below: f64,
+    ewma_readmit_above: f64,
+    min_healthy_fraction: Option<f64>,
+    route_mode: RouteMode,
+    route_key_segments: Option<i64>,
+    route_key_delimiter: Option<String>,
+    metric_prefix: String,
+    key_delimiter: Option<String>,
+    sanitize_keys: Sanitize,
+    sanitize_replacement: Option<String>,
+    binary_key_policy: BinaryKeys,
+    dirty_key_check: bool,
+    dirty_key_sample: i64,
+    counter_sample_factor: i64,
+    counter_sample_prefixes: Ordered<String, i64>,
+    rewrite_rules: Vec<RawRewrite>,
+    rewrite_first_match: bool,
+    trace_timestamps: bool,
+    track_top_keys: bool,
+    top_keys_size: i64,
+    top_keys_sample: i64,
+    vnode_key: Option<String>,
+    hash_salt: String,
+    broadcast_prefixes: Vec<String>,
+    retry_queue_size: i64,
+    max_outstanding_packets: Option<i64>,
+    drain_timeout: i64,
+    transparent: bool,
+    source_addr: Option<String>,
+    latency_sample: i64,
+    allow_sources: Vec<String>,
+    forward_seq: bool,
+    backend_mtu: i64,
+    forward_retries: i64,
+    mtu_discover: Option<MtuDiscover>,
+    pause_buffer: i64,
+    forward_checksum: bool,
+    dead_letter_node: Option<String>,
+    overflow_policy: OverflowPolicy,
+    nodes: Option<Ordered<String, RawNode>>,
+    pools: Ordered<String, RawPool>,
+    type_pools: Ordered<String, String>,
+}
+
+impl Default for RawListener {
+    fn default() -> RawListener {
+        RawListener {
+            bind: 8977,
+            recv_buffer: None,
+            force_recv_buffer: false,
+            level_triggered: false,
+            reuse_port: true,
+            unix_bind: None,
+            replicas: 20,
+            health_checks: true,
+            check_interval: 1000,
+            probe_interval: None,
+            eval_interval: None,
+            probe_jitter: 0,
+            failure_threshold: 3,
+            failure_window: 30000,
+            success_threshold: 3,
+            degraded_response: None,
+            degraded_weight: 50,
+            max_concurrent_health_checks: None,
+            warmup_duration: 0,
+            failure_weights: Ordered::default(),
+            ewma_alpha: 0.3,
+            health_scoring: Scoring::Counts,
+            ewma_evict_below: 0.5,
+            ewma_readmit_above: 0.9,
+            min_healthy_fraction: None,
+            route_mode: RouteMode::Name,
+            route_key_segments: None,
+            route_key_delimiter: None,
+            metric_prefix: String::new(),
+            key_delimiter: None,
+            sanitize_keys: Sanitize::Off,
+            sanitize_replacement: None,
+            binary_key_policy: BinaryKeys::Forward,
+            dirty_key_check: false,
+            dirty_key_sample: 0,
+            counter_sample_factor: 1,
+            counter_sample_prefixes: Ordered::default(),
+            rewrite_rules: Vec::new(),
+            rewrite_first_match: true,
+            trace_timestamps: false,
+            track_top_keys: false,
+            top_keys_size: 100,
+            top_keys_sample: 100,
+            vnode_key: None,
+            hash_salt: String::new(),
+            broadcast_prefixes: Vec::new(),
+            retry_queue_size: 1024,
+            max_outstanding_packets: None,
+            drain_timeout: 5000,
+            transparent: false,
+            source_addr: None,
+            latency_sample: 1,
+            allow_sources: Vec::new(),
+            forward_seq: false,
+            backend_mtu: 1432,
+            forward_retries: 0,
+            mtu_discover: None,
+            pause_buffer: 0,
+            forward_checksum: false,
+            dead_letter_node: None,
+            overflow_policy: OverflowPolicy::DropNewest,
+            nodes: None,
+            pools: Ordered::default(),
+            type_pools: Ordered::default(),
+        }
+    }
+}
+
+impl ListenerConfig {
+    fn from_raw(name: &str, doc: &RawListener) -> Result<ListenerConfig, ConfigError> {
+        let bind = try!(port(doc.bind, "bind"));
+        let recv_buffer = match doc.recv_buffer {
+            Some(size) => Some(try!(at_least(size, "recv_buffer", 1)) as usize),
+            None => None,
+        };
+        if doc.unix_bind.as_ref().map_or(false, |path| path.is_empty()) {
+            return invalid("`unix_bind` must be a socket path".to_owned());
+        }
+        let replicas = try!(at_least(doc.replicas, "replicas", 1));
+        let check_interval = try!(at_least(doc.check_interval, "check_interval", 1));
+        let probe_interval = try!(at_least(doc.probe_interval.unwrap_or(check_interval),
+                                           "probe_interval", 1));
+        let eval_interval = try!(at_least(doc.eval_interval.unwrap_or(check_interval),
+                                          "eval_interval", 1));
+        let probe_jitter = try!(at_least(doc.probe_jitter, "probe_jitter", 0));
+        if probe_jitter > 100 {
+            return invalid(format!("`probe_jitter` {} is more than 100 percent", probe_jitter));
+        }
+        let failure_threshold = try!(at_least(doc.failure_threshold, "failure_threshold", 1));
+        let failure_window = try!(at_least(doc.failure_window, "failure_window", 0));
+        let success_threshold = try!(at_least(doc.success_threshold, "success_threshold", 1));
+        let degraded_response = match doc.degraded_response {
+            Some(ref p) if p.is_empty() => {
+                return invalid("`degraded_response` must be a non-empty string".to_owned())
+            }
+            ref p => p.as_ref().map(|p| p.as_bytes().to_vec()),
+        };
+        let max_concurrent_checks = match doc.max_concurrent_health_checks {
+            Some(max) => Some(try!(at_least(max, "max_concurrent_health_checks", 1)) as usize),
+            None => None,
+        };
+        let warmup = try!(at_least(doc.warmup_duration, "warmup_duration", 0));
+        let mut failure_weights = Vec::new();
+        for &(kind, weight) in doc.failure_weights.0.iter() {
+            let weight = try!(at_least(weight, kind.name(), 1));
+            failure_weights.push((kind, weight as usize));
+        }
+        let score_alpha = doc.ewma_alpha;
+        if !(score_alpha > 0.0 && score_alpha <= 1.0) {
+            return invalid("`ewma_alpha` must be more than 0 and at most 1".to_owned());
+        }
+        let score_eviction = match doc.health_scoring {
+            Scoring::Counts => None,
+            Scoring::Ewma => {
+                let (evict_below, readmit_at) = (doc.ewma_evict_below, doc.ewma_readmit_above);
+                if !(evict_below > 0.0 && evict_below < readmit_at && readmit_at <= 1.0) {
+                    return invalid("`ewma_evict_below` and `ewma_readmit_above` must satisfy \
+                                    0 < evict_below < readmit_above <= 1".to_owned());
+                }
+                Some((evict_below, readmit_at))
+            }
+        };
+        if let Some(f) = doc.min_healthy_fraction {
+            if !(f > 0.0 && f <= 1.0) {
+                return invalid("`min_healthy_fraction` must be more than 0 and at most 1"
+                               .to_owned());
+            }
+        }
+        let degraded_weight = try!(at_least(doc.degraded_weight, "degraded_weight", 1));
+        if degraded_weight > 100 {
+            return invalid(format!("`degraded_weight` {} is more than 100 percent",
+                                   degraded_weight));
+        }
+
+        let key_segments = match doc.route_key_segments {
+            Some(n) => Some(try!(at_least(n, "route_key_segments", 1)) as usize),
+            None => None,
+        };
+        let key_delimiter = match doc.route_key_delimiter {
+            None => b'.',
+            Some(ref d) if d.len() == 1 => d.as_bytes()[0],
+            Some(_) => {
+                return invalid("`route_key_delimiter` must be a single character".to_owned())
+            }
+        };
+        if doc.metric_prefix.contains(|c: char| c == ':' || c == '|' || c == '\n') {
+            return invalid("`metric_prefix` must be a string without `:`, `|` or \
+                            newlines".to_owned());
+        }
+        let value_delimiter = match doc.key_delimiter {
+            None => b':',
+            Some(ref d) if d.len() == 1 && d != "|" && d != "\n" => d.as_bytes()[0],
+            Some(_) => return invalid("`key_delimiter` must be a single byte other than `|` or \
+                                       a newline".to_owned()),
+        };
+        let sanitize_replacement = match doc.sanitize_replacement {
+            None => b'_',
+            Some(ref r) if r.len() == 1 && r != "|" && r.as_bytes()[0] != value_delimiter &&
+                           statsd::name_byte_allowed(r.as_bytes()[0]) => r.as_bytes()[0],
+            Some(_) => return invalid("`sanitize_replacement` must be a single printable \
+                                       character other than `/`, `|` or the key \
+                                       delimiter".to_owned()),
+        };
+        let dirty_key_sample = try!(at_least(doc.dirty_key_sample, "dirty_key_sample", 0));
+        let counter_sample_factor = try!(at_least(doc.counter_sample_factor,
+                                                  "counter_sample_factor", 1));
+        let mut counter_sample_prefixes = Vec::new();
+        for &(ref prefix, factor) in doc.counter_sample_prefixes.0.iter() {
+            if prefix.is_empty() {
+                return invalid("`counter_sample_prefixes` keys must be non-empty \
+                                strings".to_owned());
+            }
+            if factor < 1 {
+                return invalid(format!("`counter_sample_prefixes` {} must be a factor of at \
+                                        least 1", prefix));
+            }
+            counter_sample_prefixes.push((prefix.as_bytes().to_vec(), factor as usize));
+        }
+        let mut rewrite_rules = Vec::new();
+        for rule in doc.rewrite_rules.iter() {
+            let (pattern, replacement) = match (&rule.pattern, &rule.replace) {
+                (&Some(ref p), &Some(ref r)) => (p, r),
+                _ => return invalid("`rewrite_rules` entries need a `match` pattern and a \
+                                     `replace` string".to_owned()),
+            };
+            let bad = replacement.is_empty() || replacement.contains('\n') ||
+                      replacement.contains('|') ||
+                      replacement.as_bytes().contains(&value_delimiter);
+            if bad {
+                return invalid(format!("`rewrite_rules` replacement `{}` must be non-empty, \
+                                        without `|`, newlines or the key delimiter",
+                                       replacement));
+            }
+            let pattern = match Regex::new(pattern) {
+                Ok(p) => p,
+                Err(e) => return invalid(format!("`rewrite_rules` pattern `{}`: {}", pattern, e)),
+            };
+            rewrite_rules.push(RewriteRule {
+                pattern: pattern,
+                replacement: replacement.as_bytes().to_vec(),
+            });
+        }
+        let top_keys_size = try!(at_least(doc.top_keys_size, "top_keys_size", 1));
+        let top_keys_sample = try!(at_least(doc.top_keys_sample, "top_keys_sample", 1));
+        if top_keys_size > 10000 {
+            return invalid("`top_keys_size` can be at most 10000".to_owned());
+        }
+        let vnode_key = match doc.vnode_key {
+            None => VnodeKey::default(),
+            Some(ref f) => match VnodeKey::parse(f) {
+                Ok(key) => key,
+                Err(e) => return invalid(format!("`vnode_key`: {}", e)),
+            },
+        };
+        let mut broadcast_prefixes = Vec::new();
+        for p in doc.broadcast_prefixes.iter() {
+            if p.is_empty() {
+                return invalid("`broadcast_prefixes` must be a list of non-empty \
+                                strings".to_owned());
+            }
+            broadcast_prefixes.push(p.as_bytes().to_vec());
+        }
+
+        let retry_queue_size = try!(at_least(doc.retry_queue_size, "retry_queue_size", 0));
+        let max_outstanding = match doc.max_outstanding_packets {
+            Some(max) => Some(try!(at_least(max, "max_outstanding_packets", 1)) as usize),
+            None => None,
+        };
+        let drain_timeout = try!(at_least(doc.drain_timeout, "drain_timeout", 0));
+        let source = try!(source_addr(&doc.source_addr, "global"));
+        let latency_sample = try!(at_least(doc.latency_sample, "latency_sample", 1));
+        let allow_sources = try!(allow_sources(&doc.allow_sources));
+        let backend_mtu = try!(at_least(doc.backend_mtu, "backend_mtu", 64));
+        let forward_retries = try!(at_least(doc.forward_retries, "forward_retries", 0));
+        if forward_retries > 3 {
+            return invalid("`forward_retries` can be at most 3".to_owned());
+        }
+        let pause_buffer = try!(at_least(doc.pause_buffer, "pause_buffer", 0));
+        if backend_mtu > 65507 {
+            return invalid("`backend_mtu` can't exceed the 65507 byte UDP payload".to_owned());
+        }
+        if let Some(ref addr) = doc.dead_letter_node {
+            let resolved = (&addr[..]).to_socket_addrs()
+                .map(|mut addrs| addrs.next().is_some())
+                .unwrap_or(false);
+            if !resolved {
+                return invalid(format!("`dead_letter_node` {} could not be resolved", addr));
+            }
+        }
+
+        let mut nodes = Vec::new();
+        try!(read_nodes(&doc.nodes, 0, replicas, doc.health_checks, &mut nodes));
+
+        // Extra pools each get their own ring; `type_pools` sends metric
+        // types to them, everything else stays on the top level nodes.
+        let mut pools = vec!["default".to_owned()];
+        for &(ref name, ref pool) in doc.pools.0.iter() {
+            if name == "default" {
+                return invalid("pool names must be strings other than `default`".to_owned());
+            }
+            let replicas = try!(at_least(pool.replicas.unwrap_or(replicas), "replicas", 1));
+            match read_nodes(&pool.nodes, pools.len(), replicas, doc.health_checks, &mut nodes) {
+                Ok(()) => {}
+                Err(ConfigError::Invalid(msg)) => {
+                    return invalid(format!("pool `{}`: {}", name, msg));
+                }
+                Err(e) => return Err(e),
+            }
+            pools.push(name.clone());
+        }
+
+        let mut type_pools = Vec::new();
+        for &(ref kind, ref pool) in doc.type_pools.0.iter() {
+            match &kind[..] {
+                "c" | "ms" | "g" | "s" | "h" => {}
+                _ => return invalid("`type_pools` keys must be one of c, ms, g, s, h".to_owned()),
+            }
+            let index = match pools.iter().position(|n| n == pool) {
+                Some(index) => index,
+                None => return invalid(format!("`type_pools` {} names an unknown pool", kind)),
+            };
+            type_pools.push((kind.as_bytes().to_vec(), index));
+        }
+
+        // Hash iteration order isn't stable across loads; keep node ids (and
+        // everything logged per node) reproducible. Same `host:port` order as
+        // the ring and reloads sort nodes in, so ids follow the slab.
+        nodes.sort_by_key(|n| format!("{}:{}", n.host, n.port));
+
+        let config = ListenerConfig {
+            name: name.to_owned(),
+            bind: bind,
+            replicas: replicas,
+            listen: ListenConfig {
+                recv_buffer: recv_buffer,
+                force_recv_buffer: doc.force_recv_buffer,
+                unix_bind: doc.unix_bind.clone(),
+                level_triggered: doc.level_triggered,
+                reuse_port: doc.reuse_port,
+            },
+            health: HealthConfig {
+                enabled: doc.health_checks,
+                probe_interval: probe_interval as u64,
+                probe_jitter: probe_jitter as u64,
+                eval_interval: eval_interval as u64,
+                failure_threshold: failure_threshold as usize,
+                failure_window: Duration::from_millis(failure_window as u64),
+                success_threshold: success_threshold as u32,
+                degraded_response: degraded_response,
+                degraded_weight: degraded_weight as usize,
+                warmup: Duration::from_millis(warmup as u64),
+                failure_weights: failure_weights,
+                score_alpha: score_alpha,
+                score_eviction: score_eviction,
+                min_healthy_fraction: doc.min_healthy_fraction,
+                max_concurrent_checks: max_concurrent_checks,
+            },
+            route: RouteConfig {
+                mode: doc.route_mode,
+                key_segments: key_segments,
+                key_delimiter: key_delimiter,
+                prefix: doc.metric_prefix.as_bytes().to_vec(),
+                broadcast_prefixes: broadcast_prefixes,
+                pools: pools,
+                type_pools: type_pools,
+                value_delimiter: value_delimiter,
+                sanitize: doc.sanitize_keys,
+                sanitize_replacement: sanitize_replacement,
+                binary_keys: doc.binary_key_policy,
+                dirty_key_check: doc.dirty_key_check,
+                dirty_key_sample: dirty_key_sample as usize,
+                counter_sample_factor: counter_sample_factor as usize,
+                counter_sample_prefixes: counter_sample_prefixes,
+                track_top_keys: doc.track_top_keys,
+                top_keys_size: top_keys_size as usize,
+                top_keys_sample: top_keys_sample as usize,
+                hash_salt: doc.hash_salt.as_bytes().to_vec(),
+                vnode_key: vnode_key,
+                rewrite_rules: rewrite_rules,
+                rewrite_first_match: doc.rewrite_first_match,
+                trace_timestamps: doc.trace_timestamps,
+            },
+            forward: ForwardConfig {
+                retry_queue_size: retry_queue_size as usize,
+                overflow_policy: doc.overflow_policy,
+                max_outstanding_packets: max_outstanding,
+                drain_timeout: Duration::from_millis(drain_timeout as u64),
+                transparent: doc.transparent,
+                dead_letter_node: doc.dead_letter_node.clone(),
+                source_addr: source,
+                latency_sample: latency_sample as usize,
+                allow_sources: allow_sources,
+                forward_seq: doc.forward_seq,
+                backend_mtu: backend_mtu as usize,
+                forward_retries: forward_retries as usize,
+                mtu_discover: doc.mtu_discover,
+                pause_buffer: pause_buffer as usize,
+                checksum: doc.forward_checksum,
+            },
+            nodes: nodes,
+        };
+
+        try!(config.validate());
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.nodes.is_empty() {
+            return invalid("no nodes configured".to_owned());
+        }
+        if !self.nodes.iter().any(|n| n.pool == 0 && n.enabled) {
+            return invalid("every node is disabled".to_owned());
+        }
+        for (i, pool) in self.route.pools.iter().enumerate().skip(1) {
+            if !self.nodes.iter().any(|n| n.pool == i && n.enabled) {
+                return invalid(format!("pool `{}` has no enabled nodes", pool));
+            }
+        }
+        if self.nodes.len() > MAX_NODES {
+            return invalid(format!("{} nodes configured, at most {} are supported",
+                                   self.nodes.len(), MAX_NODES));
+        }
+
+        let mut seen = HashSet::new();
+        for node in self.nodes.iter() {
+            if !seen.insert((node.host.clone(), node.port)) {
+                return invalid(format!("node `{}` duplicates {}:{}",
+                                       node.name, node.host, node.port));
+            }
+
+            match (&node.host[..], node.port).to_socket_addrs() {
+                Ok(mut addrs) => {
+                    if addrs.next().is_none() {
+                        return invalid(format!("node `{}` host {} resolved to no addresses",
+                                               node.name, node.host));
+                    }
+                }
+                Err(e) => {
+                    return invalid(format!("node `{}` host {} could not be resolved: {}",
+                                           node.name, node.host, e));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn summary(&self) -> String {
+        let mut s = format!("listener {}:\nbind: 0.0.0.0:{}\nreplicas: {}\n\
+                             probe_interval: {}ms\neval_interval: {}ms\n\
+                             failure_threshold: {} within {}ms\nsuccess_threshold: {}\n\
+                             nodes: {}\n",
+                            self.name, self.bind, self.replicas,
+                            self.health.probe_interval, self.health.eval_interval,
+                            self.health.failure_threshold, millis(self.health.failure_window),
+                            self.health.success_threshold, self.nodes.len());
+
+        if !self.health.enabled {
+            s.push_str("health_checks: off\n");
+        }
+        if self.health.probe_jitter > 0 {
+            s.push_str(&format!("probe_jitter: {}%\n", self.health.probe_jitter));
+        }
+        if let Some(max) = self.health.max_concurrent_checks {
+            s.push_str(&format!("max_concurrent_health_checks: {}\n", max));
+        }
+        if self.health.warmup > Duration::from_millis(0) {
+            s.push_str(&format!("warmup_duration: {}ms\n", millis(self.health.warmup)));
+        }
+        if let Some((evict_below, readmit_at)) = self.health.score_eviction {
+            s.push_str(&format!("health_scoring: ewma (alpha {}, evict below {}, readmit at {})\n",
+                                self.health.score_alpha, evict_below, readmit_at));
+        }
+        if let Some(min) = self.health.min_healthy_fraction {
+            s.push_str(&format!("min_healthy_fraction: {}\n", min));
+        }
+        for &(kind, weight) in self.health.failure_weights.iter() {
+            s.push_str(&format!("failure_weight: {} counts {}\n", kind.name(), weight));
+        }
+        if let Some(ref pattern) = self.health.degraded_response {
+            s.push_str(&format!("degraded_response: {} ({}% weight)\n",
+                                String::from_utf8_lossy(pattern), self.health.degraded_weight));
+        }
+        s.push_str(&format!("retry_queue_size: {} ({} on overflow)\n",
+                            self.forward.retry_queue_size, self.forward.overflow_policy.name()));
+        s.push_str(&format!("drain_timeout: {}ms\n", millis(self.forward.drain_timeout)));
+        if let Some(ref path) = self.listen.unix_bind {
+            s.push_str(&format!("unix_bind: {}\n", path));
+        }
+        if self.listen.level_triggered {
+            s.push_str("level_triggered: true\n");
+        }
+        if !self.listen.reuse_port {
+            s.push_str("reuse_port: false\n");
+        }
+        if let Some(size) = self.listen.recv_buffer {
+            s.push_str(&format!("recv_buffer: {}{}\n", size,
+                                if self.listen.force_recv_buffer { " (forced)" } else { "" }));
+        }
+        if self.forward.transparent {
+            s.push_str("transparent: true\n");
+        }
+        if self.forward.forward_seq {
+            s.push_str("forward_seq: true\n");
+        }
+        if !self.forward.allow_sources.cidrs.is_empty() {
+            s.push_str(&format!("allow_sources: {}\n",
+                                self.forward.allow_sources.cidrs.join(", ")));
+        }
+        if let Some(ref addr) = self.forward.dead_letter_node {
+            s.push_str(&format!("dead_letter_node: {}\n", addr));
+        }
+        if let Some(addr) = self.forward.source_addr {
+            s.push_str(&format!("source_addr: {}\n", addr));
+        }
+        if self.forward.forward_retries > 0 {
+            s.push_str(&format!("forward_retries: {}\n", self.forward.forward_retries));
+        }
+        if self.forward.backend_mtu != 1432 {
+            s.push_str(&format!("backend_mtu: {}\n", self.forward.backend_mtu));
+        }
+        if let Some(mode) = self.forward.mtu_discover {
+            s.push_str(&format!("mtu_discover: {}\n", mode.name()));
+        }
+        if self.forward.pause_buffer > 0 {
+            s.push_str(&format!("pause_buffer: {}\n", self.forward.pause_buffer));
+        }
+        if self.forward.checksum {
+            s.push_str("forward_checksum: on\n");
+        }
+        if self.forward.latency_sample > 1 {
+            s.push_str(&format!("latency_sample: 1 in {}\n", self.forward.latency_sample));
+        }
+        if let Some(max) = self.forward.max_outstanding_packets {
+            s.push_str(&format!("max_outstanding_packets: {}\n", max));
+        }
+
+        s.push_str(&format!("route_mode: {}\n", self.route.mode.name()));
+        if self.route.pools.len() > 1 {
+            s.push_str(&format!("pools: {}\n", self.route.pools.join(", ")));
+            for &(ref kind, pool) in self.route.type_pools.iter() {
+                s.push_str(&format!("  |{} -> {}\n", String::from_utf8_lossy(kind),
+                                    self.route.pools[pool]));
+            }
+        }
+        if !self.route.broadcast_prefixes.is_empty() {
+            let prefixes: Vec<String> = self.route.broadcast_prefixes.iter()
+                .map(|p| String::from_utf8_lossy(p).into_owned())
+                .collect();
+            s.push_str(&format!("broadcast_prefixes: {}\n", prefixes.join(", ")));
+        }
+        if !self.route.prefix.is_empty() {
+            s.push_str(&format!("metric_prefix: {}\n",
+                                String::from_utf8_lossy(&self.route.prefix)));
+        }
+        if self.route.value_delimiter != b':' {
+            s.push_str(&format!("key_delimiter: `{}`\n", self.route.value_delimiter as char));
+        }
+        match self.route.sanitize {
+            Sanitize::Off => {}
+            Sanitize::Replace => s.push_str(&format!("sanitize_keys: replace with `{}`\n",
+                                                     self.route.sanitize_replacement as char)),
+            Sanitize::Reject => s.push_str("sanitize_keys: reject\n"),
+        }
+        if self.route.binary_keys == BinaryKeys::Reject {
+            s.push_str("binary_key_policy: reject\n");
+        }
+        match self.route.dirty_key_check {
+            true if self.route.dirty_key_sample > 0 => {
+                s.push_str(&format!("dirty_key_check: on, logging 1 in {}\n",
+                                    self.route.dirty_key_sample))
+            }
+            true => s.push_str("dirty_key_check: on\n"),
+            false => {}
+        }
+        if self.route.counter_sample_factor > 1 {
+            s.push_str(&format!("counter_sample_factor: 1 in {} counters kept\n",
+                                self.route.counter_sample_factor));
+        }
+        for &(ref prefix, factor) in self.route.counter_sample_prefixes.iter() {
+            s.push_str(&format!("counter_sample_prefix: {} keeps 1 in {}\n",
+                                String::from_utf8_lossy(prefix), factor));
+        }
+        for rule in self.route.rewrite_rules.iter() {
+            s.push_str(&format!("rewrite: {} -> {}\n", rule.pattern.as_str(),
+                                String::from_utf8_lossy(&rule.replacement)));
+        }
+        if self.route.rewrite_rules.len() > 1 && !self.route.rewrite_first_match {
+            s.push_str("rewrite_first_match: off, every matching rule applies\n");
+        }
+        if self.route.trace_timestamps {
+            s.push_str("trace_timestamps: lines tagged with proxy_rx_ms\n");
+        }
+        if !self.route.hash_salt.is_empty() {
+            s.push_str(&format!("hash_salt: {}\n", String::from_utf8_lossy(&self.route.hash_salt)));
+        }
+        if self.route.track_top_keys {
+            s.push_str(&format!("track_top_keys: {} keys, 1 in {} sampled\n",
+                                self.route.top_keys_size, self.route.top_keys_sample));
+        }
+        if self.route.vnode_key != VnodeKey::default() {
+            s.push_str(&format!("vnode_key: {}\n", self.route.vnode_key.format()));
+        }
+        if let Some(n) = self.route.key_segments {
+            s.push_str(&format!("route key: first {} `{}` separated segments\n",
+                                n, self.route.key_delimiter as char));
+        }
+
+        for node in self.nodes.iter() {
+            let check = match node.adminport {
+                0 => "unchecked".to_owned(),
+                p => format!("{} {}", node.health_type.name(), p),
+            };
+            s.push_str(&format!("  {}: {}:{}{} ({}, weight {}, {} vnodes){}\n",
+                                node.name, node.host, node.port,
+                                if node.transport == Transport::Tcp { " over tcp" } else { "" },
+                                check, node.weight, node.replicas,
+                                if node.enabled { "" } else { " disabled" }));
+        }
+        s
+    }
+}
+
+// The top level as written; see `RawListener` for the listener keys, read
+// from the top level too when there is no `listeners` mapping.
+#[derive(Deserialize)]
+#[serde(default)]
+struct RawConfig {
+    stats_host: String,
+    stats_port: Option<i64>,
+    threads: i64,
+    cpu_affinity: Option<Vec<i64>>,
+    ready_min_nodes: i64,
+    admin_shutdown: bool,
+    admin_pause: bool,
+    admin_token: Option<String>,
+    log_format: LogFormat,
+    log_level: Level,
+    self_metrics_node: Option<String>,
+    self_metrics_prefix: String,
+    self_metrics_interval: i64,
+    capture_file: Option<String>,
+    capture_max_bytes: i64,
+    capture_files: i64,
+    capture_max_rate: i64,
+    listeners: Option<Ordered<String, RawListener>>,
+}
+
+impl Default for RawConfig {
+    fn default() -> RawConfig {
+        RawConfig {
+            stats_host: "127.0.0.1".to_owned(),
+            stats_port: None,
+            threads: available_cpus() as i64,
+            cpu_affinity: None,
+            ready_min_nodes: 1,
+            admin_shutdown: false,
+            admin_pause: false,
+            admin_token: None,
+            log_format: LogFormat::Text,
+            log_level: Level::Info,
+            self_metrics_node: None,
+            self_metrics_prefix: "statsd_proxy.".to_owned(),
+            self_metrics_interval: 10000,
+            capture_file: None,
+            capture_max_bytes: 104857600,
+            capture_files: 5,
+            capture_max_rate: 10000,
+            listeners: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub stats_host: String,
+    pub stats_port: Option<u16>,
+    // Workers per listener, at least 1.
+    pub threads: usize,
+    // Worker `i` of every listener is pinned to `cpu_affinity[i]`; empty
+    // leaves scheduling to the kernel.
+    pub cpu_affinity: Vec<usize>,
+    // GET /readyz needs at least this many nodes in every listener's ring.
+    pub ready_min_nodes: usize,
+    // POST /shutdown drains like SIGTERM; off unless enabled, and then only
+    // with `Authorization: Bearer <admin_token>`.
+    pub admin_shutdown: bool,
+    // POST /pause and /resume, under the same token.
+    pub admin_pause: bool,
+    pub admin_token: Option<String>,
+    pub log_format: LogFormat,
+    pub log_level: Level,
+    // The proxy's own counters go to this node every `self_metrics_interval`
+    // when set, see `SelfMetrics`. Read at startup only.
+    pub self_metrics_node: Option<String>,
+    pub self_metrics_prefix: String,
+    pub self_metrics_interval: Duration,
+    // A copy of every forwarded datagram goes to this file when set, see
+    // `Capture`. Read at startup only.
+    pub capture: Option<CaptureConfig>,
+    pub listeners: Vec<ListenerConfig>,
+}
+
+impl Config {
+    // `source` is a file path, `-` for stdin, or an http:// URL fetched once.
+    pub fn load(source: &str) -> Result<Config, ConfigError> {
+        if source == "-" {
+            let stdin = io::stdin();
+            let lock = stdin.lock();
+            Config::from_reader(lock)
+        } else if source.starts_with("http://") {
+            Config::from_reader(&try!(fetch(source))[..])
+        } else if source.starts_with("https://") {
+            invalid("https config URLs are not supported, fetch it and pipe it to `-`".to_owned())
+        } else {
+            Config::from_file(source)
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<Config, ConfigError> {
+        Config::from_value(try!(load_file(Path::new(path), &mut Vec::new())))
+    }
+
+    pub fn from_reader<R: Read>(mut r: R) -> Result<Config, ConfigError> {
+        let mut s = String::new();
+        try!(r.read_to_string(&mut s));
+
+        Config::from_str(&s)
+    }
+
+    pub fn from_str(s: &str) -> Result<Config, ConfigError> {
+        let doc = try!(parse_doc(s));
+        if doc.get("include").is_some() {
+            return invalid("`include` is only supported in config files".to_owned());
+        }
+        Config::from_value(doc)
+    }
+
+    // Without a `listeners` mapping the top level is the one listener, named
+    // `default`, as before listener groups existed.
+    fn from_value(doc: Value) -> Result<Config, ConfigError> {
+        let raw: RawConfig = try!(serde_yaml::from_value(doc.clone()));
+
+        let stats_port = match raw.stats_port {
+            Some(p) => Some(try!(port(p, "stats_port"))),
+            None => None,
+        };
+        let threads = try!(at_least(raw.threads, "threads", 1)) as usize;
+        if let Some(ref addr) = raw.self_metrics_node {
+            let resolved = (&addr[..]).to_socket_addrs()
+                .map(|mut addrs| addrs.next().is_some())
+                .unwrap_or(false);
+            if !resolved {
+                return invalid(format!("`self_metrics_node` {} could not be resolved", addr));
+            }
+        }
+        if raw.self_metrics_prefix.contains(|c| c == ':' || c == '|' || c == '\n') {
+            return invalid("`self_metrics_prefix` must be a string without `:`, `|` or \
+                            newlines".to_owned());
+        }
+        let self_metrics_interval = try!(at_least(raw.self_metrics_interval,
+                                                  "self_metrics_interval", 100));
+        let capture = match raw.capture_file {
+            None => None,
+            Some(ref path) if path.is_empty() => {
+                return invalid("`capture_file` must be a path".to_owned())
+            }
+            Some(ref path) => Some(CaptureConfig {
+                path: path.clone(),
+                max_bytes: try!(at_least(raw.capture_max_bytes, "capture_max_bytes",
+                                         4096)) as usize,
+                files: try!(at_least(raw.capture_files, "capture_files", 1)) as usize,
+                max_rate: try!(at_least(raw.capture_max_rate, "capture_max_rate", 1)) as usize,
+            }),
+        };
+        let ready_min_nodes = try!(at_least(raw.ready_min_nodes, "ready_min_nodes", 0));
+        if raw.admin_token.as_ref().map_or(false, |t| t.is_empty()) {
+            return invalid("`admin_token` must be a non-empty string".to_owned());
+        }
+        if raw.admin_shutdown && raw.admin_token.is_none() {
+            return invalid("`admin_shutdown` needs an `admin_token`".to_owned());
+        }
+        if raw.admin_pause && raw.admin_token.is_none() {
+            return invalid("`admin_pause` needs an `admin_token`".to_owned());
+        }
+        let mut cpu_affinity = Vec::new();
+        if let Some(ref list) = raw.cpu_affinity {
+            let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) } as i64;
+            for &c in list.iter() {
+                if c < 0 || c >= cpus {
+                    return invalid(format!("`cpu_affinity` lists CPU {}, this host has CPUs 0 \
+                                            to {}", c, cpus - 1));
+                }
+                cpu_affinity.push(c as usize);
+            }
+            if cpu_affinity.len() != threads {
+                return invalid(format!("`cpu_affinity` lists {} CPUs for {} threads",
+                                       cpu_affinity.len(), threads));
+            }
+        }
+
+        let mut listeners = Vec::new();
+        match raw.listeners {
+            None => {
+                let listener: RawListener = try!(serde_yaml::from_value(doc));
+                listeners.push(try!(ListenerConfig::from_raw("default", &listener)));
+            }
+            Some(ref spec) => {
+                for &(ref name, ref listener) in spec.0.iter() {
+                    let listener = match ListenerConfig::from_raw(name, listener) {
+                        Ok(l) => l,
+                        Err(ConfigError::Invalid(msg)) => {
+                            return invalid(format!("listener `{}`: {}", name, msg));
+                        }
+                        Err(e) => return Err(e),
+                    };
+                    listeners.push(listener);
+                }
+            }
+        }
+        listeners.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut binds = HashSet::new();
+        let mut paths = HashSet::new();
+        for l in listeners.iter() {
+            if !binds.insert(l.bind) {
+                return invalid(format!("listener `{}` reuses bind port {}", l.name, l.bind));
+            }
+            if let Some(ref path) = l.listen.unix_bind {
+                if !paths.insert(path) {
+                    return invalid(format!("listener `{}` reuses unix_bind {}", l.name, path));
+                }
+            }
+            // Every worker binds the port, which only SO_REUSEPORT allows.
+            if !l.listen.reuse_port && threads > 1 {
+                return invalid(format!("listener `{}` has reuse_port off, which needs \
+                                        `threads: 1` ({} configured)", l.name, threads));
+            }
+        }
+        if listeners.is_empty() {
+            return invalid("no listeners configured".to_owned());
+        }
+
+        Ok(Config {
+            stats_host: raw.stats_host,
+            stats_port: stats_port,
+            threads: threads,
+            cpu_affinity: cpu_affinity,
+            ready_min_nodes: ready_min_nodes as usize,
+            admin_shutdown: raw.admin_shutdown,
+            admin_pause: raw.admin_pause,
+            admin_token: raw.admin_token,
+            log_format: raw.log_format,
+            log_level: raw.log_level,
+            self_metrics_node: raw.self_metrics_node,
+            self_metrics_prefix: raw.self_metrics_prefix,
+            self_metrics_interval: Duration::from_millis(self_metrics_interval as u64),
+            capture: capture,
+            listeners: listeners,
+        })
+    }
+
+    pub fn summary(&self) -> String {
+        let mut s = format!("threads: {}\n", self.threads);
+        if !self.cpu_affinity.is_empty() {
+            let cpus: Vec<_> = self.cpu_affinity.iter().map(|c| c.to_string()).collect();
+            s.push_str(&format!("cpu_affinity: {}\n", cpus.join(", ")));
+        }
+        if self.log_format != LogFormat::Text {
+            s.push_str(&format!("log_format: {}\n", self.log_format.name()));
+        }
+        if self.log_level != Level::Info {
+            s.push_str(&format!("log_level: {}\n", self.log_level.name()));
+        }
+        if let Some(ref node) = self.self_metrics_node {
+            s.push_str(&format!("self_metrics: {}* to {} every {}ms\n", self.self_metrics_prefix,
+                                node, millis(self.self_metrics_interval)));
+        }
+        if let Some(ref capture) = self.capture {
+            s.push_str(&format!("capture: {} ({} bytes x {} files, at most {} datagrams/s)\n",
+                                capture.path, capture.max_bytes, capture.files,
+                                capture.max_rate));
+        }
+        if let Some(port) = self.stats_port {
+            s.push_str(&format!("stats: {}:{}\n", self.stats_host, port));
+            s.push_str(&format!("ready_min_nodes: {}\n", self.ready_min_nodes));
+            if self.admin_shutdown {
+                s.push_str("admin_shutdown: on\n");
+            }
+            if self.admin_pause {
+                s.push_str("admin_pause: on\n");
+            }
+        }
+        for l in self.listeners.iter() {
+            s.push_str(&l.summary());
+        }
+        s
+    }
+}
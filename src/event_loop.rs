@@ -1,67 +1,1940 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::str;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
 use mio;
+use mio::util::Slab;
+use regex::bytes::Regex;
+
+use socket::{AddressFamily, InetAddr, MtuDiscover, SockAddr, SockType, Socket, UdpListener,
+             UdpStream, TcpStream, UnixListener};
+use capture::Capture;
+use forwarder::{self, Forwarder, Transport};
+use hash::{self, ConsistentHash, Node, ServerNode, VnodeKey};
+use logging::{self, Level};
+use stats::{NodeStats, Stats};
+use statsd;
+
+// Every event source or timer that isn't a health connection is a `Role`
+// with a token in the control range at the bottom; connections take tokens
+// from the slab right above it, so the slab can be sized (and regrown) from
+// the node count. New roles only need a variant here.
+const CONTROL_START: usize = 0;
+const CONN_START: usize = CONTROL_START + 64;
+const MIN_CONNS: usize = 1024;
+
+// Upper bound on nodes per listener, checked at config load.
+pub const MAX_NODES: usize = 65536;
+
+// Transparent sockets cached per worker, one per client address.
+const MAX_SOURCES: usize = 1024;
+
+// How often retry queues are flushed while draining at shutdown.
+const DRAIN_INTERVAL: u64 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Server,
+    ProbeTimer,
+    EvalTimer,
+    DrainTimer,
+    UnixServer,
+}
+
+const ROLES: &'static [Role] = &[Role::Server, Role::ProbeTimer, Role::EvalTimer,
+                                 Role::DrainTimer, Role::UnixServer];
+
+// Messages sent to a worker's event loop from the main thread.
+pub enum Control {
+    Shutdown,
+    Reload(Vec<ServerNode>),
+}
+
+impl Role {
+    fn token(self) -> mio::Token {
+        mio::Token(CONTROL_START + self as usize)
+    }
+
+    fn from_token(token: mio::Token) -> Option<Role> {
+        if token.as_usize() >= CONN_START {
+            return None;
+        }
+        token.as_usize().checked_sub(CONTROL_START).and_then(|i| ROLES.get(i)).cloned()
+    }
+}
+
+const HEALTH_CHECK: &'static [u8] = b"health\n";
+const HEALTH_UP: &'static [u8] = b"health: up";
+const HTTP_HEALTH_CHECK: &'static [u8] = b"GET /health HTTP/1.0\r\n\r\n";
+
+// How a node's `adminport` is probed: the statsd admin `health` command, or
+// `GET /health` where only a 200 status counts as up.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthType {
+    StatsdAdmin,
+    Http,
+}
+
+impl HealthType {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            HealthType::StatsdAdmin => "statsd_admin",
+            HealthType::Http => "http",
+        }
+    }
+
+    fn request(&self) -> &'static [u8] {
+        match *self {
+            HealthType::StatsdAdmin => HEALTH_CHECK,
+            HealthType::Http => HTTP_HEALTH_CHECK,
+        }
+    }
+
+    fn is_up(&self, reply: &[u8]) -> bool {
+        match *self {
+            HealthType::StatsdAdmin => reply.starts_with(HEALTH_UP),
+            HealthType::Http => {
+                let mut status = reply.split(|x| *x == b' ').filter(|s| !s.is_empty());
+                status.next().map_or(false, |v| v.starts_with(b"HTTP/")) &&
+                    status.next() == Some(&b"200"[..])
+            }
+        }
+    }
+}
+
+#[allow(dead_code)]
+enum State {
+    Reading,
+    Writing,
+    Closed,
+}
+
+// Nodes are probed every `probe_interval`, and every `eval_interval` a node is
+// evicted if `failure_threshold` checks failed within `failure_window`, or
+// re-admitted after `success_threshold` consecutive successful checks. With
+// `enabled` off no admin connections are made and every node stays in the ring.
+#[derive(Clone)]
+pub struct HealthConfig {
+    pub enabled: bool,
+    pub probe_interval: u64,
+    // Each connection's probes are spread randomly over ± this percentage
+    // of `probe_interval`, so nodes aren't all probed at the same instant.
+    pub probe_jitter: u64,
+    pub eval_interval: u64,
+    pub failure_threshold: usize,
+    pub failure_window: Duration,
+    pub success_threshold: u32,
+    // A reply containing this marks a node degraded: it stays in the ring
+    // with `degraded_weight` percent of its vnodes.
+    pub degraded_response: Option<Vec<u8>>,
+    pub degraded_weight: usize,
+    pub max_concurrent_checks: Option<usize>,
+    // Nodes added by a reload start with one vnode and grow to their full
+    // count over this long; zero adds them at full weight right away.
+    pub warmup: Duration,
+    // How many failures one failed check counts as, by check type; 1 for
+    // types not listed. Failed forwards always count once.
+    pub failure_weights: Vec<(HealthType, usize)>,
+    // Every check moves a node's score, an exponentially weighted success
+    // rate starting at 1, `score_alpha` of the way to 1 or 0 (a failure
+    // once per its weight). With `score_eviction` set the score decides
+    // instead of the counts: a node is evicted below the first value and
+    // re-admitted at or above the second.
+    pub score_alpha: f64,
+    pub score_eviction: Option<(f64, f64)>,
+    // Fail-stop: with fewer than this fraction of the nodes in the ring,
+    // datagrams are dropped rather than piled onto the survivors.
+    pub min_healthy_fraction: Option<f64>,
+}
+
+impl HealthConfig {
+    fn failure_weight(&self, kind: HealthType) -> usize {
+        self.failure_weights.iter().find(|&&(k, _)| k == kind).map_or(1, |&(_, w)| w)
+    }
+
+    fn ring_replicas(&self, replicas: usize, degraded: bool) -> usize {
+        if degraded {
+            cmp::max(1, replicas * self.degraded_weight / 100)
+        } else {
+            replicas
+        }
+    }
+
+    fn is_degraded(&self, reply: &[u8]) -> bool {
+        match self.degraded_response {
+            Some(ref pattern) => reply.windows(pattern.len()).any(|w| w == &pattern[..]),
+            None => false,
+        }
+    }
+}
+
+// `Name` routes every metric line of a datagram on its own bare name, so all
+// samples of a timer land on one backend no matter which other metrics a
+// client batched them with; hashing on anything else (the value, type, tags
+// or a neighbouring line) spreads one timer over several backends and each
+// computes percentiles over only part of the samples. `Packet` is the legacy
+// mode that forwards the whole datagram to the owner of its first name.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteMode {
+    Name,
+    Packet,
+}
+
+impl RouteMode {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            RouteMode::Name => "name",
+            RouteMode::Packet => "packet",
+        }
+    }
+}
+
+// What to do with a metric name holding bytes `statsd::name_byte_allowed`
+// refuses: pass it on as is, swap each such byte for `sanitize_replacement`,
+// or drop the line.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Sanitize {
+    Off,
+    Replace,
+    Reject,
+}
+
+impl Sanitize {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Sanitize::Off => "off",
+            Sanitize::Replace => "replace",
+            Sanitize::Reject => "reject",
+        }
+    }
+}
+
+// Metric names that aren't valid UTF-8 are always forwarded byte for byte
+// with `forward`, and anything that shows them as text (logs, /route) does so
+// lossily. `reject` drops those lines before sanitizing or prefixing.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BinaryKeys {
+    Forward,
+    Reject,
+}
+
+impl BinaryKeys {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            BinaryKeys::Forward => "forward",
+            BinaryKeys::Reject => "reject",
+        }
+    }
+}
+
+// Renames metrics matching `pattern`; `$1`, `${name}` and the like in
+// `replacement` expand to the pattern's groups.
+#[derive(Clone)]
+pub struct RewriteRule {
+    pub pattern: Regex,
+    pub replacement: Vec<u8>,
+}
+
+// With `key_segments` set, only the first N `key_delimiter` separated
+// segments of the metric name are hashed. A non-empty `prefix` is prepended to
+// every metric name before routing, so the hashed name is the one backends see.
+#[derive(Clone)]
+pub struct RouteConfig {
+    pub mode: RouteMode,
+    pub key_segments: Option<usize>,
+    pub key_delimiter: u8,
+    pub prefix: Vec<u8>,
+    // Metrics whose name (without `prefix`) starts with one of these go to
+    // every node in the ring instead of the one owning their key.
+    pub broadcast_prefixes: Vec<Vec<u8>>,
+    // Node pool names, each with its own ring; pool 0 is the top level
+    // `nodes`. `type_pools` maps a metric type to the pool it hashes in.
+    pub pools: Vec<String>,
+    pub type_pools: Vec<(Vec<u8>, usize)>,
+    // Byte between a metric's name and value, `key_delimiter` in the config.
+    pub value_delimiter: u8,
+    // Applied before `prefix` is added, so the ring hashes the cleaned name.
+    pub sanitize: Sanitize,
+    pub sanitize_replacement: u8,
+    pub binary_keys: BinaryKeys,
+    // Counts names that hash to another node once stray whitespace is
+    // trimmed, logging one in `dirty_key_sample` of them (0 never). Only
+    // observes: forwarding still uses the name as received.
+    pub dirty_key_check: bool,
+    pub dirty_key_sample: usize,
+    // Counters are downsampled by the proxy: one in `counter_sample_factor`
+    // is kept and its `|@` rate divided by the factor, so the backend still
+    // scales the total back up. The longest of `counter_sample_prefixes`
+    // matching the name as received overrides it; 1 keeps everything.
+    pub counter_sample_factor: usize,
+    pub counter_sample_prefixes: Vec<(Vec<u8>, usize)>,
+    // One in `top_keys_sample` routed keys is counted in `Stats::top_keys`,
+    // which holds at most `top_keys_size` of them.
+    pub track_top_keys: bool,
+    pub top_keys_size: usize,
+    pub top_keys_sample: usize,
+    // Prepended to every key before it is hashed. Placement then depends on
+    // the salt as well as the node names, and any change to it moves nearly
+    // every key, so every proxy in front of a cluster needs the same one.
+    pub hash_salt: Vec<u8>,
+    // How each vnode's point on the ring is named, see `VnodeKey`.
+    pub vnode_key: VnodeKey,
+    // Tried in order on every metric name once sanitized, before `prefix`
+    // is added, so the ring hashes and backends see the new name. Only the
+    // first matching rule applies unless `rewrite_first_match` is off, when
+    // each rule sees the name as the ones before it left it.
+    pub rewrite_rules: Vec<RewriteRule>,
+    pub rewrite_first_match: bool,
+    // Tags every line with `proxy_rx_ms:<unix time in ms>` when its datagram
+    // was read, for tracing latency from client to backend.
+    pub trace_timestamps: bool,
+}
+
+impl RouteConfig {
+    pub fn pool(&self, kind: &[u8]) -> usize {
+        self.type_pools.iter().find(|&&(ref k, _)| &k[..] == kind).map_or(0, |&(_, p)| p)
+    }
+
+    pub fn is_broadcast(&self, name: &[u8]) -> bool {
+        if self.broadcast_prefixes.is_empty() {
+            return false;
+        }
+
+        let name = if name.starts_with(&self.prefix) { &name[self.prefix.len()..] } else { name };
+        self.broadcast_prefixes.iter().any(|p| name.starts_with(p))
+    }
+
+    // The new name for `name`, None when no rule matched.
+    pub fn rename(&self, name: &[u8]) -> Option<Vec<u8>> {
+        let mut renamed: Option<Vec<u8>> = None;
+        for rule in self.rewrite_rules.iter() {
+            let next = {
+                let current = renamed.as_ref().map_or(name, |n| &n[..]);
+                if !rule.pattern.is_match(current) {
+                    continue;
+                }
+                rule.pattern.replace(current, &rule.replacement[..]).into_owned()
+            };
+            renamed = Some(next);
+            if self.rewrite_first_match {
+                break;
+            }
+        }
+        renamed
+    }
+
+    pub fn samples_counters(&self) -> bool {
+        self.counter_sample_factor > 1 || !self.counter_sample_prefixes.is_empty()
+    }
+
+    // Whether datagrams need `Proxy::rewrite` before they're routed.
+    fn rewrites(&self) -> bool {
+        !self.prefix.is_empty() || self.sanitize != Sanitize::Off ||
+            self.binary_keys != BinaryKeys::Forward || self.samples_counters() ||
+            !self.rewrite_rules.is_empty() || self.trace_timestamps
+    }
+
+    // The factor counters named `name` are downsampled by, and which of the
+    // proxy's sampling counters they take turns on: one per prefix, the last
+    // for everything else.
+    pub fn sample_factor(&self, name: &[u8]) -> (usize, usize) {
+        let mut best: Option<(usize, usize)> = None;
+        for (i, &(ref prefix, factor)) in self.counter_sample_prefixes.iter().enumerate() {
+            let longer = best.map_or(true, |(b, _)| {
+                prefix.len() > self.counter_sample_prefixes[b].0.len()
+            });
+            if longer && name.starts_with(prefix) {
+                best = Some((i, factor));
+            }
+        }
+        best.unwrap_or((self.counter_sample_prefixes.len(), self.counter_sample_factor))
+    }
+
+    pub fn owner<'r>(&self, ring: &'r ConsistentHash<ServerNode>,
+                     key: &[u8]) -> Option<&'r ServerNode> {
+        if self.hash_salt.is_empty() {
+            return ring.get(key);
+        }
+        let mut salted = Vec::with_capacity(self.hash_salt.len() + key.len());
+        salted.extend_from_slice(&self.hash_salt);
+        salted.extend_from_slice(key);
+        ring.get(&salted)
+    }
+
+    // The key `line` (a metric name or a whole statsd line) hashes on and
+    // the pool it hashes in, as the forwarding path would see it: sanitized,
+    // renamed and prefixed like `Proxy::rewrite` does, or None where it
+    // would reject the name. A bare name has no type and goes to the default
+    // pool.
+    pub fn route_key(&self, line: &[u8]) -> Option<(Vec<u8>, usize)> {
+        let pool = statsd::parse_with(line, self.value_delimiter).map_or(0, |m| self.pool(m.kind));
+        let name = line.split(|x| *x == self.value_delimiter).next().unwrap_or(b"");
+        if name.is_empty() {
+            return None;
+        }
+        if self.binary_keys == BinaryKeys::Reject && str::from_utf8(name).is_err() {
+            return None;
+        }
+        let clean = name.iter().all(|&b| statsd::name_byte_allowed(b));
+        let name: Vec<u8> = match self.sanitize {
+            Sanitize::Reject if !clean => return None,
+            Sanitize::Replace if !clean => name.iter().map(|&b| {
+                if statsd::name_byte_allowed(b) { b } else { self.sanitize_replacement }
+            }).collect(),
+            _ => name.to_vec(),
+        };
+
+        let mut full = self.prefix.clone();
+        match self.rename(&name) {
+            Some(renamed) => full.extend_from_slice(&renamed),
+            None => full.extend_from_slice(&name),
+        }
+        let len = self.key(&full).len();
+        full.truncate(len);
+        Some((full, pool))
+    }
+
+    pub fn key<'a>(&self, name: &'a [u8]) -> &'a [u8] {
+        let segments = match self.key_segments {
+            Some(n) => n,
+            None => return name,
+        };
+
+        let mut seen = 0;
+        for (i, b) in name.iter().enumerate() {
+            if *b == self.key_delimiter {
+                seen += 1;
+                if seen == segments {
+                    return &name[0..i];
+                }
+            }
+        }
+        name
+    }
+}
+
+// What to do with a packet that would block when the node's retry queue is
+// already full. For statsd, `drop_oldest` keeps the freshest gauge values
+// while `drop_newest` keeps latency bounded for whatever is already queued;
+// counters lose the same number of increments either way. `block` only makes
+// sense for stream backends, on UDP it behaves like `drop_newest` since the
+// event loop must never stall.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    DropNewest,
+    DropOldest,
+    Block,
+}
+
+impl OverflowPolicy {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            OverflowPolicy::DropNewest => "drop_newest",
+            OverflowPolicy::DropOldest => "drop_oldest",
+            OverflowPolicy::Block => "block",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ForwardConfig {
+    pub retry_queue_size: usize,
+    pub overflow_policy: OverflowPolicy,
+    pub max_outstanding_packets: Option<usize>,
+    pub drain_timeout: Duration,
+    pub transparent: bool,
+    pub dead_letter_node: Option<String>,
+    pub source_addr: Option<IpAddr>,
+    // Forward latency is measured for one in every `latency_sample` datagrams.
+    pub latency_sample: usize,
+    // Diagnostics only: count datagrams sent per node for /stats.
+    pub forward_seq: bool,
+    pub allow_sources: AllowList,
+    // Largest datagram sent to a node; longer ones are cut at line breaks.
+    pub backend_mtu: usize,
+    // Other nodes tried, one after another, when a send fails outright.
+    pub forward_retries: usize,
+    // IP_MTU_DISCOVER for forwarding sockets; None leaves the kernel's.
+    pub mtu_discover: Option<MtuDiscover>,
+    // Datagrams each worker holds while paused, forwarded on resume; past
+    // that they are dropped (all of them with 0).
+    pub pause_buffer: usize,
+    // Diagnostics for garbled metrics: sums a checksum of every datagram
+    // sent per node, and checks retried and forwarded datagrams still match
+    // what was queued or read. Debug builds always check.
+    pub checksum: bool,
+}
+
+impl ForwardConfig {
+    pub fn verifies(&self) -> bool {
+        self.checksum || cfg!(debug_assertions)
+    }
+}
+
+// FNV-1a, cheap enough to run on every datagram.
+pub fn checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c9dc5, |h, &b| (h ^ b as u32).wrapping_mul(0x01000193))
+}
+
+// Rules out the proxy's own buffer handling when metrics arrive garbled:
+// `data` must still be what summed to `sum`. Counted and logged; debug
+// builds panic.
+fn verify(data: &[u8], sum: u32, what: &str, stats: &Stats) -> bool {
+    if checksum(data) == sum {
+        return true;
+    }
+    let total = stats.checksum_mismatches.fetch_add(1, Ordering::Relaxed) + 1;
+    log!(Error, "{} datagram changed inside the proxy: {:?} ({} total)",
+         what, String::from_utf8_lossy(data), total);
+    debug_assert!(false, "{} datagram changed inside the proxy", what);
+    false
+}
+
+// A retry queue entry for `packet`.
+fn queued(packet: &[u8], forward: &ForwardConfig) -> (Vec<u8>, Option<u32>) {
+    let sum = if forward.verifies() { Some(checksum(packet)) } else { None };
+    (packet.to_vec(), sum)
+}
+
+// Source networks UDP datagrams are accepted from, kept as sorted, merged
+// inclusive IPv4 ranges so a lookup is one binary search. An empty list
+// accepts every source.
+#[derive(Clone, Default)]
+pub struct AllowList {
+    pub cidrs: Vec<String>,
+    ranges: Vec<(u32, u32)>,
+}
+
+impl AllowList {
+    pub fn new(cidrs: Vec<String>, mut ranges: Vec<(u32, u32)>) -> AllowList {
+        ranges.sort();
+        let mut merged: Vec<(u32, u32)> = Vec::new();
+        for (start, end) in ranges.into_iter() {
+            if let Some(last) = merged.last_mut() {
+                if start <= last.1.saturating_add(1) {
+                    last.1 = cmp::max(last.1, end);
+                    continue;
+                }
+            }
+            merged.push((start, end));
+        }
+
+        AllowList {
+            cidrs: cidrs,
+            ranges: merged,
+        }
+    }
+
+    pub fn allows(&self, addr: &SocketAddr) -> bool {
+        if self.ranges.is_empty() {
+            return true;
+        }
+
+        let ip = match *addr {
+            SocketAddr::V4(ref a) => u32::from(*a.ip()),
+            SocketAddr::V6(_) => return false,
+        };
+        match self.ranges.binary_search_by(|&(start, _)| start.cmp(&ip)) {
+            Ok(_) => true,
+            Err(0) => false,
+            Err(i) => ip <= self.ranges[i - 1].1,
+        }
+    }
+}
+
+// Forwarding sockets of a worker, one per source address in use.
+fn forward_socket(socks: &mut HashMap<Option<IpAddr>, Socket>, source: Option<IpAddr>,
+                  mtu_discover: Option<MtuDiscover>) -> io::Result<Socket> {
+    if let Some(sock) = socks.get(&source) {
+        return Ok(sock.clone());
+    }
+
+    let sock = try!(Socket::new(AddressFamily::Inet, SockType::Datagram, true));
+    if let Some(ip) = source {
+        try!(sock.bind(&SockAddr::Inet(InetAddr::from_std(&SocketAddr::new(ip, 0)))));
+    }
+    if let Some(mode) = mtu_discover {
+        try!(sock.set_mtu_discover(mode));
+    }
+    socks.insert(source, sock.clone());
+    Ok(sock)
+}
+
+// What became of a datagram handed to `Connection::send`.
+struct Sent {
+    // Datagrams waiting in the retry queue.
+    queued: usize,
+    // Where the data that wasn't sent starts, after a send failed outright.
+    failed_at: Option<usize>,
+}
+
+// Sends `data` to `token`'s node; if that fails outright the unsent rest
+// goes to up to `forward_retries` failover nodes in `ring`. Returns how many
+// datagrams were queued for a retry.
+fn send_with_failover(conns: &mut Slab<Connection>, tokens: &[mio::Token],
+                      ring: &ConsistentHash<ServerNode>, token: mio::Token, data: &[u8],
+                      via: Option<&Socket>, health: &HealthConfig, forward: &ForwardConfig,
+                      stats: &Stats) -> usize {
+    let mut sent = conns[token].send(data, via, health, forward, stats);
+    let mut queued = sent.queued;
+    if sent.failed_at.is_none() || forward.forward_retries == 0 {
+        return queued;
+    }
+
+    let primary = conns[token].node.name();
+    let mut tried = vec![conns[token].node.id];
+    let mut rest = data;
+    while let Some(at) = sent.failed_at {
+        if tried.len() > forward.forward_retries {
+            break;
+        }
+        let next = match hash::failover(ring, &primary, &tried) {
+            Some(node) => tokens[node.id],
+            None => break,
+        };
+        rest = &rest[at..];
+        tried.push(conns[next].node.id);
+        stats.failover_forwards.fetch_add(1, Ordering::Relaxed);
+        sent = conns[next].send(rest, via, health, forward, stats);
+        queued += sent.queued;
+    }
+    queued
+}
+
+struct Connection {
+    stream: Option<TcpStream>,
+    out: Box<Forwarder>,
+    token: mio::Token,
+    node: ServerNode,
+    buf: Vec<u8>,
+    registered: bool,
+    pending: bool,
+    next_probe: Instant,
+    failures: VecDeque<Instant>,
+    success: u32,
+    in_ring: bool,
+    // `reported_degraded` is what the last reply said, `degraded` what the
+    // ring currently reflects; `evaluate` brings them in line.
+    reported_degraded: bool,
+    degraded: bool,
+    forward_errors: usize,
+    // With the checksum taken when queued when `ForwardConfig::verifies`.
+    queue: VecDeque<(Vec<u8>, Option<u32>)>,
+    // The queue length last added to `NodeStats::queue_depth`.
+    depth: usize,
+    dropped_overflow: usize,
+    batch: Vec<u8>,
+    // Scratch space for adding the node's tags in `send`.
+    tagged: Vec<u8>,
+    seq: Option<Arc<AtomicUsize>>,
+    // While ramping up after a reload added the node: when it started and
+    // the vnodes it has in the ring now.
+    warmup: Option<(Instant, usize)>,
+    // See `HealthConfig::score_alpha`.
+    score: f64,
+    // Set when the admin connection is dropped after a failure, so the
+    // connects that follow count as reconnects until one succeeds.
+    lost: bool,
+    // When the health check waiting for its reply was written.
+    check_sent: Option<Instant>,
+    history: Arc<NodeStats>,
+    capture: Option<Capture>,
+}
+
+impl Connection {
+    fn new(token: mio::Token, node: ServerNode, stream: Option<TcpStream>,
+           sock: Socket, seq: Option<Arc<AtomicUsize>>, history: Arc<NodeStats>) -> Connection {
+        history.score.store(1000, Ordering::Relaxed);
+        Connection {
+            stream: stream,
+            out: forwarder::forwarder(node.transport, sock, node.addr, history.clone()),
+            token: token,
+            node: node,
+            buf: vec![0;128],
+            registered: false,
+            pending: false,
+            next_probe: Instant::now(),
+            failures: VecDeque::new(),
+            success: 0,
+            in_ring: true,
+            reported_degraded: false,
+            degraded: false,
+            forward_errors: 0,
+            queue: VecDeque::new(),
+            depth: 0,
+            dropped_overflow: 0,
+            batch: Vec::new(),
+            tagged: Vec::new(),
+            seq: seq,
+            warmup: None,
+            score: 1.0,
+            lost: false,
+            check_sent: None,
+            history: history,
+            capture: None,
+        }
+    }
+
+    // Adds the node's tags to every line of `data`, after routing so keys
+    // still hash on the line as received, then sends it.
+    fn send(&mut self, data: &[u8], via: Option<&Socket>, health: &HealthConfig,
+            forward: &ForwardConfig, stats: &Stats) -> Sent {
+        if self.node.tags.is_empty() {
+            return self.send_split(data, via, health, forward, stats);
+        }
+
+        let mut tagged = mem::replace(&mut self.tagged, Vec::new());
+        tagged.clear();
+        for (i, line) in data.split(|x| *x == b'\n').enumerate() {
+            if i > 0 {
+                tagged.push(b'\n');
+            }
+            if !line.is_empty() {
+                statsd::append_tags(line, &self.node.tags, &mut tagged);
+            }
+        }
+        let mut sent = self.send_split(&tagged, via, health, forward, stats);
+
+        // Tagging keeps the newlines, so the untagged rest for a failover
+        // node starts after as many lines of `data`.
+        if let Some(at) = sent.failed_at {
+            let lines = tagged[0..at].iter().filter(|x| **x == b'\n').count();
+            sent.failed_at = Some(match lines {
+                0 => 0,
+                n => data.iter().enumerate().filter(|&(_, x)| *x == b'\n')
+                    .nth(n - 1)
+                    .map_or(data.len(), |(i, _)| i + 1),
+            });
+        }
+        self.tagged = tagged;
+        sent
+    }
+
+    // Sends `data` in datagrams of at most `backend_mtu` bytes, split between
+    // lines; a single line over the limit goes out whole (and fragmented).
+    // Stops at the first send that fails outright.
+    fn send_split(&mut self, data: &[u8], via: Option<&Socket>, health: &HealthConfig,
+                  forward: &ForwardConfig, stats: &Stats) -> Sent {
+        let mtu = forward.backend_mtu;
+        let mut queued = 0;
+        let mut rest = data;
+        while !rest.is_empty() {
+            let cut = if rest.len() <= mtu {
+                rest.len()
+            } else {
+                match rest[0..mtu + 1].iter().rposition(|x| *x == b'\n') {
+                    Some(i) => i,
+                    None => {
+                        let end = rest.iter().position(|x| *x == b'\n').unwrap_or(rest.len());
+                        let total = stats.oversize_forwards.fetch_add(1, Ordering::Relaxed) + 1;
+                        log!(Warn, "{} byte line to {}:{} exceeds backend_mtu {} \
+                              ({} total)", end, self.node.host, self.node.port, mtu, total);
+                        end
+                    }
+                }
+            };
+            if cut > 0 {
+                match self.send_one(&rest[0..cut], via, health, forward, stats) {
+                    Ok(true) => queued += 1,
+                    Ok(false) => {}
+                    Err(_) => {
+                        return Sent { queued: queued, failed_at: Some(data.len() - rest.len()) };
+                    }
+                }
+            }
+            rest = &rest[cmp::min(cut + 1, rest.len())..];
+        }
+        Sent { queued: queued, failed_at: None }
+    }
+
+    // Returns true if `data` was queued to retry later. With `via` a UDP
+    // datagram is sent from that socket instead of the node's forwarding
+    // socket; queued retries always go out from the forwarding socket.
+    fn send_one(&mut self, data: &[u8], via: Option<&Socket>, health: &HealthConfig,
+                forward: &ForwardConfig, stats: &Stats) -> io::Result<bool> {
+        let res = self.out.send(data, via);
+        match res {
+            Ok(Some(n)) => {
+                self.check_short(n, data.len(), stats);
+                self.count_sent(data, forward, stats);
+                Ok(false)
+            }
+            Ok(None) => Ok(self.enqueue(data, forward, stats)),
+            Err(e) => {
+                self.forward_errors += 1;
+                self.history.forward_errors.fetch_add(1, Ordering::Relaxed);
+                event!(Warn, "forward_failed", self.node.name(),
+                       "forward to {}:{} failed: {} ({} errors)",
+                       self.node.host, self.node.port, e, self.forward_errors);
+                self.record_failure(health, Instant::now());
+                Err(e)
+            }
+        }
+    }
+
+    // A short UDP write means the backend got a truncated line, which it
+    // parses as a corrupt metric. It points at a kernel or buffer problem.
+    fn check_short(&self, sent: usize, len: usize, stats: &Stats) {
+        if sent < len {
+            let total = stats.short_forwards.fetch_add(1, Ordering::Relaxed) + 1;
+            log!(Warn, "short forward to {}:{}, sent {} of {} bytes ({} total)",
+                 self.node.host, self.node.port, sent, len, total);
+        }
+    }
+
+    fn count_sent(&self, data: &[u8], forward: &ForwardConfig, stats: &Stats) {
+        self.history.forwarded.fetch_add(1, Ordering::Relaxed);
+        if let Some(ref capture) = self.capture {
+            if !capture.record(&self.node.name(), data) {
+                stats.capture_drops.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if forward.checksum {
+            self.history.sent_checksum.fetch_add(checksum(data) as usize, Ordering::Relaxed);
+        }
+        if let Some(ref seq) = self.seq {
+            seq.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Queues a packet whose send would block. Returns true if the queue grew.
+    fn enqueue(&mut self, packet: &[u8], forward: &ForwardConfig, stats: &Stats) -> bool {
+        if self.queue.len() < forward.retry_queue_size {
+            if !stats.reserve(forward.max_outstanding_packets) {
+                return false;
+            }
+            self.queue.push_back(queued(packet, forward));
+            self.publish_depth();
+            return true;
+        }
+
+        self.dropped_overflow += 1;
+        event!(Warn, "retry_queue_full", self.node.name(),
+               "retry queue for {}:{} is full, dropped {} packets",
+               self.node.host, self.node.port, self.dropped_overflow);
+
+        match forward.overflow_policy {
+            OverflowPolicy::DropOldest => {
+                self.queue.pop_front();
+                self.queue.push_back(queued(packet, forward));
+            }
+            OverflowPolicy::DropNewest | OverflowPolicy::Block => {}
+        }
+        false
+    }
+
+    // Brings this worker's share of the node's queue depth gauge up to date.
+    fn publish_depth(&mut self) {
+        let len = self.queue.len();
+        if len > self.depth {
+            self.history.queue_grew(len - self.depth);
+        } else if len < self.depth {
+            self.history.queue_depth.fetch_sub(self.depth - len, Ordering::Relaxed);
+        }
+        self.depth = len;
+    }
+
+    // Sends queued packets until the socket would block. Returns how many
+    // packets left the queue.
+    fn flush(&mut self, forward: &ForwardConfig, stats: &Stats) -> io::Result<usize> {
+        let mut n = 0;
+
+        while let Some((packet, sum)) = self.queue.pop_front() {
+            if let Some(sum) = sum {
+                if !verify(&packet, sum, "queued", stats) {
+                    n += 1;
+                    continue;
+                }
+            }
+            match self.out.send(&packet, None) {
+                Ok(Some(sent)) => {
+                    self.check_short(sent, packet.len(), stats);
+                    self.count_sent(&packet, forward, stats);
+                    n += 1;
+                }
+                Ok(None) => {
+                    self.queue.push_front((packet, sum));
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(n)
+    }
+
+    // Drops the admin connection; `Proxy::check` opens a new one when the
+    // node is next probed.
+    fn close_stream(&mut self, event_loop: &mut mio::EventLoop<Proxy>) {
+        if self.registered {
+            if let Some(ref stream) = self.stream {
+                let _ = event_loop.deregister(stream);
+            }
+            self.registered = false;
+        }
+        self.stream = None;
+    }
+
+    fn lose_stream(&mut self, event_loop: &mut mio::EventLoop<Proxy>) {
+        self.close_stream(event_loop);
+        self.lost = true;
+    }
+
+    fn connect_stream(&mut self) {
+        if self.lost {
+            self.history.reconnects.fetch_add(1, Ordering::Relaxed);
+        }
+        match TcpStream::connect((&self.node.host[..], self.node.adminport)) {
+            Ok(stream) => {
+                self.stream = Some(stream);
+                self.lost = false;
+            }
+            Err(e) => {
+                log!(Warn, "reconnect to {}:{} failed: {}", self.node.host, self.node.adminport, e);
+                self.lost = true;
+            }
+        }
+    }
+
+    fn register(&mut self, event_loop: &mut mio::EventLoop<Proxy>, events: mio::EventSet) {
+        let res = match self.stream {
+            Some(ref stream) if self.registered =>
+                event_loop.reregister(stream, self.token, events, mio::PollOpt::oneshot()),
+            Some(ref stream) =>
+                event_loop.register_opt(stream, self.token, events, mio::PollOpt::oneshot()),
+            None => return,
+        };
+
+        match res {
+            Ok(_) => self.registered = true,
+            Err(e) => log!(Error, "register health check for {}:{} failed: {}",
+                           self.node.host, self.node.adminport, e),
+        }
+    }
+
+    fn record_failure(&mut self, health: &HealthConfig, now: Instant) {
+        self.record_failures(health, now, 1);
+    }
+
+    fn record_check_failure(&mut self, health: &HealthConfig, now: Instant) {
+        let weight = health.failure_weight(self.node.health_type);
+        self.record_failures(health, now, weight);
+    }
+
+    fn record_failures(&mut self, health: &HealthConfig, now: Instant, n: usize) {
+        self.success = 0;
+        for _ in 0..n {
+            self.failures.push_back(now);
+            self.score *= 1.0 - health.score_alpha;
+        }
+        self.publish_score();
+
+        // Only the most recent `failure_threshold` failures matter.
+        while self.failures.len() > health.failure_threshold {
+            self.failures.pop_front();
+        }
+    }
+
+    fn record_success(&mut self, health: &HealthConfig) {
+        self.success += 1;
+        self.score += health.score_alpha * (1.0 - self.score);
+        self.publish_score();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        self.history.last_success.store(now as usize, Ordering::Relaxed);
+    }
+
+    fn evaluate(&mut self, ring: &mut ConsistentHash<ServerNode>, health: &HealthConfig,
+                now: Instant) {
+        while let Some(&t) = self.failures.front() {
+            if now.duration_since(t) <= health.failure_window {
+                break;
+            }
+            self.failures.pop_front();
+        }
+
+        let (down, up) = match health.score_eviction {
+            Some((evict_below, readmit_at)) => (self.score < evict_below, self.score >= readmit_at),
+            None => (self.failures.len() >= health.failure_threshold,
+                     self.success >= health.success_threshold),
+        };
+        // Without an admin port failed forwards still fail over, but nothing
+        // could tell the node is back, so it is never evicted.
+        let down = down && self.node.health_checked();
+        if self.in_ring && down {
+            event!(Warn, "node_down", self.node.name(), "node {}:{} is down, removing from ring",
+                   self.node.host, self.node.port);
+            ring.remove(&self.node);
+            self.in_ring = false;
+            self.end_warmup();
+            self.success = 0;
+            self.failures.clear();
+        } else if !self.in_ring && up {
+            event!(Info, "node_up", self.node.name(), "node {}:{} is up, adding to ring",
+                   self.node.host, self.node.port);
+            self.degraded = self.reported_degraded;
+            ring.add(&self.node, health.ring_replicas(self.node.replicas, self.degraded));
+            self.in_ring = true;
+            self.success = 0;
+            self.failures.clear();
+        } else if self.in_ring && self.degraded != self.reported_degraded {
+            self.degraded = self.reported_degraded;
+            let replicas = health.ring_replicas(self.node.replicas, self.degraded);
+            let (event, state) = if self.degraded {
+                ("node_degraded", "degraded")
+            } else {
+                ("node_healthy", "healthy again")
+            };
+            event!(Info, event, self.node.name(), "node {}:{} is {}, {} vnodes",
+                   self.node.host, self.node.port, state, replicas);
+            ring.remove(&self.node);
+            ring.add(&self.node, replicas);
+            self.end_warmup();
+        } else if let Some((start, current)) = self.warmup {
+            // Vnode i hashes the same whatever the total, so growing the count
+            // only takes keys over for the new vnodes.
+            let full = health.ring_replicas(self.node.replicas, self.degraded);
+            let elapsed = now.duration_since(start);
+            let target = if elapsed >= health.warmup {
+                full
+            } else {
+                cmp::max(1, full * ms(elapsed) as usize / ms(health.warmup) as usize)
+            };
+            if target != current {
+                ring.add(&self.node, target);
+            }
+            if target == full {
+                event!(Info, "node_warmed_up", self.node.name(), "node {}:{} warmed up, {} vnodes",
+                       self.node.host, self.node.port, full);
+                self.end_warmup();
+            } else {
+                self.warmup = Some((start, target));
+                self.history.warmup.store(target * 100 / full, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn publish_score(&self) {
+        self.history.score.store((self.score * 1000.0).round() as usize, Ordering::Relaxed);
+    }
+
+    // Vnodes of the node in the ring right now.
+    fn vnodes(&self, health: &HealthConfig) -> usize {
+        match self.warmup {
+            _ if !self.in_ring => 0,
+            Some((_, n)) => n,
+            None => health.ring_replicas(self.node.replicas, self.degraded),
+        }
+    }
+
+    fn start_warmup(&mut self, now: Instant) {
+        self.warmup = Some((now, 1));
+        self.history.warmup.store(0, Ordering::Relaxed);
+    }
+
+    fn end_warmup(&mut self) {
+        if self.warmup.take().is_some() {
+            self.history.warmup.store(100, Ordering::Relaxed);
+        }
+    }
+}
+
+pub struct Proxy {
+    server: UdpListener,
+    unix: Option<UnixListener>,
+    level: bool,
+    read_buf: Vec<u8>,
+    scratch: Vec<u8>,
+    // A line's value, type and fields, for `rewrite` to add the trace tag.
+    tail: Vec<u8>,
+    state: State,
+    // One per pool, indexed by `ServerNode::pool`. Evictions and reloads
+    // run on this loop between datagrams, so one is routed against one
+    // ring state and never to a connection that was already removed.
+    rings: Vec<ConsistentHash<ServerNode>>,
+    conns: Slab<Connection>,
+    capacity: usize,
+    tokens: Vec<mio::Token>,
+    batched: Vec<mio::Token>,
+    sources: HashMap<SocketAddr, Socket>,
+    dead_letter: Option<UdpStream>,
+    // Nodes sharing a source address share a forwarding socket.
+    forward_socks: HashMap<Option<IpAddr>, Socket>,
+    health: HealthConfig,
+    route: RouteConfig,
+    forward: ForwardConfig,
+    queued: usize,
+    draining: Option<Instant>,
+    rx_drops: u32,
+    reads: usize,
+    drain_start: usize,
+    rng: u64,
+    // Datagrams read while `stats.paused` was set, with their source.
+    held: VecDeque<(Vec<u8>, Option<SockAddr>)>,
+    // Counters seen per `RouteConfig::sample_factor` counter.
+    sample_seen: Vec<usize>,
+    capture: Option<Capture>,
+    stats: Arc<Stats>,
+}
+
+// xorshift64, good enough to spread probes.
+fn next_random(state: &mut u64) -> u64 {
+    let mut x = *state;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    *state = x;
+    x
+}
+
+// `interval` moved randomly by up to `jitter` percent either way.
+fn jittered(rng: &mut u64, interval: u64, jitter: u64) -> u64 {
+    let spread = interval * jitter / 100;
+    if spread == 0 {
+        return interval;
+    }
+    interval - spread + next_random(rng) % (2 * spread + 1)
+}
+
+fn ms(d: Duration) -> u64 {
+    d.as_secs() * 1000 + (d.subsec_nanos() / 1000000) as u64
+}
+
+impl Proxy {
+    pub fn new(server: UdpListener, mut node_conf: Vec<ServerNode>, health: HealthConfig,
+               route: RouteConfig, forward: ForwardConfig, stats: Arc<Stats>) -> Proxy {
+        // Nodes whose forwarding socket can't be opened are left out, as on
+        // reload.
+        let mut forward_socks = HashMap::new();
+        node_conf.retain(|node| {
+            match forward_socket(&mut forward_socks, node.source_addr, forward.mtu_discover) {
+                Ok(_) => true,
+                Err(e) => {
+                    log!(Error, "no forwarding socket for {}: {}", node.name(), e);
+                    false
+                }
+            }
+        });
+        let rings = hash::build_rings(&mut node_conf, route.pools.len(), &route.vnode_key);
+        for role in ROLES.iter() {
+            let t = role.token().as_usize();
+            assert!(t < CONN_START, "{:?} token {} overlaps the connection slab", role, t);
+        }
+
+        // Room to add as many nodes again on reload before the slab regrows.
+        let capacity = cmp::max(MIN_CONNS, node_conf.len() * 2);
+        let mut conns = Slab::new_starting_at(mio::Token(CONN_START), capacity);
+        let mut tokens = Vec::new();
+
+        for node in node_conf.iter() {
+            // A node that can't be reached yet still gets its connection;
+            // probes keep reconnecting until it answers.
+            let stream = if health.enabled && node.health_checked() {
+                match TcpStream::connect((&node.host[..], node.adminport)) {
+                    Ok(stream) => Some(stream),
+                    Err(e) => {
+                        log!(Warn, "connect to {}:{} failed: {}", node.host, node.adminport, e);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            let lost = health.enabled && node.health_checked() && stream.is_none();
+            let sock = forward_socks[&node.source_addr].clone();
+            let seq = if forward.forward_seq {
+                Some(stats.forward_seq(&node.name()))
+            } else {
+                None
+            };
+            let token = conns.insert_with(|token| {
+                let mut c = Connection::new(token, node.clone(), stream, sock, seq,
+                                            stats.node(&node.name()));
+                c.lost = lost;
+                c
+            }).expect("connection slab is sized from the node count");
+            if tokens.len() <= node.id {
+                tokens.resize(node.id + 1, mio::Token(0));
+            }
+            tokens[node.id] = token;
+        }
+
+        let dead_letter = forward.dead_letter_node.as_ref().and_then(|addr| {
+            match UdpStream::new(&addr[..]) {
+                Ok(stream) => Some(stream),
+                Err(e) => {
+                    log!(Error, "no socket for dead_letter_node {}, running without it: {}",
+                         addr, e);
+                    None
+                }
+            }
+        });
+        let sample_seen = vec![0; route.counter_sample_prefixes.len() + 1];
+
+        Proxy {
+            server: server,
+            unix: None,
+            level: false,
+            read_buf: vec![0;4096],
+            scratch: Vec::new(),
+            tail: Vec::new(),
+            state: State::Reading,
+            rings: rings,
+            conns: conns,
+            capacity: capacity,
+            tokens: tokens,
+            batched: Vec::new(),
+            sources: HashMap::new(),
+            dead_letter: dead_letter,
+            forward_socks: forward_socks,
+            health: health,
+            route: route,
+            forward: forward,
+            queued: 0,
+            draining: None,
+            rx_drops: 0,
+            reads: 0,
+            drain_start: 0,
+            rng: SystemTime::now().duration_since(UNIX_EPOCH)
+                .map(|d| d.subsec_nanos() as u64 ^ d.as_secs())
+                .unwrap_or(0) | 1,
+            held: VecDeque::new(),
+            sample_seen: sample_seen,
+            capture: None,
+            stats: stats,
+        }
+    }
+
+    // Edge triggered oneshot (the default) hands over one readiness event
+    // per registration, so every path out of `read` must reregister or the
+    // listener goes quiet. Level triggered keeps reporting readiness while
+    // datagrams are queued, so nothing is rearmed and a missed reregister
+    // can't stall it. Call before `start`.
+    pub fn set_level_triggered(&mut self, level: bool) {
+        self.level = level;
+    }
+
+    // Copies every datagram forwarded to `capture`, nodes added by a reload
+    // included.
+    pub fn set_capture(&mut self, capture: Capture) {
+        for c in self.conns.iter_mut() {
+            c.capture = Some(capture.clone());
+        }
+        self.capture = Some(capture);
+    }
+
+    // Also reads from a Unix datagram socket, feeding the same ring. Call
+    // before `start`.
+    pub fn add_unix(&mut self, listener: UnixListener) {
+        self.unix = Some(listener);
+    }
+
+    pub fn start(&mut self, event_loop: &mut mio::EventLoop<Proxy>) {
+        let opt = if self.level { mio::PollOpt::level() } else { mio::PollOpt::edge() };
+        event_loop.register_opt(
+            &self.server, Role::Server.token(),
+            mio::EventSet::readable() |
+                mio::EventSet::hup() |
+                mio::EventSet::error(),
+            opt).unwrap();
+        if let Some(ref unix) = self.unix {
+            event_loop.register_opt(
+                unix, Role::UnixServer.token(),
+                mio::EventSet::readable() |
+                    mio::EventSet::hup() |
+                    mio::EventSet::error(),
+                opt).unwrap();
+        }
+
+        self.publish_ring("startup");
+        self.publish_sockets();
+
+        // Stagger the first probes as well.
+        let now = Instant::now();
+        let spread = self.health.probe_interval * self.health.probe_jitter / 100;
+        if spread > 0 {
+            for c in self.conns.iter_mut() {
+                let delay = next_random(&mut self.rng) % (spread + 1);
+                c.next_probe = now + Duration::from_millis(delay);
+            }
+        }
 
-use socket::{UdpListener};
-use hash::{ConsistentHash, ServerNode};
+        // The probe timer also flushes retry queues, so it runs either way.
+        event_loop.timeout_ms(Role::ProbeTimer, self.health.probe_interval).unwrap();
+        if self.health.enabled {
+            event_loop.timeout_ms(Role::EvalTimer, self.health.eval_interval).unwrap();
+        }
+    }
 
-pub const SERVER: mio::Token = mio::Token(0);
+    // Applies a new node list. Nodes that are gone are dropped along with
+    // their retry queues, new nodes start out in the ring, and nodes that stay
+    // keep their health state and id. Only the vnodes of nodes that changed
+    // are touched, the rest of the ring is left as it is.
+    pub fn reload(&mut self, event_loop: &mut mio::EventLoop<Proxy>,
+                  mut nodes: Vec<ServerNode>) {
+        nodes.sort_by(|a, b| a.name().cmp(&b.name()));
+        let names: HashSet<String> = nodes.iter().map(|n| n.name()).collect();
+        let mut added = Vec::new();
+        let mut removed = Vec::new();
+        let mut free_ids = Vec::new();
 
-#[allow(dead_code)]
-enum State {
-    Reading,
-    Writing,
-    Closed,
-}
+        let gone: Vec<mio::Token> = self.conns.iter()
+            .filter(|c| !names.contains(&c.node.name()))
+            .map(|c| c.token)
+            .collect();
+        for token in gone {
+            if let Some(mut c) = self.conns.remove(token) {
+                if c.registered {
+                    if let Some(ref stream) = c.stream {
+                        let _ = event_loop.deregister(stream);
+                    }
+                }
+                self.queued -= c.queue.len();
+                self.stats.release(c.queue.len(), self.forward.max_outstanding_packets);
+                c.queue.clear();
+                c.publish_depth();
+                event!(Info, "node_removed", c.node.name(), "removed node {}", c.node.name());
+                free_ids.push(c.node.id);
+                if c.in_ring {
+                    removed.push(c.node);
+                }
+            }
+        }
 
-pub struct Proxy {
-    server: UdpListener,
-    read_buf: Vec<u8>,
-    state: State,
-    ring: ConsistentHash<ServerNode>,
-}
+        let mut existing: HashMap<String, mio::Token> = self.conns.iter()
+            .map(|c| (c.node.name(), c.token))
+            .collect();
+        let new = nodes.iter().filter(|n| !existing.contains_key(&n.name())).count();
+        if existing.len() + new > self.capacity {
+            let capacity = (existing.len() + new) * 2;
+            self.grow_conns(event_loop, capacity);
+            existing = self.conns.iter().map(|c| (c.node.name(), c.token)).collect();
+        }
 
-impl Proxy {
-    pub fn new(server: UdpListener, node_conf: Vec<ServerNode>) -> Proxy {
-        let mut ring = ConsistentHash::new();
-        for node in node_conf.iter() {
-            ring.add(node, 20);
+        for mut node in nodes.into_iter() {
+            let name = node.name();
+            let sock = match forward_socket(&mut self.forward_socks, node.source_addr,
+                                            self.forward.mtu_discover) {
+                Ok(sock) => sock,
+                Err(e) => {
+                    log!(Error, "no forwarding socket for {}: {}", name, e);
+                    continue;
+                }
+            };
+
+            if let Some(&token) = existing.get(&name) {
+                let c = &mut self.conns[token];
+                node.id = c.node.id;
+                let moved = c.node.replicas != node.replicas || c.node.pool != node.pool;
+                if c.in_ring && moved {
+                    removed.push(c.node.clone());
+                    let mut n = node.clone();
+                    n.replicas = self.health.ring_replicas(node.replicas, c.degraded);
+                    added.push(n);
+                }
+                // An open TCP connection is kept, a UDP node picks up its
+                // forwarding socket for a changed source address.
+                if node.transport != Transport::Tcp || c.node.transport != Transport::Tcp {
+                    c.out = forwarder::forwarder(node.transport, sock, node.addr,
+                                                 c.history.clone());
+                }
+                // The check in flight, if any, went to the old admin port or
+                // protocol; the next one connects afresh right away.
+                if node.adminport != c.node.adminport || node.health_type != c.node.health_type {
+                    c.close_stream(event_loop);
+                    c.pending = false;
+                    c.check_sent = None;
+                    c.next_probe = Instant::now();
+                }
+                c.node = node;
+                continue;
+            }
+
+            node.id = free_ids.pop().unwrap_or(self.tokens.len());
+
+            let stream = if self.health.enabled && node.health_checked() {
+                match TcpStream::connect((&node.host[..], node.adminport)) {
+                    Ok(stream) => Some(stream),
+                    Err(e) => {
+                        log!(Warn, "connect to {}:{} failed: {}", node.host, node.adminport, e);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            let id = node.id;
+            let warm = self.health.enabled && self.health.warmup > Duration::from_millis(0);
+            let mut entry = node.clone();
+            if warm {
+                entry.replicas = 1;
+            }
+            added.push(entry);
+            let seq = if self.forward.forward_seq {
+                Some(self.stats.forward_seq(&name))
+            } else {
+                None
+            };
+            let history = self.stats.node(&name);
+            let capture = self.capture.clone();
+            let inserted = self.conns.insert_with(|token| {
+                let mut c = Connection::new(token, node, stream, sock, seq, history);
+                c.capture = capture;
+                c
+            });
+            let token = match inserted {
+                Some(token) => token,
+                None => {
+                    added.pop();
+                    free_ids.push(id);
+                    event!(Error, "node_not_added", name, "too many nodes, {} not added", name);
+                    continue;
+                }
+            };
+            if self.tokens.len() <= id {
+                self.tokens.resize(id + 1, mio::Token(0));
+            }
+            self.tokens[id] = token;
+            if warm {
+                self.conns[token].start_warmup(Instant::now());
+            }
+            event!(Info, "node_added", name, "added node {}", name);
         }
 
-        Proxy {
-            server: server,
-            read_buf: vec![0;4096],
-            state: State::Reading,
-            ring: ring,
+        let start = Instant::now();
+        let mut vnodes = 0;
+        for (pool, ring) in self.rings.iter_mut().enumerate() {
+            let added: Vec<ServerNode> = added.iter().filter(|n| n.pool == pool).cloned().collect();
+            let removed: Vec<ServerNode> = removed.iter().filter(|n| n.pool == pool)
+                .cloned()
+                .collect();
+            vnodes += hash::apply_node_diff(ring, &added, &removed);
+        }
+        log!(Info, "ring updated in place: {} nodes added, {} removed ({} vnodes) in {}us",
+             added.len(), removed.len(), vnodes, ms(start.elapsed() * 1000));
+        self.publish_ring("reload");
+    }
+
+    // Moves every connection into a bigger slab. Tokens change, so health
+    // streams are deregistered and any probe in flight is dropped without
+    // counting against the node; the next probe registers them again.
+    fn grow_conns(&mut self, event_loop: &mut mio::EventLoop<Proxy>, capacity: usize) {
+        let mut old = mem::replace(&mut self.conns,
+                                   Slab::new_starting_at(mio::Token(CONN_START), capacity));
+        let tokens: Vec<mio::Token> = old.iter().map(|c| c.token).collect();
+        for token in tokens {
+            let mut c = match old.remove(token) {
+                Some(c) => c,
+                None => continue,
+            };
+            if c.registered {
+                if let Some(ref stream) = c.stream {
+                    let _ = event_loop.deregister(stream);
+                }
+                c.registered = false;
+            }
+            c.pending = false;
+            self.conns.insert_with(move |token| {
+                c.token = token;
+                c
+            });
+        }
+        for c in self.conns.iter() {
+            self.tokens[c.node.id] = c.token;
+        }
+        log!(Info, "connection slab grown from {} to {}", self.capacity, capacity);
+        self.capacity = capacity;
+    }
+
+    fn publish_sockets(&self) {
+        let mut sockets = Vec::new();
+        let opts = Some(("udp", self.server.options()))
+            .into_iter()
+            .chain(self.unix.as_ref().map(|u| ("unix", u.options())))
+            .chain(self.forward_socks.values().next().map(|s| ("forward", s.options())));
+        for (name, opts) in opts {
+            match opts {
+                Ok(opts) => sockets.push((name, opts)),
+                Err(e) => log!(Warn, "reading {} socket options failed: {}", name, e),
+            }
+        }
+        *self.stats.sockets.lock().unwrap() = sockets;
+    }
+
+    // `reason` is what changed the ring: startup, reload or health.
+    fn publish_ring(&self, reason: &'static str) {
+        let n = self.conns.iter().filter(|c| c.in_ring).count();
+        self.stats.ring_nodes.store(n, Ordering::Relaxed);
+        let mut members: Vec<String> = self.conns.iter()
+            .filter(|c| c.in_ring)
+            .map(|c| c.node.name())
+            .collect();
+        members.sort();
+        if let Some(change) = self.stats.record_ring(reason, members) {
+            let added: Vec<&str> = change.added().iter().map(|n| &n[..]).collect();
+            let removed: Vec<&str> = change.removed().iter().map(|n| &n[..]).collect();
+            logging::write(Level::Info, Some("ring_changed"), None,
+                           &format!("ring changed on {}: added [{}], removed [{}], {} nodes \
+                                     [{}] (was [{}])", reason, added.join(", "),
+                                    removed.join(", "), change.after.len(),
+                                    change.after.join(", "), change.before.join(", ")));
+        }
+        let degraded = self.conns.iter().filter(|c| c.in_ring && c.degraded).count();
+        self.stats.degraded_nodes.store(degraded, Ordering::Relaxed);
+        for c in self.conns.iter() {
+            c.history.vnodes.store(c.vnodes(&self.health), Ordering::Relaxed);
+        }
+
+        if let Some(min) = self.health.min_healthy_fraction {
+            let total = self.conns.iter().count();
+            let below = (n as f64) < min * total as f64;
+            if self.stats.below_min_healthy.swap(below, Ordering::Relaxed) != below {
+                if below {
+                    log!(Error, "only {} of {} nodes in the ring, below min_healthy_fraction {}: \
+                                 dropping until more recover", n, total, min);
+                } else {
+                    log!(Info, "{} of {} nodes in the ring, forwarding again", n, total);
+                }
+            }
+        }
+    }
+
+    // The node `key` (a metric name or statsd line) routes to in this
+    // worker's rings right now. Forwards nothing.
+    pub fn route_for(&self, key: &str) -> Option<String> {
+        let (key, pool) = match self.route.route_key(key.as_bytes()) {
+            Some(k) => k,
+            None => return None,
+        };
+        self.route.owner(&self.rings[pool], &key).map(|n| n.name())
+    }
+
+    // Notes the first line's name in `Stats::unrouted` and dead letters
+    // `data`.
+    fn unrouted(&self, data: &[u8], reason: &'static str) {
+        let line = statsd::lines(data).next().unwrap_or(data);
+        let end = line.iter().position(|x| *x == self.route.value_delimiter)
+            .unwrap_or(line.len());
+        self.stats.unrouted.lock().unwrap().record(&line[0..end], reason);
+        self.dead_letter(data);
+    }
+
+    fn dead_letter(&self, data: &[u8]) {
+        if let Some(ref sink) = self.dead_letter {
+            self.stats.dead_lettered.fetch_add(1, Ordering::Relaxed);
+            if let Err(e) = sink.write(data) {
+                log!(Warn, "forward to dead letter node failed: {}", e);
+            }
+        }
+    }
+
+    fn source_socket(&mut self, src: &SockAddr) -> Option<Socket> {
+        let addr = match *src {
+            SockAddr::Inet(ref a) => a.to_std(),
+            _ => return None,
+        };
+
+        if let Some(sock) = self.sources.get(&addr) {
+            return Some(sock.clone());
+        }
+
+        if self.sources.len() >= MAX_SOURCES {
+            self.sources.clear();
+        }
+
+        let sock = Socket::transparent(&addr).and_then(|sock| {
+            match self.forward.mtu_discover {
+                Some(mode) => sock.set_mtu_discover(mode).map(|_| sock),
+                None => Ok(sock),
+            }
+        });
+        match sock {
+            Ok(sock) => {
+                self.sources.insert(addr, sock.clone());
+                Some(sock)
+            }
+            Err(e) => {
+                log!(Error, "transparent socket for {} failed: {}", addr, e);
+                None
+            }
+        }
+    }
+
+    // Rewrites the datagram in `read_buf` without rejected lines, with every
+    // metric name sanitized and renamed by `rewrite_rules` and the metric
+    // prefix in front of every line, counters downsampled and tagged with
+    // the receive time. It's built in `scratch` and the two buffers are
+    // swapped, so nothing is allocated once `scratch` has grown to the
+    // largest datagram.
+    fn rewrite(&mut self, n: usize) -> usize {
+        self.scratch.clear();
+        let sampling = self.route.samples_counters();
+        let stamp = if self.route.trace_timestamps {
+            let ms = SystemTime::now().duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() * 1000 + (d.subsec_nanos() / 1000000) as u64)
+                .unwrap_or(0);
+            Some(format!("proxy_rx_ms:{}", ms).into_bytes())
+        } else {
+            None
+        };
+        for line in statsd::lines(&self.read_buf[0..n]) {
+            let delimiter = self.route.value_delimiter;
+            let end = line.iter().position(|x| *x == delimiter).unwrap_or(line.len());
+            let binary = self.route.binary_keys == BinaryKeys::Reject &&
+                str::from_utf8(&line[0..end]).is_err();
+            if binary {
+                self.stats.binary_keys.fetch_add(1, Ordering::Relaxed);
+                self.stats.unrouted.lock().unwrap().record(&line[0..end], "binary");
+                continue;
+            }
+            let clean = self.route.sanitize == Sanitize::Off ||
+                line[0..end].iter().all(|&b| statsd::name_byte_allowed(b));
+            if !clean && self.route.sanitize == Sanitize::Reject {
+                self.stats.rejected_keys.fetch_add(1, Ordering::Relaxed);
+                self.stats.unrouted.lock().unwrap().record(&line[0..end], "rejected");
+                continue;
+            }
+
+            let mut rate = None;
+            if sampling {
+                if let Some(m) = statsd::parse_with(line, delimiter) {
+                    let (counter, factor) = self.route.sample_factor(m.name);
+                    match m.rate() {
+                        // A rate that doesn't parse is left for the backend.
+                        Some(r) if factor > 1 && m.kind == b"c" => {
+                            let seen = self.sample_seen[counter];
+                            self.sample_seen[counter] = seen.wrapping_add(1);
+                            if seen % factor != 0 {
+                                self.stats.downsampled_counters.fetch_add(1, Ordering::Relaxed);
+                                continue;
+                            }
+                            rate = Some(r / factor as f64);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            if !self.scratch.is_empty() {
+                self.scratch.push(b'\n');
+            }
+            self.scratch.extend_from_slice(&self.route.prefix);
+            let start = self.scratch.len();
+            if clean {
+                self.scratch.extend_from_slice(&line[0..end]);
+            } else {
+                let replacement = self.route.sanitize_replacement;
+                self.scratch.extend(line[0..end].iter().map(|&b| {
+                    if statsd::name_byte_allowed(b) { b } else { replacement }
+                }));
+            }
+            if !self.route.rewrite_rules.is_empty() {
+                if let Some(name) = self.route.rename(&self.scratch[start..]) {
+                    self.scratch.truncate(start);
+                    self.scratch.extend_from_slice(&name);
+                    self.stats.renamed_keys.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            let mut tail = mem::replace(&mut self.tail, Vec::new());
+            tail.clear();
+            {
+                let out = if stamp.is_some() { &mut tail } else { &mut self.scratch };
+                match rate {
+                    Some(rate) => statsd::set_sample_rate(&line[end..], rate, out),
+                    None => out.extend_from_slice(&line[end..]),
+                }
+            }
+            if let Some(ref stamp) = stamp {
+                statsd::append_tags(&tail, stamp, &mut self.scratch);
+            }
+            self.tail = tail;
         }
+
+        let len = self.scratch.len();
+        // Keep room for a full read once this becomes `read_buf`.
+        let size = cmp::max(len, self.read_buf.len());
+        self.scratch.resize(size, 0);
+        mem::swap(&mut self.read_buf, &mut self.scratch);
+        len
     }
 
-    fn parse(&mut self, n: usize) {
-        let packet = &self.read_buf[0..n];
-        match packet.iter().position(|x| *x == b':') {
-            None => println!("Wrong format of data."),
-            Some(n) => {
-                match self.ring.get(&packet[0..n]) {
-                    Some(node) => {
-                        let _ = node.sock.write(packet).unwrap();
+    // `src` is None for datagrams from the Unix socket, which are never sent
+    // transparently.
+    fn parse(&mut self, n: usize, src: Option<&SockAddr>) {
+        if self.stats.paused.load(Ordering::Relaxed) {
+            if self.held.len() < self.forward.pause_buffer {
+                self.held.push_back((self.read_buf[0..n].to_vec(), src.cloned()));
+            } else {
+                self.stats.paused_drops.fetch_add(1, Ordering::Relaxed);
+            }
+            return;
+        }
+        if self.stats.below_min_healthy.load(Ordering::Relaxed) {
+            self.stats.min_healthy_drops.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let via = match src {
+            Some(src) if self.forward.transparent => self.source_socket(src),
+            _ => None,
+        };
+
+        let multi = self.route.mode == RouteMode::Name &&
+            statsd::lines(&self.read_buf[0..n]).nth(1).is_some();
+
+        if multi {
+            self.forward_lines(n, via.as_ref());
+            return;
+        }
+
+        // None for a broadcast metric.
+        let token = {
+            let packet = &self.read_buf[0..n];
+            let delimiter = self.route.value_delimiter;
+            let metric = statsd::lines(packet).next()
+                .and_then(|line| statsd::parse_with(line, delimiter));
+            let pool = metric.as_ref().map_or(0, |m| self.route.pool(m.kind));
+            let name = match self.route.mode {
+                RouteMode::Name => metric.map(|m| m.name),
+                RouteMode::Packet => packet.iter().position(|x| *x == delimiter)
+                    .map(|i| &packet[0..i]),
+            };
+
+            let key = match name {
+                Some(name) if self.route.is_broadcast(name) => None,
+                Some(name) => Some(self.route.key(name)),
+                None => {
+                    log!(Debug, "Wrong format of data.");
+                    self.unrouted(packet, "malformed");
+                    return;
+                }
+            };
+
+            match key {
+                Some(key) => {
+                    self.stats.record_key_length(key.len());
+                    match self.route.owner(&self.rings[pool], key) {
+                        Some(node) => {
+                            if self.route.dirty_key_check {
+                                self.check_dirty_key(name.unwrap_or(b""), pool, node);
+                            }
+                            if self.route.track_top_keys {
+                                self.track_top_key(key, node);
+                            }
+                            Some(self.tokens[node.id])
+                        }
+                        None => {
+                            log!(Debug, "No node, skip.");
+                            self.unrouted(packet, "no_route");
+                            return;
+                        }
                     }
-                    None => println!("No node, skip.")
                 }
+                None => None,
             }
         };
+
+        let token = match token {
+            Some(token) => token,
+            None => return self.broadcast(n, via.as_ref()),
+        };
+        let pool = self.conns[token].node.pool;
+        self.queued += send_with_failover(&mut self.conns, &self.tokens, &self.rings[pool], token,
+                                          &self.read_buf[0..n], via.as_ref(), &self.health,
+                                          &self.forward, &self.stats);
+    }
+
+    fn track_top_key(&self, key: &[u8], node: &ServerNode) {
+        let seen = self.stats.top_key_lines.fetch_add(1, Ordering::Relaxed);
+        if seen % self.route.top_keys_sample == 0 {
+            self.stats.top_keys.lock().unwrap().record(key, &node.name(), self.route.top_keys_size);
+        }
+    }
+
+    // `owner` is where `name` routes as received.
+    fn check_dirty_key(&self, name: &[u8], pool: usize, owner: &ServerNode) {
+        let trimmed = statsd::trim(name);
+        if trimmed.len() == name.len() {
+            return;
+        }
+        let clean = match self.route.owner(&self.rings[pool], self.route.key(trimmed)) {
+            Some(node) if node.id == owner.id => return,
+            Some(node) => node.name(),
+            None => "no node".to_owned(),
+        };
+
+        let seen = self.stats.dirty_key_packets.fetch_add(1, Ordering::Relaxed);
+        let sample = self.route.dirty_key_sample;
+        if sample > 0 && seen % sample == 0 {
+            log!(Info, "{:?} routes to {}, trimmed to {:?} it would go to {}",
+                 String::from_utf8_lossy(name), owner.name(), String::from_utf8_lossy(trimmed),
+                 clean);
+        }
+    }
+
+    // Sends the datagram in `read_buf` to every node in the ring.
+    fn broadcast(&mut self, n: usize, via: Option<&Socket>) {
+        let mut sent = 0;
+        for c in self.conns.iter_mut() {
+            if !c.in_ring {
+                continue;
+            }
+            self.queued += c.send(&self.read_buf[0..n], via, &self.health, &self.forward,
+                                  &self.stats).queued;
+            sent += 1;
+        }
+
+        if sent == 0 {
+            log!(Debug, "No node for broadcast, skip.");
+            self.unrouted(&self.read_buf[0..n], "no_route");
+            return;
+        }
+        self.stats.broadcasts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Splits a multi-metric datagram by the node owning each line and sends
+    // one datagram per node.
+    fn forward_lines(&mut self, n: usize, via: Option<&Socket>) {
+        for line in statsd::lines(&self.read_buf[0..n]) {
+            let (name, pool) = match statsd::parse_with(line, self.route.value_delimiter) {
+                Some(m) => (m.name, self.route.pool(m.kind)),
+                None => {
+                    log!(Debug, "Wrong format of data.");
+                    self.unrouted(line, "malformed");
+                    continue;
+                }
+            };
+
+            if self.route.is_broadcast(name) {
+                let mut sent = false;
+                for c in self.conns.iter_mut() {
+                    if !c.in_ring {
+                        continue;
+                    }
+                    if c.batch.is_empty() {
+                        self.batched.push(c.token);
+                    } else {
+                        c.batch.push(b'\n');
+                    }
+                    c.batch.extend_from_slice(line);
+                    sent = true;
+                }
+
+                if sent {
+                    self.stats.broadcasts.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    log!(Debug, "No node for broadcast, skip.");
+                    self.unrouted(line, "no_route");
+                }
+                continue;
+            }
+
+            let key = self.route.key(name);
+            self.stats.record_key_length(key.len());
+
+            let token = match self.route.owner(&self.rings[pool], key) {
+                Some(node) => {
+                    if self.route.dirty_key_check {
+                        self.check_dirty_key(name, pool, node);
+                    }
+                    if self.route.track_top_keys {
+                        self.track_top_key(key, node);
+                    }
+                    self.tokens[node.id]
+                }
+                None => {
+                    log!(Debug, "No node, skip.");
+                    self.unrouted(line, "no_route");
+                    continue;
+                }
+            };
+
+            let batch = &mut self.conns[token].batch;
+            if batch.is_empty() {
+                self.batched.push(token);
+            } else {
+                batch.push(b'\n');
+            }
+            batch.extend_from_slice(line);
+        }
+
+        for token in self.batched.drain(..) {
+            let mut batch = mem::replace(&mut self.conns[token].batch, Vec::new());
+            let pool = self.conns[token].node.pool;
+            self.queued += send_with_failover(&mut self.conns, &self.tokens, &self.rings[pool],
+                                              token, &batch, via, &self.health, &self.forward,
+                                              &self.stats);
+            batch.clear();
+            self.conns[token].batch = batch;
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.queued == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        for c in self.conns.iter_mut() {
+            if c.queue.is_empty() {
+                continue;
+            }
+
+            let before = c.queue.len();
+            if let Err(e) = c.flush(&self.forward, &self.stats) {
+                // The failed packet is dropped, the rest wait for the next flush.
+                c.forward_errors += 1;
+                c.history.forward_errors.fetch_add(1, Ordering::Relaxed);
+                event!(Warn, "forward_failed", c.node.name(),
+                       "forward to {}:{} failed: {} ({} errors)",
+                       c.node.host, c.node.port, e, c.forward_errors);
+                c.record_failure(&self.health, now);
+            }
+            c.publish_depth();
+            let removed = before - c.queue.len();
+            self.queued -= removed;
+            self.stats.release(removed, self.forward.max_outstanding_packets);
+        }
     }
 
     fn read(&mut self, event_loop: &mut mio::EventLoop<Proxy>) {
-        match self.server.read(&mut self.read_buf) {
-            Ok(Some((0, _))) => {
-                println!("read 0 bytes");
+        match self.server.read_msg(&mut self.read_buf) {
+            // Valid but carries no metrics; some health probes send these.
+            Ok(Some((0, _, _))) => {
+                self.stats.empty_packets.fetch_add(1, Ordering::Relaxed);
+                self.reregister(event_loop);
             }
-            Ok(Some((n, _))) => {
-                println!("read {} bytes", n);
+            Ok(Some((n, src, drops))) => {
+                log!(Debug, "read {} bytes", n);
+                if let Some(drops) = drops {
+                    self.record_rx_drops(drops);
+                }
+
+                let allowed = match src {
+                    SockAddr::Inet(ref a) => self.forward.allow_sources.allows(&a.to_std()),
+                    _ => true,
+                };
+                if !allowed {
+                    self.stats.denied_source_packets.fetch_add(1, Ordering::Relaxed);
+                    self.reregister(event_loop);
+                    return;
+                }
 
-                self.parse(n);
+                self.stats.udp_packets.fetch_add(1, Ordering::Relaxed);
+                self.handle(n, Some(&src));
                 self.reregister(event_loop);
             }
             Ok(None) => {
-                println!("None");
+                log!(Debug, "None");
                 self.reregister(event_loop);
             }
             Err(e) => {
@@ -71,35 +1944,353 @@ impl Proxy {
 
     }
 
+    // Both listeners are oneshot and give up the loop after a single
+    // datagram, so when both are busy every turn reads one from each and
+    // neither transport can starve the other.
+    fn read_unix(&mut self, event_loop: &mut mio::EventLoop<Proxy>) {
+        let res = match self.unix {
+            Some(ref unix) => unix.read(&mut self.read_buf),
+            None => return,
+        };
+
+        match res {
+            Ok(Some(0)) => {
+                self.stats.empty_packets.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(None) => {}
+            Ok(Some(n)) => {
+                self.stats.unix_packets.fetch_add(1, Ordering::Relaxed);
+                self.handle(n, None);
+            }
+            Err(e) => log!(Warn, "unix socket read failed: {}", e),
+        }
+        self.reregister_unix(event_loop);
+    }
+
+    // Forwards what was held while paused, oldest first, once resumed.
+    // Each held datagram stands in for `read_buf` while it is parsed, so one
+    // already read into it is left as it was.
+    fn replay(&mut self) {
+        if self.held.is_empty() || self.stats.paused.load(Ordering::Relaxed) {
+            return;
+        }
+        // Bounded in case a pause lands midway and parse holds them again.
+        for _ in 0..self.held.len() {
+            let (mut data, src) = match self.held.pop_front() {
+                Some(held) => held,
+                None => break,
+            };
+            let n = data.len();
+            mem::swap(&mut self.read_buf, &mut data);
+            self.parse(n, src.as_ref());
+            mem::swap(&mut self.read_buf, &mut data);
+        }
+    }
+
+    // Shared by both transports: the datagram of `n` bytes is in `read_buf`.
+    fn handle(&mut self, n: usize, src: Option<&SockAddr>) {
+        self.replay();
+        self.reads = self.reads.wrapping_add(1);
+        let start = if self.reads % self.forward.latency_sample == 0 {
+            Some(Instant::now())
+        } else {
+            None
+        };
+
+        let n = if self.route.rewrites() { self.rewrite(n) } else { n };
+        // Nothing left once every line was rejected.
+        if n > 0 {
+            let sum = if self.forward.verifies() {
+                Some(checksum(&self.read_buf[0..n]))
+            } else {
+                None
+            };
+            self.parse(n, src);
+            if let Some(sum) = sum {
+                verify(&self.read_buf[0..n], sum, "forwarded", &self.stats);
+            }
+        }
+        self.flush();
+        if let Some(start) = start {
+            self.stats.record_latency(start.elapsed());
+        }
+    }
+
+    // The kernel counter is cumulative per socket; fold the growth into the
+    // gauge shared by all workers.
+    fn record_rx_drops(&mut self, drops: u32) {
+        let new = drops.wrapping_sub(self.rx_drops);
+        if new > 0 {
+            self.stats.kernel_rx_drops.fetch_add(new as usize, Ordering::Relaxed);
+            self.rx_drops = drops;
+        }
+    }
+
     fn reregister(&self, event_loop: &mut mio::EventLoop<Proxy>) {
+        if self.draining.is_some() || self.level {
+            return;
+        }
+
         let event_set = match self.state {
             State::Reading => mio::EventSet::readable(),
             State::Writing => mio::EventSet::writable(),
             _ => mio::EventSet::none(),
         };
 
-        event_loop.reregister(&self.server, SERVER, event_set, mio::PollOpt::oneshot())
+        event_loop.reregister(&self.server, Role::Server.token(), event_set,
+                              mio::PollOpt::oneshot())
             .unwrap();
     }
+
+    fn reregister_unix(&self, event_loop: &mut mio::EventLoop<Proxy>) {
+        if self.draining.is_some() || self.level {
+            return;
+        }
+
+        if let Some(ref unix) = self.unix {
+            event_loop.reregister(unix, Role::UnixServer.token(), mio::EventSet::readable(),
+                                  mio::PollOpt::oneshot())
+                .unwrap();
+        }
+    }
+
+    // Stops reading from the listener and keeps flushing the retry queues
+    // until they are empty or `drain_timeout` has passed.
+    fn start_drain(&mut self, event_loop: &mut mio::EventLoop<Proxy>) {
+        if self.draining.is_some() {
+            return;
+        }
+
+        let _ = event_loop.deregister(&self.server);
+        if let Some(ref unix) = self.unix {
+            let _ = event_loop.deregister(unix);
+        }
+        self.draining = Some(Instant::now() + self.forward.drain_timeout);
+        self.drain_start = self.queued;
+        log!(Info, "draining {} queued packets", self.queued);
+        self.drain(event_loop);
+    }
+
+    fn drain(&mut self, event_loop: &mut mio::EventLoop<Proxy>) {
+        self.flush();
+
+        let deadline = match self.draining {
+            Some(d) => d,
+            None => return,
+        };
+        if self.queued > 0 && Instant::now() < deadline {
+            event_loop.timeout_ms(Role::DrainTimer, DRAIN_INTERVAL).unwrap();
+            return;
+        }
+
+        let dropped = self.queued;
+        log!(Info, "shutdown: drained {} packets, dropped {}",
+             self.drain_start - dropped, dropped);
+        for c in self.conns.iter_mut() {
+            c.queue.clear();
+            c.publish_depth();
+        }
+        self.queued = 0;
+        self.stats.release(dropped, self.forward.max_outstanding_packets);
+        event_loop.shutdown();
+    }
+
+    // Probes every connection that is due and returns the delay in ms until
+    // the next one is. With `max_concurrent_checks` at most that many checks
+    // (and so admin connects) are in flight; due nodes beyond it wait for a
+    // later tick.
+    fn check(&mut self, event_loop: &mut mio::EventLoop<Proxy>) -> u64 {
+        let now = Instant::now();
+        let mut next = self.health.probe_interval;
+        let mut in_flight = self.conns.iter().filter(|c| c.pending).count();
+        let mut deferred = false;
+
+        for c in self.conns.iter_mut() {
+            if !c.node.health_checked() {
+                continue;
+            }
+            if c.next_probe > now {
+                next = cmp::min(next, ms(c.next_probe - now));
+                continue;
+            }
+
+            if c.pending {
+                // The previous check was never answered.
+                c.pending = false;
+                in_flight -= 1;
+                c.record_check_failure(&self.health, now);
+                c.lose_stream(event_loop);
+            }
+
+            if let Some(max) = self.health.max_concurrent_checks {
+                if in_flight >= max {
+                    deferred = true;
+                    continue;
+                }
+            }
+
+            if c.stream.is_none() {
+                c.connect_stream();
+            }
+            in_flight += 1;
+            c.pending = true;
+            c.register(event_loop, mio::EventSet::writable() |
+                       mio::EventSet::hup() | mio::EventSet::error());
+
+            let delay = jittered(&mut self.rng, self.health.probe_interval,
+                                 self.health.probe_jitter);
+            c.next_probe = now + Duration::from_millis(delay);
+            next = cmp::min(next, delay);
+        }
+
+        self.stats.pending_health_checks.store(in_flight, Ordering::Relaxed);
+        if deferred {
+            next = cmp::min(next, self.health.probe_interval / 10);
+        }
+        cmp::max(next, 1)
+    }
+
+    fn evaluate(&mut self) {
+        let now = Instant::now();
+        for c in self.conns.iter_mut() {
+            c.evaluate(&mut self.rings[c.node.pool], &self.health, now);
+        }
+        self.publish_ring("health");
+    }
+
+    fn on_error(&mut self, event_loop: &mut mio::EventLoop<Proxy>, token: mio::Token) {
+        let c = &mut self.conns[token];
+        if c.pending {
+            c.pending = false;
+            c.record_check_failure(&self.health, Instant::now());
+        }
+        c.check_sent = None;
+        c.lose_stream(event_loop);
+    }
+
+    fn on_write(&mut self, event_loop: &mut mio::EventLoop<Proxy>, token: mio::Token) {
+        let res = {
+            let c = &self.conns[token];
+            match c.stream {
+                Some(ref stream) => stream.send(c.node.health_type.request()),
+                None => return,
+            }
+        };
+
+        match res {
+            Ok(Some(_)) => {
+                let c = &mut self.conns[token];
+                c.check_sent = Some(Instant::now());
+                c.register(event_loop, mio::EventSet::readable() | mio::EventSet::hup() |
+                           mio::EventSet::error());
+            }
+            Ok(None) => {
+                self.conns[token].register(event_loop, mio::EventSet::writable() |
+                                           mio::EventSet::hup() | mio::EventSet::error());
+            }
+            Err(_) => self.on_error(event_loop, token),
+        }
+    }
+
+    fn on_read(&mut self, event_loop: &mut mio::EventLoop<Proxy>, token: mio::Token) {
+        let res = {
+            let c = &mut self.conns[token];
+            match c.stream {
+                Some(ref stream) => stream.read(&mut c.buf),
+                None => return,
+            }
+        };
+
+        match res {
+            Ok(Some(0)) | Err(_) => self.on_error(event_loop, token),
+            Ok(Some(n)) => {
+                let c = &mut self.conns[token];
+                c.pending = false;
+                // Timed whatever the reply says: a node still up but slow to
+                // answer is the early warning this is for.
+                if let Some(sent) = c.check_sent.take() {
+                    c.history.record_check_latency(sent.elapsed());
+                }
+                if self.health.is_degraded(&c.buf[0..n]) {
+                    c.reported_degraded = true;
+                    c.record_success(&self.health);
+                } else if c.node.health_type.is_up(&c.buf[0..n]) {
+                    c.reported_degraded = false;
+                    c.record_success(&self.health);
+                } else {
+                    c.record_check_failure(&self.health, Instant::now());
+                }
+
+                // HTTP/1.0 servers close after each response.
+                if c.node.health_type == HealthType::Http {
+                    c.close_stream(event_loop);
+                }
+            }
+            Ok(None) => {
+                self.conns[token].register(event_loop, mio::EventSet::readable() |
+                                           mio::EventSet::hup() | mio::EventSet::error());
+            }
+        }
+    }
 }
 
 impl mio::Handler for Proxy {
-    type Timeout = ();
-    type Message = ();
+    type Timeout = Role;
+    type Message = Control;
 
     fn ready(&mut self, event_loop: &mut mio::EventLoop<Proxy>,
              token: mio::Token, events: mio::EventSet) {
-        match token {
-            SERVER => {
+        match Role::from_token(token) {
+            Some(Role::Server) => {
                 assert!(events.is_readable());
                 self.read(event_loop);
             }
+            Some(Role::UnixServer) => self.read_unix(event_loop),
+            None if self.conns.contains(token) => {
+                if events.is_error() || events.is_hup() {
+                    self.on_error(event_loop, token);
+                } else if events.is_writable() {
+                    self.on_write(event_loop, token);
+                } else if events.is_readable() {
+                    self.on_read(event_loop, token);
+                }
+            }
+            _ => {
+                log!(Debug, "other");
+            }
+        }
+    }
+
+    fn timeout(&mut self, event_loop: &mut mio::EventLoop<Proxy>, role: Role) {
+        match role {
+            Role::ProbeTimer => {
+                self.replay();
+                self.flush();
+                let next = if self.health.enabled {
+                    self.check(event_loop)
+                } else {
+                    self.health.probe_interval
+                };
+                event_loop.timeout_ms(Role::ProbeTimer, next).unwrap();
+            }
+            Role::EvalTimer => {
+                self.evaluate();
+                event_loop.timeout_ms(Role::EvalTimer, self.health.eval_interval).unwrap();
+            }
+            Role::DrainTimer => self.drain(event_loop),
             _ => {
-                println!("other");
+                log!(Debug, "other timeout");
             }
         }
     }
 
+    fn notify(&mut self, event_loop: &mut mio::EventLoop<Proxy>, msg: Control) {
+        match msg {
+            Control::Shutdown => self.start_drain(event_loop),
+            Control::Reload(nodes) => self.reload(event_loop, nodes),
+        }
+    }
+
     #[allow(unused_variables)]
     fn tick(&mut self, event_loop: &mut mio::EventLoop<Proxy>) {
     }
@@ -0,0 +1,155 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use socket::{Socket, TcpStream};
+use stats::NodeStats;
+
+// Milliseconds a node's lost TCP connection stays down before the next send
+// reconnects; sends in between fail, so failover and eviction take over.
+const RECONNECT_DELAY: u64 = 1000;
+
+// How datagrams reach a node, its `transport`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+impl Transport {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+        }
+    }
+}
+
+// A connection's way out to its node. Like `Socket::send_to`, Ok(None) means
+// the data wasn't taken and the caller should queue it for a retry.
+pub trait Forwarder {
+    fn send(&mut self, data: &[u8], via: Option<&Socket>) -> io::Result<Option<usize>>;
+}
+
+pub fn forwarder(transport: Transport, sock: Socket, addr: SocketAddr,
+                 history: Arc<NodeStats>) -> Box<Forwarder> {
+    match transport {
+        Transport::Udp => Box::new(UdpForwarder { sock: sock, addr: addr }),
+        Transport::Tcp => Box::new(TcpForwarder::new(addr, history)),
+    }
+}
+
+// Datagrams from the worker's forwarding socket for the node's source
+// address, or from `via` when one is given.
+pub struct UdpForwarder {
+    sock: Socket,
+    addr: SocketAddr,
+}
+
+impl Forwarder for UdpForwarder {
+    fn send(&mut self, data: &[u8], via: Option<&Socket>) -> io::Result<Option<usize>> {
+        via.unwrap_or(&self.sock).send_to(data, &self.addr)
+    }
+}
+
+// Newline delimited statsd over one persistent nonblocking connection per
+// worker. Each datagram is written whole before the next is taken: what the
+// socket doesn't take waits in `pending`, and until it's written sends
+// return Ok(None) so the retry queue holds the backlog. A failed write drops
+// the connection along with whatever was pending; it is reopened on a later
+// send and counted in `forward_reconnects`. `via` is ignored, a stream has
+// its own source address.
+pub struct TcpForwarder {
+    addr: SocketAddr,
+    stream: Option<TcpStream>,
+    pending: Vec<u8>,
+    // Set when the connection is dropped, so the connects that follow count
+    // as reconnects until one carries data.
+    lost: bool,
+    retry_at: Instant,
+    history: Arc<NodeStats>,
+}
+
+impl TcpForwarder {
+    fn new(addr: SocketAddr, history: Arc<NodeStats>) -> TcpForwarder {
+        TcpForwarder {
+            addr: addr,
+            stream: None,
+            pending: Vec::new(),
+            lost: false,
+            retry_at: Instant::now(),
+            history: history,
+        }
+    }
+
+    fn connect(&mut self) -> io::Result<()> {
+        if Instant::now() < self.retry_at {
+            return Err(io::Error::new(io::ErrorKind::NotConnected,
+                                      format!("reconnecting to {}", self.addr)));
+        }
+        if self.lost {
+            self.history.forward_reconnects.fetch_add(1, Ordering::Relaxed);
+        }
+        match TcpStream::connect(self.addr) {
+            Ok(stream) => {
+                self.stream = Some(stream);
+                Ok(())
+            }
+            Err(e) => {
+                self.lose();
+                Err(e)
+            }
+        }
+    }
+
+    fn lose(&mut self) {
+        self.stream = None;
+        self.pending.clear();
+        self.lost = true;
+        self.retry_at = Instant::now() + Duration::from_millis(RECONNECT_DELAY);
+    }
+
+    // Writes `pending` until the socket would block. Returns true once it's
+    // all written.
+    fn drain(&mut self) -> io::Result<bool> {
+        while !self.pending.is_empty() {
+            let res = match self.stream {
+                Some(ref stream) => stream.send(&self.pending),
+                None => return Ok(false),
+            };
+            match res {
+                Ok(Some(n)) => {
+                    self.pending.drain(0..n);
+                    self.lost = false;
+                }
+                Ok(None) => return Ok(false),
+                Err(e) => {
+                    self.lose();
+                    return Err(e);
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl Forwarder for TcpForwarder {
+    fn send(&mut self, data: &[u8], _via: Option<&Socket>) -> io::Result<Option<usize>> {
+        if self.stream.is_none() {
+            try!(self.connect());
+        }
+        if !try!(self.drain()) {
+            return Ok(None);
+        }
+
+        self.pending.extend_from_slice(data);
+        if !data.ends_with(b"\n") {
+            self.pending.push(b'\n');
+        }
+        try!(self.drain());
+        Ok(Some(data.len()))
+    }
+}
@@ -1,14 +1,193 @@
-pub use conhash::ConsistentHash;
-use conhash::Node;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 
-use socket::UdpStream;
+use md5;
+
+use event_loop::HealthType;
+use forwarder::Transport;
+
+pub trait Node: Clone {
+    fn name(&self) -> String;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Name,
+    Host,
+    Port,
+    Index,
+}
+
+// How the key hashed for vnode `i` of a node is spelled, e.g. `{name}:{i}`
+// (the default, as conhash does it) or `{host}-{port}-{i}`. `{name}` is the
+// node's host:port, `{host}` and `{port}` its two halves. Proxies sharing
+// backends only place keys alike if they spell vnodes identically.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VnodeKey {
+    format: String,
+    parts: Vec<Part>,
+}
+
+impl VnodeKey {
+    pub fn parse(format: &str) -> Result<VnodeKey, String> {
+        let mut parts = Vec::new();
+        let mut rest = format;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Text(rest[0..start].to_owned()));
+            }
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => return Err(format!("unclosed `{{` in `{}`", format)),
+            };
+            parts.push(match &rest[start + 1..end] {
+                "name" => Part::Name,
+                "host" => Part::Host,
+                "port" => Part::Port,
+                "i" => Part::Index,
+                other => {
+                    return Err(format!("unknown placeholder `{{{}}}`, use {{name}}, {{host}}, \
+                                        {{port}} or {{i}}", other))
+                }
+            });
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_owned()));
+        }
+
+        // Without them a node's vnodes, or different nodes, share a point.
+        if !parts.contains(&Part::Index) {
+            return Err(format!("`{}` has no {{i}}", format));
+        }
+        if !parts.iter().any(|p| *p == Part::Name || *p == Part::Host || *p == Part::Port) {
+            return Err(format!("`{}` has none of {{name}}, {{host}}, {{port}}", format));
+        }
+        Ok(VnodeKey {
+            format: format.to_owned(),
+            parts: parts,
+        })
+    }
+
+    pub fn format(&self) -> &str {
+        &self.format
+    }
+
+    pub fn key(&self, name: &str, i: usize) -> String {
+        let (host, port) = match name.rfind(':') {
+            Some(colon) => (&name[0..colon], &name[colon + 1..]),
+            None => (name, ""),
+        };
+        let mut key = String::with_capacity(name.len() + 8);
+        for part in self.parts.iter() {
+            match *part {
+                Part::Text(ref text) => key.push_str(text),
+                Part::Name => key.push_str(name),
+                Part::Host => key.push_str(host),
+                Part::Port => key.push_str(port),
+                Part::Index => key.push_str(&i.to_string()),
+            }
+        }
+        key
+    }
+}
+
+impl Default for VnodeKey {
+    fn default() -> VnodeKey {
+        VnodeKey::parse("{name}:{i}").unwrap()
+    }
+}
+
+fn point(key: &[u8]) -> Vec<u8> {
+    md5::compute(key).to_vec()
+}
+
+// The ring, laid out as conhash lays it out so the default vnode key places
+// keys exactly as before: each vnode sits at the md5 of its vnode key, and a
+// key belongs to the first vnode at or after its own md5, wrapping around.
+// Vnodes hashing to the same point are all kept, sorted by node name, and
+// the first name wins: the owner then doesn't depend on the order nodes were
+// added in, and removing one hands the point to the other.
+pub struct ConsistentHash<N: Node> {
+    vnode_key: VnodeKey,
+    points: BTreeMap<Vec<u8>, Vec<N>>,
+    replicas: HashMap<String, usize>,
+}
+
+impl<N: Node> ConsistentHash<N> {
+    pub fn with_vnode_key(vnode_key: VnodeKey) -> ConsistentHash<N> {
+        ConsistentHash {
+            vnode_key: vnode_key,
+            points: BTreeMap::new(),
+            replicas: HashMap::new(),
+        }
+    }
+
+    // Re-adding a node replaces its vnodes. Returns how many vnodes were
+    // removed and placed.
+    pub fn add(&mut self, node: &N, replicas: usize) -> usize {
+        let removed = self.remove(node);
+        let name = node.name();
+        for i in 0..replicas {
+            let at = self.points.entry(point(self.vnode_key.key(&name, i).as_bytes()))
+                .or_insert_with(Vec::new);
+            let pos = at.iter().position(|n| n.name() > name).unwrap_or(at.len());
+            at.insert(pos, node.clone());
+        }
+        self.replicas.insert(name, replicas);
+        removed + replicas
+    }
+
+    // Returns how many vnodes were removed.
+    pub fn remove(&mut self, node: &N) -> usize {
+        let name = node.name();
+        let replicas = self.replicas.remove(&name).unwrap_or(0);
+        for i in 0..replicas {
+            let p = point(self.vnode_key.key(&name, i).as_bytes());
+            let empty = match self.points.get_mut(&p) {
+                Some(at) => {
+                    at.retain(|n| n.name() != name);
+                    at.is_empty()
+                }
+                None => false,
+            };
+            if empty {
+                self.points.remove(&p);
+            }
+        }
+        replicas
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&N> {
+        self.points.range(point(key)..).next()
+            .or_else(|| self.points.iter().next())
+            .and_then(|(_, nodes)| nodes.first())
+    }
+}
 
 #[derive(Clone, Eq, PartialEq)]
 pub struct ServerNode {
+    pub id: usize,
     pub host: String,
     pub port: u16,
+    // 0 for a node that is never health checked.
     pub adminport: u16,
-    pub sock: UdpStream
+    pub replicas: usize,
+    pub health_type: HealthType,
+    // Datagrams go out from the worker's forwarding socket, nodes only carry
+    // the resolved address.
+    pub addr: SocketAddr,
+    // Local address forwarded datagrams are sent from, if pinned.
+    pub source_addr: Option<IpAddr>,
+    // Which pool's ring the node belongs to.
+    pub pool: usize,
+    // DogStatsD tags added to every line forwarded to the node, comma
+    // separated; empty for none.
+    pub tags: Vec<u8>,
+    pub transport: Transport,
 }
 
 impl Node for ServerNode {
@@ -17,13 +196,80 @@ impl Node for ServerNode {
     }
 }
 
+// One ring per pool, each node placed in its own pool's ring. Placement
+// depends only on node names, replica counts and the vnode key, the sort
+// keeps everything else derived from the node list (ids, slab tokens) stable.
+pub fn build_rings(nodes: &mut Vec<ServerNode>, pools: usize,
+                   vnode_key: &VnodeKey) -> Vec<ConsistentHash<ServerNode>> {
+    nodes.sort_by(|a, b| a.name().cmp(&b.name()));
+
+    let mut rings: Vec<_> = (0..cmp::max(pools, 1))
+        .map(|_| ConsistentHash::with_vnode_key(vnode_key.clone()))
+        .collect();
+    for node in nodes.iter() {
+        rings[node.pool].add(node, node.replicas);
+    }
+    rings
+}
+
+// Updates `ring` in place: the ring adds and removes one node's vnodes
+// without touching the others, so the cost follows the size of the diff, not
+// of the ring. A node whose replica count changed goes in both lists.
+// Returns how many vnodes were removed and added.
+pub fn apply_node_diff(ring: &mut ConsistentHash<ServerNode>, added: &[ServerNode],
+                       removed: &[ServerNode]) -> usize {
+    let mut vnodes = 0;
+    for node in removed.iter() {
+        vnodes += ring.remove(node);
+    }
+    for node in added.iter() {
+        vnodes += ring.add(node, node.replicas);
+    }
+    vnodes
+}
+
+// Where traffic for `node` goes when sending to it fails, skipping the
+// nodes (by id) in `tried`. The ring doesn't expose a vnode's neighbours, so
+// candidates come from hashing the node's name with an attempt number: each
+// node has a stable failover order and a dead node's keys all move together.
+pub fn failover<'a>(ring: &'a ConsistentHash<ServerNode>, node: &str, tried: &[usize])
+                    -> Option<&'a ServerNode> {
+    for attempt in 0..16 {
+        let key = format!("{}#{}", node, attempt);
+        match ring.get(key.as_bytes()) {
+            Some(n) if !tried.contains(&n.id) => return Some(n),
+            Some(_) => {}
+            None => return None,
+        }
+    }
+    None
+}
+
 impl ServerNode {
-    pub fn new(host: &str, port: u16, adminport: u16) -> ServerNode {
-        ServerNode {
+    pub fn health_checked(&self) -> bool {
+        self.adminport != 0
+    }
+
+    // Fails if `host` doesn't resolve.
+    pub fn new(id: usize, host: &str, port: u16, adminport: u16, replicas: usize,
+               health_type: HealthType, source_addr: Option<IpAddr>) -> io::Result<ServerNode> {
+        let addr = match try!((host, port).to_socket_addrs()).next() {
+            Some(addr) => addr,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound,
+                                              format!("{} has no address", host))),
+        };
+        Ok(ServerNode {
+            id: id,
             host: host.to_owned(),
             port: port,
             adminport: adminport,
-            sock: UdpStream::new((host, port)).unwrap()
-        }
+            replicas: replicas,
+            health_type: health_type,
+            addr: addr,
+            source_addr: source_addr,
+            pool: 0,
+            tags: Vec::new(),
+            transport: Transport::Udp,
+        })
     }
 }
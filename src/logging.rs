@@ -0,0 +1,108 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use admin::quote;
+
+static JSON: AtomicBool = ATOMIC_BOOL_INIT;
+// The lowest level written, as `Level as usize + 1`; 0 until set is Info.
+static LEVEL: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// `text` prints the bare message as always; `json` prints one object per
+// line for log pipelines, with the event name and node where there is one.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        }
+    }
+}
+
+// Ordered by severity; messages below `log_level` (Info by default, which
+// leaves out the per datagram Debug lines) aren't formatted or written.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+pub fn set_format(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as usize + 1, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => level >= Level::Info,
+        min => level as usize + 1 >= min,
+    }
+}
+
+pub fn write(level: Level, event: Option<&str>, node: Option<&str>, msg: &str) {
+    if JSON.load(Ordering::Relaxed) {
+        println!("{}", json_line(level, event, node, msg));
+    } else {
+        println!("{}", msg);
+    }
+}
+
+pub fn json_line(level: Level, event: Option<&str>, node: Option<&str>, msg: &str) -> String {
+    let ts = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1000 + (d.subsec_nanos() / 1000000) as u64)
+        .unwrap_or(0);
+    let mut s = format!("{{\"ts\":{}.{:03},\"level\":{}", ts / 1000, ts % 1000,
+                        quote(level.name()));
+    if let Some(event) = event {
+        s.push_str(&format!(",\"event\":{}", quote(event)));
+    }
+    if let Some(node) = node {
+        s.push_str(&format!(",\"node\":{}", quote(node)));
+    }
+    s.push_str(&format!(",\"msg\":{}}}", quote(msg)));
+    s
+}
+
+// `log!(Warn, "...", args)` for plain messages.
+macro_rules! log {
+    ($level:ident, $($arg:tt)+) => (
+        if $crate::logging::enabled($crate::logging::Level::$level) {
+            $crate::logging::write($crate::logging::Level::$level, None, None,
+                                   &format!($($arg)+))
+        }
+    )
+}
+
+// `event!(Info, "node_down", node_name, "...", args)` for the ring and node
+// changes that log pipelines want to query on.
+macro_rules! event {
+    ($level:ident, $event:expr, $node:expr, $($arg:tt)+) => (
+        if $crate::logging::enabled($crate::logging::Level::$level) {
+            $crate::logging::write($crate::logging::Level::$level, Some($event),
+                                   Some(&$node[..]), &format!($($arg)+))
+        }
+    )
+}
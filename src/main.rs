@@ -1,68 +1,199 @@
 extern crate nix;
 extern crate mio;
-extern crate yaml_rust;
-extern crate conhash;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_yaml;
+extern crate md5;
+extern crate libc;
+extern crate regex;
 
+#[macro_use]
+mod logging;
+mod admin;
+mod build_info;
+mod capture;
+mod config;
 mod socket;
+mod self_metrics;
+mod soak;
 mod event_loop;
+mod forwarder;
 mod hash;
+mod stats;
+mod statsd;
 
+#[cfg(test)]
+mod tests;
+
+use std::cmp;
 use std::env;
 use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process;
+use std::sync::{mpsc, Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
 use std::thread;
-use std::io::Read;
-use yaml_rust::yaml;
+use std::time::{Duration, Instant};
+
+use admin::Admin;
+use capture::Capture;
+use self_metrics::SelfMetrics;
+use config::{Config, ListenerConfig};
+use hash::{Node, ServerNode};
+use socket::{AddressFamily, Socket, SockType, UdpListener, UnixListener};
+use stats::Stats;
+use event_loop::{Control, Proxy};
+use nix::sched::{self, CpuSet};
+
+static SHUTDOWN: AtomicBool = ATOMIC_BOOL_INIT;
+static RELOAD: AtomicBool = ATOMIC_BOOL_INIT;
 
-use hash::ServerNode;
-use socket::UdpListener;
-use event_loop::{Proxy, SERVER};
+extern "C" fn on_signal(signum: libc::c_int) {
+    if signum == libc::SIGHUP {
+        RELOAD.store(true, Ordering::SeqCst);
+    } else {
+        SHUTDOWN.store(true, Ordering::SeqCst);
+    }
+}
 
 struct Manager {
     host: &'static str,
-    port: u16,
     threads: Vec<thread::JoinHandle<()>>,
+    senders: Vec<mio::Sender<Control>>,
     nodes: Vec<ServerNode>,
-    check_interval: u64
+    config: ListenerConfig,
+    stats: Arc<Stats>,
+    // Bound once, every worker reads from its own duplicate descriptor.
+    unix: Option<UnixListener>,
+    capture: Option<Capture>,
 }
 
+// One manager per listener, each running its own set of worker threads.
 impl Manager {
-    fn new(host: &'static str, port: u16, check_interval: u64, nodes: Vec<ServerNode>) -> Manager {
+    fn new(host: &'static str, config: ListenerConfig, nodes: Vec<ServerNode>,
+           stats: Arc<Stats>) -> Manager {
         Manager {
             threads: vec![],
+            senders: vec![],
             host: host,
-            port: port,
             nodes: nodes,
-            check_interval: check_interval
+            config: config,
+            stats: stats,
+            unix: None,
+            capture: None,
         }
     }
 
-    fn run(&mut self) {
+    // Binds in the calling thread so a taken port is reported before any
+    // worker claims to be running. The worker pins itself to `cpu` first.
+    fn run(&mut self, cpu: Option<usize>) -> io::Result<()> {
         let host = self.host;
-        let port = self.port;
-        let ci = self.check_interval;
+        let port = self.config.bind;
+        let listen = self.config.listen.clone();
+        let health = self.config.health.clone();
+        let route = self.config.route.clone();
+        let forward = self.config.forward.clone();
+        let stats = self.stats.clone();
+        let name = self.config.name.clone();
+        let mut ci = cmp::min(health.probe_interval, health.eval_interval);
+        if health.probe_jitter > 0 {
+            // Jittered probe times need a finer timer than the interval.
+            ci = cmp::max(1, ci / 10);
+        }
 
         let nodes = self.nodes.clone();
+        let (tx, rx) = mpsc::channel();
+
+        if self.unix.is_none() {
+            if let Some(ref path) = self.config.listen.unix_bind {
+                self.unix = Some(try!(UnixListener::bind(path).map_err(|e| context(e, path))));
+            }
+        }
+        let unix = match self.unix {
+            Some(ref unix) => Some(try!(unix.try_clone())),
+            None => None,
+        };
+        let unix_path = self.config.listen.unix_bind.clone();
+        let capture = self.capture.clone();
+
+        let server = try!(UdpListener::bind_reuse((host, port), listen.reuse_port)
+            .map_err(|e| context(e, &format!("{}:{}", host, port))));
+        // `bind: 0` takes the port the kernel gave the first worker; the
+        // rest must join that one port to share its traffic.
+        let port = if port == 0 {
+            let port = try!(server.local_addr()).port();
+            self.config.bind = port;
+            port
+        } else {
+            port
+        };
 
         let t = thread::spawn(move || {
-            let server = UdpListener::bind((host, port)).unwrap();
+            if let Some(cpu) = cpu {
+                match pin_to_cpu(cpu) {
+                    Ok(()) => log!(Info, "worker of proxy {} pinned to CPU {}", name, cpu),
+                    Err(e) => log!(Warn, "pinning worker of proxy {} to CPU {} failed: {}",
+                                   name, cpu, e),
+                }
+            }
+
+            if let Err(e) = server.set_rxq_ovfl() {
+                log!(Warn, "kernel drop counter unavailable: {}", e);
+            }
+
+            if let Some(size) = listen.recv_buffer {
+                match server.set_recv_buffer(size, listen.force_recv_buffer) {
+                    Ok(effective) => log!(Info, "receive buffer is {} bytes", effective),
+                    Err(e) => log!(Warn, "setting receive buffer failed: {}", e),
+                }
+            }
 
             let mut config = mio::EventLoopConfig::default();
             config.timer_tick_ms = ci;
             let mut event_loop = mio::EventLoop::configured(config).unwrap();
+            tx.send(event_loop.channel()).unwrap();
 
-            event_loop.register_opt(
-                &server, SERVER,
-                mio::EventSet::readable() |
-                    mio::EventSet::hup() |
-                    mio::EventSet::error(),
-                mio::PollOpt::edge()).unwrap();
+            let mut proxy = Proxy::new(server, nodes, health, route, forward, stats.clone());
+            proxy.set_level_triggered(listen.level_triggered);
+            if let Some(unix) = unix {
+                proxy.add_unix(unix);
+            }
+            if let Some(capture) = capture {
+                proxy.set_capture(capture);
+            }
+            proxy.start(&mut event_loop);
 
-            let mut proxy = Proxy::new(server, nodes);
-
-            println!("running proxy at {}:{}", host, port);
-            event_loop.run(&mut proxy).unwrap();
+            log!(Info, "running proxy {} at {}:{}", name, host, port);
+            if let Some(path) = unix_path {
+                log!(Info, "running proxy {} at {}", name, path);
+            }
+            stats.workers.fetch_add(1, Ordering::SeqCst);
+            let res = event_loop.run(&mut proxy);
+            stats.workers.fetch_sub(1, Ordering::SeqCst);
+            res.unwrap();
         });
         self.threads.push(t);
+
+        if let Ok(sender) = rx.recv() {
+            self.senders.push(sender);
+        }
+        Ok(())
+    }
+
+    // Only the node list of a running listener can change, everything else
+    // needs a restart.
+    fn reload(&mut self, nodes: Vec<ServerNode>) {
+        for sender in self.senders.iter() {
+            let _ = sender.send(Control::Reload(nodes.clone()));
+        }
+        self.nodes = nodes;
+    }
+
+    fn shutdown(&self) {
+        for sender in self.senders.iter() {
+            let _ = sender.send(Control::Shutdown);
+        }
     }
 
     fn join(self) {
@@ -72,38 +203,334 @@ impl Manager {
     }
 }
 
+// `e` with the address it is about in front, for `main` to report.
+fn context(e: io::Error, addr: &str) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", addr, e))
+}
+
+// Restricts the calling thread to `cpu`.
+fn pin_to_cpu(cpu: usize) -> io::Result<()> {
+    let mut set = CpuSet::new();
+    set.set(cpu);
+    sched::sched_setaffinity(0, &set).map_err(|e| io::Error::from_raw_os_error(e.errno() as i32))
+}
+
+fn usage(program: &str) -> ! {
+    println!("usage: {} [--check-config] <config.yml | - | http://...>\n       \
+              {} --ring-dump <config.yml> [keys-file]\n       \
+              {} --generate <config.yml> <packets/sec> [names]\n       \
+              {} --replay <capture-file> <host:port>\n       \
+              {} --version", program, program, program, program, program);
+    process::exit(2);
+}
+
+// Disabled nodes are left out, so they get neither ring entries nor health
+// check connections.
+fn build_nodes(config: &ListenerConfig) -> Vec<ServerNode> {
+    let mut nodes: Vec<ServerNode> = Vec::new();
+    for spec in config.nodes.iter().filter(|n| n.enabled) {
+        let node = ServerNode::new(nodes.len(), &spec.host, spec.port, spec.adminport,
+                                   spec.replicas, spec.health_type,
+                                   spec.source_addr.or(config.forward.source_addr));
+        // A host can stop resolving after the config was checked; leave the
+        // node out rather than take down the caller.
+        let mut node = match node {
+            Ok(node) => node,
+            Err(e) => {
+                log!(Error, "node {}:{} left out: {}", spec.host, spec.port, e);
+                continue;
+            }
+        };
+        node.pool = spec.pool;
+        node.tags = spec.tags.join(",").into_bytes();
+        node.transport = spec.transport;
+        nodes.push(node);
+    }
+    nodes
+}
+
+// Applies the node lists of the config at `path` on SIGHUP. Everything else
+// stays as started, so only the node lists and the logging settings (both
+// applied here) change in the running config.
+fn reload(path: &str, running: &RwLock<Config>, managers: &mut [Manager]) {
+    if path == "-" {
+        log!(Warn, "config was read from stdin, restart to change it");
+        return;
+    }
+
+    let config = match Config::load(path) {
+        Ok(config) => config,
+        Err(e) => {
+            log!(Error, "reload of {} failed, keeping the running config: {}", path, e);
+            return;
+        }
+    };
+
+    let mut running = running.write().unwrap();
+    for m in managers.iter_mut() {
+        match config.listeners.iter().find(|l| l.name == m.config.name) {
+            // The rings are sized per pool when the workers start.
+            Some(listener) if listener.route.pools != m.config.route.pools => {
+                log!(Warn, "pools of listener {} changed, restart to apply", m.config.name)
+            }
+            Some(listener) if listener.route.vnode_key != m.config.route.vnode_key => {
+                log!(Warn, "vnode_key of listener {} changed, restart to apply", m.config.name)
+            }
+            Some(listener) => {
+                m.config.nodes = listener.nodes.clone();
+                let nodes = build_nodes(&m.config);
+                m.reload(nodes);
+                if let Some(l) = running.listeners.iter_mut().find(|l| l.name == listener.name) {
+                    l.nodes = listener.nodes.clone();
+                }
+            }
+            None => log!(Warn, "listener {} is no longer configured, restart to stop it",
+                         m.config.name),
+        }
+    }
+    logging::set_format(config.log_format);
+    logging::set_level(config.log_level);
+    running.log_format = config.log_format;
+    running.log_level = config.log_level;
+    log!(Info, "reloaded {}", path);
+}
+
+// Replaces `bind: 0` with the port each running listener actually got.
+fn bound_ports(config: &mut Config, managers: &[Manager]) {
+    for listener in config.listeners.iter_mut().filter(|l| l.bind == 0) {
+        if let Some(m) = managers.iter().find(|m| m.config.name == listener.name) {
+            listener.bind = m.config.bind;
+        }
+    }
+}
+
+fn read_keys(path: Option<&String>) -> io::Result<Vec<String>> {
+    let input: Box<BufRead> = match path {
+        Some(path) => Box::new(BufReader::new(try!(File::open(path)))),
+        None => Box::new(BufReader::new(io::stdin())),
+    };
+    input.lines().collect()
+}
+
+// Prints the ring the proxy would build from `config` and the node each key
+// (a metric name or a full statsd line, one per line) routes to. The ring
+// doesn't expose the vnode points, so per node the vnode count and the share
+// of the sampled keys it owns are shown instead.
+fn ring_dump(config: &ListenerConfig, keys: &[String]) {
+    let mut nodes = build_nodes(config);
+    let rings = hash::build_rings(&mut nodes, config.route.pools.len(), &config.route.vnode_key);
+
+    let mut owned = vec![0; nodes.len()];
+    let mut total = 0;
+    let delimiter = config.route.value_delimiter;
+    for line in keys.iter() {
+        let name = line.split(delimiter as char).next().unwrap_or("").trim();
+        if name.is_empty() {
+            continue;
+        }
+        let (key, pool) = match config.route.route_key(line.trim().as_bytes()) {
+            Some(k) => k,
+            None => continue,
+        };
+
+        match config.route.owner(&rings[pool], &key) {
+            Some(node) => {
+                owned[nodes.iter().position(|n| n == node).unwrap()] += 1;
+                total += 1;
+                println!("{} -> {} (key {})", name, node.name(), String::from_utf8_lossy(&key));
+            }
+            None => println!("{} -> no node", name),
+        }
+    }
+
+    println!("listener {} ring: {} nodes", config.name, nodes.len());
+    for (i, node) in nodes.iter().enumerate() {
+        let share = if total > 0 { owned[i] as f64 * 100.0 / total as f64 } else { 0.0 };
+        println!("  {}: {} vnodes, {} keys ({:.1}%), pool {}", node.name(), node.replicas,
+                 owned[i], share, config.route.pools[node.pool]);
+    }
+}
+
 pub fn main() {
     let args: Vec<_> = env::args().collect();
-    let mut s = String::new();
-    let mut f = File::open(&args[1]).unwrap();
-    f.read_to_string(&mut s).unwrap();
 
-    let docs = yaml::YamlLoader::load_from_str(&s).unwrap();
+    if args.len() == 2 && args[1] == "--version" {
+        println!("{}", build_info::describe());
+        return;
+    }
+
+    // Re-sends a `capture_file`; needs no config.
+    if args.len() == 4 && args[1] == "--replay" {
+        unsafe {
+            libc::signal(libc::SIGTERM, on_signal as libc::sighandler_t);
+            libc::signal(libc::SIGINT, on_signal as libc::sighandler_t);
+        }
+        match capture::replay(&args[2], &args[3], &SHUTDOWN) {
+            Ok(n) => println!("replayed {} datagrams to {}", n, args[3]),
+            Err(e) => {
+                let _ = writeln!(io::stderr(), "replaying {} failed: {}", args[2], e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
 
-    assert!(docs.len() >= 1);
+    let (check_only, ring_only, path) = match args.len() {
+        2 => (false, false, &args[1]),
+        3 if args[1] == "--check-config" => (true, false, &args[2]),
+        3 | 4 if args[1] == "--ring-dump" => (false, true, &args[2]),
+        4 | 5 if args[1] == "--generate" => (false, false, &args[2]),
+        _ => usage(&args[0]),
+    };
 
-    let doc = &docs[0];
+    let config = match Config::load(path) {
+        Ok(config) => config,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "{}: {}", path, e);
+            process::exit(1);
+        }
+    };
 
-    let bind = doc["bind"].as_i64().unwrap_or(8977) as u16;
-    let threads = doc["threads"].as_i64().unwrap_or(4);
-    let check_interval = doc["check_interval"].as_i64().unwrap_or(1000) as u64;
+    if check_only {
+        print!("{}", config.summary());
+        println!("{}: config ok", path);
+        return;
+    }
 
-    let mut nodes: Vec<ServerNode> = Vec::new();
-    let node_spec = doc["nodes"].as_hash().unwrap();
+    logging::set_format(config.log_format);
+    logging::set_level(config.log_level);
+
+    if ring_only {
+        let keys = match read_keys(args.get(3)) {
+            Ok(keys) => keys,
+            Err(e) => {
+                let _ = writeln!(io::stderr(), "reading keys failed: {}", e);
+                process::exit(1);
+            }
+        };
+        for listener in config.listeners.iter() {
+            ring_dump(listener, &keys);
+        }
+        return;
+    }
+
+    // Soak test traffic for a proxy already running with this config; nothing
+    // is bound or forwarded here.
+    if args[1] == "--generate" {
+        let rate = args[3].parse::<u64>().unwrap_or_else(|_| usage(&args[0]));
+        let names = match args.get(4) {
+            Some(n) => n.parse::<u64>().ok().and_then(|n| if n > 0 { Some(n) } else { None })
+                .unwrap_or_else(|| usage(&args[0])),
+            None => 1000,
+        };
+        unsafe {
+            libc::signal(libc::SIGTERM, on_signal as libc::sighandler_t);
+            libc::signal(libc::SIGINT, on_signal as libc::sighandler_t);
+        }
+        if let Err(e) = soak::generate(&config, rate, names, &SHUTDOWN) {
+            let _ = writeln!(io::stderr(), "generating traffic failed: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
 
-    for i in node_spec.values() {
-        nodes.push(ServerNode::new(
-                i["host"].as_str().unwrap(),
-                i["port"].as_i64().unwrap() as u16,
-                i["adminport"].as_i64().unwrap() as u16,
-            )
-        )
+    if config.listeners.iter().any(|l| l.forward.transparent) {
+        let probe = Socket::new(AddressFamily::Inet, SockType::Datagram, true)
+            .and_then(|s| s.set_transparent());
+        if let Err(e) = probe {
+            let _ = writeln!(io::stderr(), "transparent mode needs CAP_NET_ADMIN \
+                                            (IP_TRANSPARENT failed: {})", e);
+            process::exit(1);
+        }
+    }
+
+    let stats: Vec<_> = config.listeners.iter()
+        .map(|l| (l.name.clone(), Arc::new(Stats::new())))
+        .collect();
+
+    let running = Arc::new(RwLock::new(config.clone()));
+
+    if let Some(port) = config.stats_port {
+        let admin = Admin::new(running.clone(), stats.clone(), &SHUTDOWN);
+        if let Err(e) = admin::spawn(&config.stats_host, port, admin) {
+            let _ = writeln!(io::stderr(), "failed to bind stats port {}: {}", port, e);
+            process::exit(1);
+        }
     }
 
-    let mut m = Manager::new("0.0.0.0", bind, check_interval, nodes);
+    let capture = match config.capture {
+        Some(ref c) => match Capture::start(c) {
+            Ok(capture) => {
+                log!(Info, "capturing forwarded datagrams to {}", c.path);
+                Some(capture)
+            }
+            Err(e) => {
+                let _ = writeln!(io::stderr(), "opening capture file {} failed: {}", c.path, e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
 
-    for _ in 0..threads {
-        m.run();
+    log!(Info, "starting {} workers per listener", config.threads);
+    let mut managers = Vec::new();
+    for (listener, &(_, ref stats)) in config.listeners.iter().zip(stats.iter()) {
+        let nodes = build_nodes(listener);
+        let mut m = Manager::new("0.0.0.0", listener.clone(), nodes, stats.clone());
+        m.capture = capture.clone();
+        for i in 0..config.threads {
+            if let Err(e) = m.run(config.cpu_affinity.get(i).cloned()) {
+                let hint = if e.kind() == io::ErrorKind::AddrInUse {
+                    " (held by a process that doesn't set SO_REUSEPORT)"
+                } else {
+                    ""
+                };
+                let _ = writeln!(io::stderr(), "failed to bind listener {}: {}{}",
+                                 listener.name, e, hint);
+                process::exit(1);
+            }
+        }
+        managers.push(m);
+    }
+    bound_ports(&mut running.write().unwrap(), &managers);
+
+    unsafe {
+        libc::signal(libc::SIGTERM, on_signal as libc::sighandler_t);
+        libc::signal(libc::SIGINT, on_signal as libc::sighandler_t);
+        libc::signal(libc::SIGHUP, on_signal as libc::sighandler_t);
+    }
+    let mut self_metrics = match config.self_metrics_node {
+        Some(ref node) => match SelfMetrics::new(node, &config.self_metrics_prefix) {
+            Ok(m) => Some(m),
+            Err(e) => {
+                let _ = writeln!(io::stderr(), "self_metrics_node {} failed: {}", node, e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let mut reported = Instant::now();
+
+    while !SHUTDOWN.load(Ordering::SeqCst) {
+        if RELOAD.swap(false, Ordering::SeqCst) {
+            reload(path, &running, &mut managers);
+        }
+        if let Some(ref mut m) = self_metrics {
+            if reported.elapsed() >= config.self_metrics_interval {
+                if let Err(e) = m.report(&stats) {
+                    log!(Warn, "sending self metrics failed: {}", e);
+                }
+                reported = Instant::now();
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    log!(Info, "shutting down");
+    for m in managers.iter() {
+        m.shutdown();
+    }
+    for m in managers.into_iter() {
+        m.join();
     }
-    m.join();
 }
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use socket::UdpStream;
+use stats::Stats;
+
+// Largest datagram sent, the usual statsd client limit.
+const MAX_DATAGRAM: usize = 1432;
+
+// Listener and node names as one metric name segment: dots would nest it and
+// colons end the name.
+fn segment(s: &str) -> String {
+    s.chars().map(|c| match c {
+        'a'...'z' | 'A'...'Z' | '0'...'9' | '-' | '_' => c,
+        _ => '_',
+    }).collect()
+}
+
+// The proxy's own counters as statsd metrics, `<prefix><listener>.<name>`,
+// sent to one fixed node rather than hashed over the ring (or sent through
+// the proxy), so they neither skew placement nor count themselves. Counters
+// are reported as the change since the last report, gauges as they stand.
+pub struct SelfMetrics {
+    out: UdpStream,
+    prefix: String,
+    last: HashMap<String, usize>,
+}
+
+impl SelfMetrics {
+    pub fn new(node: &str, prefix: &str) -> io::Result<SelfMetrics> {
+        Ok(SelfMetrics {
+            out: try!(UdpStream::new(node)),
+            prefix: prefix.to_owned(),
+            last: HashMap::new(),
+        })
+    }
+
+    pub fn lines(&mut self, stats: &[(String, Arc<Stats>)]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for &(ref listener, ref s) in stats.iter() {
+            let base = format!("{}{}.", self.prefix, segment(listener));
+            let mut counters = vec![
+                ("packets_received".to_owned(),
+                 s.udp_packets.load(Ordering::Relaxed) + s.unix_packets.load(Ordering::Relaxed)),
+                ("dropped_backpressure".to_owned(), s.dropped_backpressure.load(Ordering::Relaxed)),
+                ("paused_drops".to_owned(), s.paused_drops.load(Ordering::Relaxed)),
+                ("dead_lettered".to_owned(), s.dead_lettered.load(Ordering::Relaxed)),
+                ("rejected_keys".to_owned(), s.rejected_keys.load(Ordering::Relaxed)),
+                ("kernel_rx_drops".to_owned(), s.kernel_rx_drops.load(Ordering::Relaxed)),
+            ];
+            let mut forwarded = 0;
+            for (node, history) in s.nodes.lock().unwrap().iter() {
+                let sent = history.forwarded.load(Ordering::Relaxed);
+                forwarded += sent;
+                counters.push((format!("nodes.{}.forwarded", segment(node)), sent));
+                counters.push((format!("nodes.{}.forward_errors", segment(node)),
+                               history.forward_errors.load(Ordering::Relaxed)));
+            }
+            counters.push(("forwarded".to_owned(), forwarded));
+
+            for (name, value) in counters.into_iter() {
+                let name = format!("{}{}", base, name);
+                let last = self.last.insert(name.clone(), value).unwrap_or(0);
+                let delta = if value > last { value - last } else { 0 };
+                lines.push(format!("{}:{}|c", name, delta));
+            }
+            lines.push(format!("{}ring_nodes:{}|g", base, s.ring_nodes.load(Ordering::Relaxed)));
+            lines.push(format!("{}outstanding:{}|g", base, s.outstanding.load(Ordering::Relaxed)));
+            for (node, history) in s.nodes.lock().unwrap().iter() {
+                lines.push(format!("{}nodes.{}.queue_depth:{}|g", base, segment(node),
+                                   history.queue_depth.load(Ordering::Relaxed)));
+            }
+        }
+        lines
+    }
+
+    // Batched into as few datagrams as fit; a send that would block is
+    // skipped, the next report carries its counts on.
+    pub fn report(&mut self, stats: &[(String, Arc<Stats>)]) -> io::Result<()> {
+        let mut datagram = String::new();
+        for line in self.lines(stats).iter() {
+            if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM {
+                try!(self.out.write(datagram.as_bytes()));
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(line);
+        }
+        if !datagram.is_empty() {
+            try!(self.out.write(datagram.as_bytes()));
+        }
+        Ok(())
+    }
+}
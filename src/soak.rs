@@ -0,0 +1,89 @@
+use std::cmp;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use build_nodes;
+use config::{millis, Config};
+use hash::{self, Node};
+
+// Most packets sent in one go when behind, so `stop` and the report are
+// still checked when the target rate can't be reached.
+const MAX_BURST: u64 = 1000;
+
+fn key(i: u64) -> String {
+    format!("soak.metric.{}", i)
+}
+
+fn seconds(d: Duration) -> f64 {
+    millis(d) as f64 / 1000.0
+}
+
+// `--generate`: sends `rate` counters a second to the listeners' bind ports
+// on 127.0.0.1 in turn, cycling through `cardinality` names, until `stop` is
+// set. Prints the rate achieved every second and at the end how the names
+// hash over each listener's nodes, to compare with what the backends got.
+pub fn generate(config: &Config, rate: u64, cardinality: u64,
+                stop: &AtomicBool) -> io::Result<()> {
+    let sock = try!(UdpSocket::bind("127.0.0.1:0"));
+    let targets: Vec<SocketAddr> = config.listeners.iter()
+        .map(|l| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), l.bind))
+        .collect();
+
+    let start = Instant::now();
+    let mut report = start;
+    let mut reported = 0;
+    let mut sent = 0;
+    while !stop.load(Ordering::SeqCst) {
+        let due = rate * millis(start.elapsed()) / 1000;
+        let burst = if due > sent { due - sent } else { 0 };
+        for _ in 0..cmp::min(burst, MAX_BURST) {
+            let target = targets[(sent % targets.len() as u64) as usize];
+            let name = key(sent / targets.len() as u64 % cardinality);
+            try!(sock.send_to(format!("{}:1|c", name).as_bytes(), target));
+            sent += 1;
+        }
+
+        if report.elapsed() >= Duration::from_secs(1) {
+            println!("sent {} packets, {:.0}/s", sent,
+                     (sent - reported) as f64 / seconds(report.elapsed()));
+            report = Instant::now();
+            reported = sent;
+        }
+        if burst <= MAX_BURST {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    let elapsed = seconds(start.elapsed());
+    println!("sent {} packets in {:.1}s, {:.0}/s on average (asked for {}/s)",
+             sent, elapsed, sent as f64 / elapsed, rate);
+
+    // Every name is sent equally often, so its owner's share of the names
+    // is the share of the packets it should have received.
+    for listener in config.listeners.iter() {
+        let mut nodes = build_nodes(listener);
+        let rings = hash::build_rings(&mut nodes, listener.route.pools.len(),
+                                      &listener.route.vnode_key);
+        let mut owned = vec![0; nodes.len()];
+        for i in 0..cardinality {
+            let owner = listener.route.route_key(key(i).as_bytes())
+                .and_then(|(key, pool)| {
+                    listener.route.owner(&rings[pool], &key)
+                        .and_then(|node| nodes.iter().position(|n| n == node))
+                });
+            if let Some(i) = owner {
+                owned[i] += 1;
+            }
+        }
+
+        println!("listener {}: {} names over {} nodes", listener.name, cardinality, nodes.len());
+        for (i, node) in nodes.iter().enumerate() {
+            println!("  {}: {} names ({:.1}%)", node.name(), owned[i],
+                     owned[i] as f64 * 100.0 / cardinality as f64);
+        }
+    }
+    Ok(())
+}
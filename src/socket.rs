@@ -1,9 +1,14 @@
 #![allow(dead_code)]
 
+use std::fs;
 use std::io::{self, Error, ErrorKind};
-use std::net::ToSocketAddrs;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
 use std::os::unix::io::RawFd;
+use std::slice;
+use std::sync::Arc;
 
+use libc;
 use mio;
 use nix;
 use nix::sys::socket as sock;
@@ -16,6 +21,83 @@ pub use nix::sys::socket::{
     Shutdown
 };
 
+const IP_FREEBIND: libc::c_int = 15;
+const IP_TRANSPARENT: libc::c_int = 19;
+const SO_RCVBUFFORCE: libc::c_int = 33;
+const SO_RXQ_OVFL: libc::c_int = 40;
+const IP_MTU_DISCOVER: libc::c_int = 10;
+
+// For options nix doesn't wrap.
+fn setsockopt_int(fd: RawFd, level: libc::c_int, name: libc::c_int,
+                  val: libc::c_int) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(fd, level, name,
+                         &val as *const libc::c_int as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn getsockopt_int(fd: RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<libc::c_int> {
+    let mut val: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(fd, level, name,
+                         &mut val as *mut libc::c_int as *mut libc::c_void, &mut len)
+    };
+
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(val)
+}
+
+fn to_std_addr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let a = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(a.sin_addr.s_addr));
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(a.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let a = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(a.sin6_addr.s6_addr);
+            Some(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(a.sin6_port),
+                                                  a.sin6_flowinfo, a.sin6_scope_id)))
+        }
+        _ => None,
+    }
+}
+
+// Walks the control messages by hand, older libc has no CMSG_* helpers.
+fn rxq_ovfl(control: &[u8], len: usize) -> Option<u32> {
+    let align = |n: usize| (n + mem::size_of::<usize>() - 1) & !(mem::size_of::<usize>() - 1);
+    let header = mem::size_of::<libc::cmsghdr>();
+
+    let mut offset = 0;
+    while offset + header <= len {
+        let cmsg = unsafe { &*(control[offset..].as_ptr() as *const libc::cmsghdr) };
+        let cmsg_len = cmsg.cmsg_len as usize;
+        if cmsg_len < header {
+            return None;
+        }
+
+        if cmsg.cmsg_level == libc::SOL_SOCKET && cmsg.cmsg_type == SO_RXQ_OVFL &&
+                offset + align(header) + 4 <= len {
+            let data = &control[offset + align(header)..];
+            let mut n = [0u8; 4];
+            n.copy_from_slice(&data[0..4]);
+            return Some(unsafe { mem::transmute::<[u8; 4], u32>(n) });
+        }
+        offset += align(cmsg_len);
+    }
+    None
+}
+
 fn from_nix_error(err: nix::Error) -> Error {
     Error::from_raw_os_error(err.errno() as i32)
 }
@@ -32,6 +114,22 @@ fn err_check<T>(err: nix::Error) -> io::Result<Option<T>> {
     Err(e)
 }
 
+// Signal handlers (shutdown, reload) can interrupt any of these calls. Retry a
+// bounded number of times so a signal storm can't spin forever.
+const EINTR_RETRIES: usize = 16;
+
+fn retry<T, F>(mut f: F) -> nix::Result<T>
+    where F: FnMut() -> nix::Result<T>
+{
+    let mut tries = 0;
+    loop {
+        match f() {
+            Err(nix::Error::Sys(nix::errno::EINTR)) if tries < EINTR_RETRIES => tries += 1,
+            res => return res,
+        }
+    }
+}
+
 fn each_addr<A: ToSocketAddrs, F, T>(addr: A, mut f: F) -> io::Result<T>
     where F: FnMut(&SockAddr) -> io::Result<T>
 {
@@ -48,11 +146,33 @@ fn each_addr<A: ToSocketAddrs, F, T>(addr: A, mut f: F) -> io::Result<T>
     }))
 }
 
-#[derive(Clone, Eq, PartialEq)]
+// Ownership of raw descriptors: a `Socket` owns its fd through an `Arc`, and
+// cloning a `Socket` (or a `UdpStream`, which every `ServerNode` clone in the
+// ring and in each worker does) shares that one descriptor instead of copying
+// the integer. The fd is closed exactly once, when the last clone is dropped.
+// Clones therefore also share socket options and mio registrations; anything
+// that needs an independent descriptor must create a new `Socket`.
+struct Fd(RawFd);
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        let _ = nix::unistd::close(self.0);
+    }
+}
+
+#[derive(Clone)]
 pub struct Socket {
-    fd: RawFd,
+    fd: Arc<Fd>,
 }
 
+impl PartialEq for Socket {
+    fn eq(&self, other: &Socket) -> bool {
+        self.fd.0 == other.fd.0
+    }
+}
+
+impl Eq for Socket {}
+
 impl Socket {
     pub fn new(family: AddressFamily, ty: SockType, nonblock: bool) -> io::Result<Socket> {
         let opts = if nonblock {
@@ -67,68 +187,135 @@ impl Socket {
     }
 
     pub fn bind(&self, addr: &SockAddr) -> io::Result<()> {
-        sock::bind(self.fd, addr)
+        sock::bind(self.fd.0, addr)
             .map_err(from_nix_error)
     }
 
     pub fn listen(&self, backlog: usize) -> io::Result<()> {
-        sock::listen(self.fd, backlog)
+        sock::listen(self.fd.0, backlog)
             .map_err(from_nix_error)
     }
 
     fn from_rawfd(fd: RawFd) -> Socket {
-        Socket{fd: fd}
+        Socket{fd: Arc::new(Fd(fd))}
     }
 
+    // An interrupted connect carries on in the background, so EINTR is
+    // reported like EINPROGRESS rather than retried.
     pub fn connect(&self, addr: &SockAddr) -> io::Result<bool> {
-        match sock::connect(self.fd, addr) {
+        match sock::connect(self.fd.0, addr) {
             Ok(_) => Ok(true),
             Err(e) => {
                 match e {
-                    nix::Error::Sys(nix::errno::EINPROGRESS) => Ok(false),
+                    nix::Error::Sys(nix::errno::EINPROGRESS) |
+                    nix::Error::Sys(nix::errno::EINTR) => Ok(false),
                     _ => Err(from_nix_error(e))
                 }
             }
         }
     }
 
-    pub fn accept(&self, nonblock: bool) -> io::Result<Socket> {
+    // Ok(None) once the backlog is empty on a nonblocking listener.
+    pub fn accept(&self, nonblock: bool) -> io::Result<Option<Socket>> {
         let opts = if nonblock {
             sock::SOCK_NONBLOCK | sock::SOCK_CLOEXEC
         } else {
             sock::SOCK_CLOEXEC
         };
 
-        let fd = try!(sock::accept4(self.fd, opts).map_err(from_nix_error));
-
-        Ok(Socket::from_rawfd(fd))
+        match retry(|| sock::accept4(self.fd.0, opts)) {
+            Ok(fd) => Ok(Some(Socket::from_rawfd(fd))),
+            Err(e) => err_check(e),
+        }
     }
 
     pub fn shutdown(&self, how: sock::Shutdown) -> io::Result<()> {
-        sock::shutdown(self.fd, how)
+        sock::shutdown(self.fd.0, how)
             .map_err(from_nix_error)
     }
 
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match try!(sock::getsockname(self.fd.0).map_err(from_nix_error)) {
+            SockAddr::Inet(addr) => Ok(addr.to_std()),
+            _ => Err(Error::new(ErrorKind::InvalidInput, "not an inet socket")),
+        }
+    }
+
     pub fn recvfrom(&self, buf: &mut [u8]) -> io::Result<Option<(usize, SockAddr)>> {
-        sock::recvfrom(self.fd, buf)
+        retry(|| sock::recvfrom(self.fd.0, buf))
             .map(|n| Some(n))
             .or_else(err_check)
     }
 
+    // Like `recvfrom`, but uses recvmsg so the SO_RXQ_OVFL control message,
+    // the kernel's cumulative count of datagrams dropped on this socket, can
+    // be read along with the datagram.
+    pub fn recvmsg(&self, buf: &mut [u8]) -> io::Result<Option<(usize, SockAddr, Option<u32>)>> {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        // usize words keep the control messages aligned for `cmsghdr`.
+        let mut control = [0usize; 8];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut storage as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+
+        let mut tries = 0;
+        let n = loop {
+            let n = unsafe { libc::recvmsg(self.fd.0, &mut msg, libc::MSG_DONTWAIT) };
+            if n >= 0 {
+                break n;
+            }
+
+            let err = Error::last_os_error();
+            match err.kind() {
+                ErrorKind::Interrupted if tries < EINTR_RETRIES => tries += 1,
+                ErrorKind::WouldBlock => return Ok(None),
+                _ => return Err(err),
+            }
+        };
+
+        let addr = match to_std_addr(&storage) {
+            Some(addr) => SockAddr::Inet(InetAddr::from_std(&addr)),
+            None => return Err(Error::new(ErrorKind::InvalidData, "not an inet address")),
+        };
+        let control = unsafe {
+            slice::from_raw_parts(control.as_ptr() as *const u8, mem::size_of_val(&control))
+        };
+        let drops = rxq_ovfl(control, msg.msg_controllen as usize);
+        Ok(Some((n as usize, addr, drops)))
+    }
+
+    // Asks the kernel to attach its drop counter to every received datagram.
+    pub fn set_rxq_ovfl(&self) -> io::Result<()> {
+        setsockopt_int(self.fd.0, libc::SOL_SOCKET, SO_RXQ_OVFL, 1)
+    }
+
     pub fn sendto(&self, buf: &[u8], target: &SockAddr) -> io::Result<Option<usize>> {
-        sock::sendto(self.fd, buf, target, sock::MSG_DONTWAIT)
+        retry(|| sock::sendto(self.fd.0, buf, target, sock::MSG_DONTWAIT))
             .map(|n| Some(n))
             .or_else(err_check)
     }
 
+    pub fn send_to(&self, buf: &[u8], addr: &SocketAddr) -> io::Result<Option<usize>> {
+        self.sendto(buf, &SockAddr::Inet(InetAddr::from_std(addr)))
+    }
+
     pub fn recv(&self, buf: &mut [u8]) -> io::Result<Option<usize>> {
-        sock::recv(self.fd, buf, sock::MSG_DONTWAIT)
+        retry(|| sock::recv(self.fd.0, buf, sock::MSG_DONTWAIT))
             .map(|n| Some(n))
             .or_else(err_check)
     }
 
     pub fn send(&self, buf: &[u8]) -> io::Result<Option<usize>> {
-        sock::send(self.fd, buf, sock::MSG_DONTWAIT)
+        retry(|| sock::send(self.fd.0, buf, sock::MSG_DONTWAIT))
             .map(|n| Some(n))
             .or_else(err_check)
     }
@@ -136,26 +323,140 @@ impl Socket {
     pub fn set_reuse(&self) -> io::Result<()> {
         let val = true;
 
-        try!(sock::setsockopt(self.fd, sock::sockopt::ReuseAddr, &val).map_err(from_nix_error));
+        try!(self.set_reuse_addr());
 
-        sock::setsockopt(self.fd, sock::sockopt::ReusePort, &val)
+        sock::setsockopt(self.fd.0, sock::sockopt::ReusePort, &val)
             .map_err(from_nix_error)
     }
+
+    pub fn set_reuse_addr(&self) -> io::Result<()> {
+        let val = true;
+
+        sock::setsockopt(self.fd.0, sock::sockopt::ReuseAddr, &val).map_err(from_nix_error)
+    }
+
+    // With `force`, SO_RCVBUFFORCE lifts the net.core.rmem_max cap but needs
+    // CAP_NET_ADMIN; without the capability this falls back to SO_RCVBUF.
+    // Returns the effective size as reported by the kernel.
+    pub fn set_recv_buffer(&self, size: usize, force: bool) -> io::Result<usize> {
+        if force {
+            match setsockopt_int(self.fd.0, libc::SOL_SOCKET, SO_RCVBUFFORCE,
+                                 size as libc::c_int) {
+                Ok(_) => return self.recv_buffer(),
+                Err(e) => log!(Warn, "SO_RCVBUFFORCE failed ({}), falling back to SO_RCVBUF", e),
+            }
+        }
+
+        try!(setsockopt_int(self.fd.0, libc::SOL_SOCKET, libc::SO_RCVBUF, size as libc::c_int));
+        self.recv_buffer()
+    }
+
+    pub fn recv_buffer(&self) -> io::Result<usize> {
+        getsockopt_int(self.fd.0, libc::SOL_SOCKET, libc::SO_RCVBUF).map(|v| v as usize)
+    }
+
+    pub fn send_buffer(&self) -> io::Result<usize> {
+        getsockopt_int(self.fd.0, libc::SOL_SOCKET, libc::SO_SNDBUF).map(|v| v as usize)
+    }
+
+    pub fn reuse_port(&self) -> io::Result<bool> {
+        getsockopt_int(self.fd.0, libc::SOL_SOCKET, libc::SO_REUSEPORT).map(|v| v != 0)
+    }
+
+    // Only meaningful for IPv4 sockets.
+    pub fn tos(&self) -> io::Result<u8> {
+        getsockopt_int(self.fd.0, libc::IPPROTO_IP, libc::IP_TOS).map(|v| v as u8)
+    }
+
+    pub fn set_mtu_discover(&self, mode: MtuDiscover) -> io::Result<()> {
+        setsockopt_int(self.fd.0, libc::IPPROTO_IP, IP_MTU_DISCOVER, mode as libc::c_int)
+    }
+
+    // None for modes other than the three we set (e.g. IP_PMTUDISC_PROBE).
+    pub fn mtu_discover(&self) -> io::Result<Option<MtuDiscover>> {
+        getsockopt_int(self.fd.0, libc::IPPROTO_IP, IP_MTU_DISCOVER).map(|v| match v {
+            0 => Some(MtuDiscover::Dont),
+            1 => Some(MtuDiscover::Want),
+            2 => Some(MtuDiscover::Do),
+            _ => None,
+        })
+    }
+
+    pub fn options(&self) -> io::Result<SockOpts> {
+        Ok(SockOpts {
+            recv_buffer: try!(self.recv_buffer()),
+            send_buffer: try!(self.send_buffer()),
+            reuse_port: try!(self.reuse_port()),
+            tos: self.tos().ok(),
+            mtu_discover: self.mtu_discover().ok().and_then(|m| m),
+        })
+    }
+
+    // Allows binding to, and sending from, addresses that aren't local.
+    // Needs CAP_NET_ADMIN.
+    pub fn set_transparent(&self) -> io::Result<()> {
+        try!(setsockopt_int(self.fd.0, libc::IPPROTO_IP, IP_TRANSPARENT, 1));
+        setsockopt_int(self.fd.0, libc::IPPROTO_IP, IP_FREEBIND, 1)
+    }
+
+    // A socket bound to a client's address, used to forward its datagrams
+    // with the original source address and port preserved.
+    pub fn transparent(src: &SocketAddr) -> io::Result<Socket> {
+        let sock = try!(Socket::new(AddressFamily::Inet, SockType::Datagram, true));
+
+        try!(sock.set_reuse());
+        try!(sock.set_transparent());
+        try!(sock.bind(&SockAddr::new_inet(InetAddr::from_std(src))));
+        Ok(sock)
+    }
 }
 
 impl mio::Evented for Socket {
     fn register(&self, selector: &mut mio::Selector, token: mio::Token,
                 interest: mio::EventSet, opts: mio::PollOpt) -> io::Result<()> {
-        selector.register(self.fd, token, interest, opts)
+        selector.register(self.fd.0, token, interest, opts)
     }
 
     fn reregister(&self, selector: &mut mio::Selector, token: mio::Token,
                   interest: mio::EventSet, opts: mio::PollOpt) -> io::Result<()> {
-        selector.reregister(self.fd, token, interest, opts)
+        selector.reregister(self.fd.0, token, interest, opts)
     }
 
     fn deregister(&self, selector: &mut mio::Selector) -> io::Result<()> {
-        selector.deregister(self.fd)
+        selector.deregister(self.fd.0)
+    }
+}
+
+// Options as the kernel reports them, which for buffers is double the size
+// asked for (the kernel counts its bookkeeping overhead too).
+#[derive(Clone, Copy, Debug)]
+pub struct SockOpts {
+    pub recv_buffer: usize,
+    pub send_buffer: usize,
+    pub reuse_port: bool,
+    pub tos: Option<u8>,
+    pub mtu_discover: Option<MtuDiscover>,
+}
+
+// Path MTU discovery on a socket (IP_MTU_DISCOVER). `Dont` never sets DF
+// and lets routers fragment, `Want` is the kernel default and fragments
+// locally to the path MTU it knows, `Do` always sets DF, so a datagram over
+// the path MTU fails with EMSGSIZE instead of going out fragmented.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MtuDiscover {
+    Dont = 0,
+    Want = 1,
+    Do = 2,
+}
+
+impl MtuDiscover {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            MtuDiscover::Dont => "dont",
+            MtuDiscover::Want => "want",
+            MtuDiscover::Do => "do",
+        }
     }
 }
 
@@ -200,15 +501,73 @@ impl TcpStream {
     }
 }
 
+// A nonblocking TCP listener for the event loop. Registered edge triggered
+// it is signalled once for however many connections arrived, so readiness
+// handlers must drain the backlog with `accept_pending`, not take one.
+pub struct TcpListener {
+    sock: Socket,
+}
+
+impl TcpListener {
+    pub fn bind<A: ToSocketAddrs>(addr: A, backlog: usize) -> io::Result<TcpListener> {
+        let sock = try!(Socket::new(AddressFamily::Inet, SockType::Stream, true));
+
+        try!(sock.set_reuse());
+        try!(each_addr(addr, |a| sock.bind(a)));
+        try!(sock.listen(backlog));
+
+        Ok(TcpListener {sock: sock})
+    }
+
+    pub fn accept(&self) -> io::Result<Option<TcpStream>> {
+        Ok(try!(self.sock.accept(true)).map(|sock| TcpStream {sock: sock}))
+    }
+
+    // Accepts until the backlog is empty and returns how many were added to
+    // `streams`. A client that reset while queued fails only its own accept
+    // (ECONNABORTED, EPROTO) and is skipped; on any other error the ones
+    // accepted before it are kept there.
+    pub fn accept_pending(&self, streams: &mut Vec<TcpStream>) -> io::Result<usize> {
+        let mut n = 0;
+        loop {
+            match self.accept() {
+                Ok(Some(stream)) => {
+                    streams.push(stream);
+                    n += 1;
+                }
+                Ok(None) => return Ok(n),
+                Err(e) => match e.raw_os_error() {
+                    Some(libc::ECONNABORTED) | Some(libc::EPROTO) => {}
+                    _ => return Err(e),
+                },
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.sock.local_addr()
+    }
+}
+
 pub struct UdpListener {
     sock: Socket,
 }
 
 impl UdpListener {
     pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<UdpListener> {
+        UdpListener::bind_reuse(addr, true)
+    }
+
+    // Without `reuse_port` the socket is the port's only queue: no other
+    // socket, another worker included, can bind it alongside.
+    pub fn bind_reuse<A: ToSocketAddrs>(addr: A, reuse_port: bool) -> io::Result<UdpListener> {
         let sock = try!(Socket::new(AddressFamily::Inet, SockType::Datagram, true));
 
-        try!(sock.set_reuse());
+        if reuse_port {
+            try!(sock.set_reuse());
+        } else {
+            try!(sock.set_reuse_addr());
+        }
         try!(each_addr(addr, |a| sock.bind(a)));
 
         Ok(UdpListener {sock: sock})
@@ -217,6 +576,100 @@ impl UdpListener {
     pub fn read(&self, buf: &mut [u8]) -> io::Result<Option<(usize, SockAddr)>> {
         self.sock.recvfrom(buf)
     }
+
+    // Also returns the kernel drop counter once `set_rxq_ovfl` is on.
+    pub fn read_msg(&self, buf: &mut [u8]) -> io::Result<Option<(usize, SockAddr, Option<u32>)>> {
+        self.sock.recvmsg(buf)
+    }
+
+    pub fn set_rxq_ovfl(&self) -> io::Result<()> {
+        self.sock.set_rxq_ovfl()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.sock.local_addr()
+    }
+
+    pub fn set_recv_buffer(&self, size: usize, force: bool) -> io::Result<usize> {
+        self.sock.set_recv_buffer(size, force)
+    }
+
+    pub fn options(&self) -> io::Result<SockOpts> {
+        self.sock.options()
+    }
+}
+
+// A Unix datagram socket at `path`. A stale socket file left by an earlier
+// run is removed before binding.
+pub struct UnixListener {
+    sock: Socket,
+}
+
+impl UnixListener {
+    pub fn bind(path: &str) -> io::Result<UnixListener> {
+        let sock = try!(Socket::new(AddressFamily::Unix, SockType::Datagram, true));
+        let addr = try!(SockAddr::new_unix(path).map_err(from_nix_error));
+
+        match fs::remove_file(path) {
+            Err(ref e) if e.kind() != ErrorKind::NotFound => {
+                return Err(Error::new(e.kind(), format!("removing stale {}: {}", path, e)));
+            }
+            _ => {}
+        }
+        try!(sock.bind(&addr));
+
+        Ok(UnixListener {sock: sock})
+    }
+
+    // Each worker reads through its own descriptor for the one bound path.
+    pub fn try_clone(&self) -> io::Result<UnixListener> {
+        let fd = try!(nix::unistd::dup(self.sock.fd.0).map_err(from_nix_error));
+        Ok(UnixListener {sock: Socket::from_rawfd(fd)})
+    }
+
+    pub fn read(&self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        self.sock.recv(buf)
+    }
+
+    pub fn set_recv_buffer(&self, size: usize, force: bool) -> io::Result<usize> {
+        self.sock.set_recv_buffer(size, force)
+    }
+
+    pub fn options(&self) -> io::Result<SockOpts> {
+        self.sock.options()
+    }
+}
+
+impl mio::Evented for UnixListener {
+    fn register(&self, selector: &mut mio::Selector, token: mio::Token,
+                interest: mio::EventSet, opts: mio::PollOpt) -> io::Result<()> {
+        self.sock.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut mio::Selector, token: mio::Token,
+                  interest: mio::EventSet, opts: mio::PollOpt) -> io::Result<()> {
+        self.sock.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut mio::Selector) -> io::Result<()> {
+        self.sock.deregister(selector)
+    }
+}
+
+impl mio::Evented for TcpListener {
+    fn register(&self, selector: &mut mio::Selector, token: mio::Token,
+                interest: mio::EventSet, opts: mio::PollOpt) -> io::Result<()> {
+        self.sock.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut mio::Selector, token: mio::Token,
+                  interest: mio::EventSet, opts: mio::PollOpt) -> io::Result<()> {
+        self.sock.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut mio::Selector) -> io::Result<()> {
+        self.sock.deregister(selector)
+    }
 }
 
 impl mio::Evented for UdpListener {
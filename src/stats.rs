@@ -0,0 +1,409 @@
+use std::cmp;
+use std::mem;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use socket::SockOpts;
+
+// Upper bounds of the routing key length buckets; the last bucket counts
+// everything longer.
+pub const KEY_LENGTH_BUCKETS: [usize; 3] = [16, 64, 256];
+
+// Upper bounds, in microseconds, of the forward latency buckets (a datagram
+// read until its last send returns); the last bucket counts everything slower.
+pub const LATENCY_BUCKETS: [usize; 7] = [10, 25, 50, 100, 250, 1000, 10000];
+
+// Upper bounds, in microseconds, of the health check latency buckets (a
+// check written until its reply is read) served on GET /metrics; the last
+// bucket counts everything slower.
+pub const CHECK_LATENCY_BUCKETS: [usize; 8] = [500, 1000, 2500, 5000, 10000, 25000, 100000,
+                                               1000000];
+
+// Ring membership changes kept for GET /events.
+pub const RING_EVENTS: usize = 100;
+
+// Distinct unrouted keys kept for GET /unrouted, and the bytes kept of each.
+pub const UNROUTED_KEYS: usize = 256;
+pub const UNROUTED_KEY_LEN: usize = 128;
+
+// Counters shared by every worker thread.
+#[derive(Default)]
+pub struct Stats {
+    pub outstanding: AtomicUsize,
+    pub backpressure_active: AtomicBool,
+    // Set by POST /pause: workers hold or drop what they read instead of
+    // forwarding it, counting the drops in `paused_drops`.
+    pub paused: AtomicBool,
+    pub paused_drops: AtomicUsize,
+    // Set while fewer than `min_healthy_fraction` of the nodes are in the
+    // ring; datagrams are dropped and counted meanwhile.
+    pub below_min_healthy: AtomicBool,
+    pub min_healthy_drops: AtomicUsize,
+    pub dropped_backpressure: AtomicUsize,
+    pub dead_lettered: AtomicUsize,
+    // Lines dropped by `sanitize_keys: reject`.
+    pub rejected_keys: AtomicUsize,
+    // Lines dropped by `binary_key_policy: reject`.
+    pub binary_keys: AtomicUsize,
+    // Counters dropped by `counter_sample_factor` downsampling.
+    pub downsampled_counters: AtomicUsize,
+    // Metric names changed by `rewrite_rules`.
+    pub renamed_keys: AtomicUsize,
+    // Metrics `dirty_key_check` found routing elsewhere once trimmed.
+    pub dirty_key_packets: AtomicUsize,
+    // Metrics sent to every node for matching `broadcast_prefixes`.
+    pub broadcasts: AtomicUsize,
+    // Datagrams read, by the transport they came in on.
+    pub udp_packets: AtomicUsize,
+    pub unix_packets: AtomicUsize,
+    // Zero-length datagrams on either transport, not counted above.
+    pub empty_packets: AtomicUsize,
+    // UDP datagrams dropped because their source is outside `allow_sources`.
+    pub denied_source_packets: AtomicUsize,
+    // UDP sends that wrote less than the whole datagram, i.e. truncated lines.
+    pub short_forwards: AtomicUsize,
+    // Sends retried on another node after the owner's failed outright.
+    pub failover_forwards: AtomicUsize,
+    // Forwarded datagrams left out of the capture file, see `capture_file`.
+    pub capture_drops: AtomicUsize,
+    // Datagrams that no longer matched their checksum, see `forward_checksum`.
+    pub checksum_mismatches: AtomicUsize,
+    // Single lines sent whole although longer than `backend_mtu`.
+    pub oversize_forwards: AtomicUsize,
+    // Datagrams the kernel dropped on the listener sockets before we read them.
+    pub kernel_rx_drops: AtomicUsize,
+    pub key_lengths: [AtomicUsize; 4],
+    pub forward_latency: [AtomicUsize; 8],
+    // Worker event loops that are bound and running.
+    pub workers: AtomicUsize,
+    // Nodes currently in the ring, as last seen by any worker.
+    pub ring_nodes: AtomicUsize,
+    // Of those, nodes in the ring with reduced weight for reporting degraded.
+    pub degraded_nodes: AtomicUsize,
+    // Health checks sent and not yet answered, as last seen by any worker.
+    pub pending_health_checks: AtomicUsize,
+    // With `forward_seq` on, datagrams sent to each node so far, shared by
+    // every worker's connection to it.
+    pub forward_seqs: Mutex<BTreeMap<String, Arc<AtomicUsize>>>,
+    // Effective options of the listener sockets and one forwarding socket, as
+    // last read back by any worker.
+    pub sockets: Mutex<Vec<(&'static str, SockOpts)>>,
+    // Health check history per node, keyed like `forward_seqs`.
+    pub nodes: Mutex<BTreeMap<String, Arc<NodeStats>>>,
+    // Nodes in the ring as last published and the latest changes to that.
+    // Every worker sees the same change; only the first to publish it
+    // records it.
+    pub ring_members: Mutex<Vec<String>>,
+    pub ring_events: Mutex<VecDeque<RingEvent>>,
+    // With `track_top_keys`, routed keys seen so far and the sampled ones.
+    pub top_key_lines: AtomicUsize,
+    pub top_keys: Mutex<TopKeys>,
+    pub unrouted: Mutex<UnroutedKeys>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RingEvent {
+    // Unix time in milliseconds.
+    pub ts: u64,
+    // startup, reload or health.
+    pub reason: &'static str,
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+}
+
+impl RingEvent {
+    pub fn added(&self) -> Vec<&String> {
+        self.after.iter().filter(|n| !self.before.contains(n)).collect()
+    }
+
+    pub fn removed(&self) -> Vec<&String> {
+        self.before.iter().filter(|n| !self.after.contains(n)).collect()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TopKey {
+    pub count: usize,
+    // How much of `count` may belong to the keys this one replaced.
+    pub error: usize,
+    // Where the key was last routed.
+    pub node: String,
+}
+
+// Space-saving top-k: at most `size` keys are counted. An unseen key takes
+// the place of the least counted one and starts from its count, so counts
+// are over-estimates by at most `error`, and any key seen more often than
+// one in `size` times is kept.
+#[derive(Default)]
+pub struct TopKeys {
+    keys: HashMap<Vec<u8>, TopKey>,
+}
+
+impl TopKeys {
+    pub fn record(&mut self, key: &[u8], node: &str, size: usize) {
+        if let Some(top) = self.keys.get_mut(key) {
+            top.count += 1;
+            if top.node != node {
+                top.node = node.to_owned();
+            }
+            return;
+        }
+
+        let mut floor = 0;
+        if self.keys.len() >= size {
+            let least = self.keys.iter().min_by_key(|&(_, t)| t.count)
+                .map(|(k, t)| (k.clone(), t.count));
+            match least {
+                Some((k, count)) => {
+                    self.keys.remove(&k);
+                    floor = count;
+                }
+                None => return,
+            }
+        }
+        self.keys.insert(key.to_vec(), TopKey {
+            count: floor + 1,
+            error: floor,
+            node: node.to_owned(),
+        });
+    }
+
+    // The `n` most counted keys, most first.
+    pub fn top(&self, n: usize) -> Vec<(Vec<u8>, TopKey)> {
+        let mut keys: Vec<_> = self.keys.iter().map(|(k, t)| (k.clone(), t.clone())).collect();
+        keys.sort_by(|a, b| b.1.count.cmp(&a.1.count).then_with(|| a.0.cmp(&b.0)));
+        keys.truncate(n);
+        keys
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Unrouted {
+    // malformed, no_route, rejected or binary.
+    pub reason: &'static str,
+    pub count: usize,
+    // Unix time in milliseconds it was last seen.
+    pub last_seen: u64,
+    seq: u64,
+}
+
+// The most recently seen keys that were dropped or dead lettered instead of
+// routed, at most `UNROUTED_KEYS` of them cut to `UNROUTED_KEY_LEN` bytes:
+// memory stays bounded however many distinct keys go by. `order` maps each
+// key's last sighting to it, so the least recent one is evicted first.
+#[derive(Default)]
+pub struct UnroutedKeys {
+    keys: HashMap<Vec<u8>, Unrouted>,
+    order: BTreeMap<u64, Vec<u8>>,
+    seq: u64,
+}
+
+impl UnroutedKeys {
+    pub fn record(&mut self, key: &[u8], reason: &'static str) {
+        let key = &key[0..cmp::min(key.len(), UNROUTED_KEY_LEN)];
+        let last_seen = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() * 1000 + (d.subsec_nanos() / 1000000) as u64)
+            .unwrap_or(0);
+        self.seq += 1;
+        let seq = self.seq;
+
+        if let Some(seen) = self.keys.get_mut(key) {
+            let owned = self.order.remove(&seen.seq).unwrap_or_else(|| key.to_vec());
+            self.order.insert(seq, owned);
+            seen.reason = reason;
+            seen.count += 1;
+            seen.last_seen = last_seen;
+            seen.seq = seq;
+            return;
+        }
+
+        if self.keys.len() >= UNROUTED_KEYS {
+            let oldest = self.order.keys().next().cloned();
+            if let Some(k) = oldest.and_then(|s| self.order.remove(&s)) {
+                self.keys.remove(&k);
+            }
+        }
+        self.order.insert(seq, key.to_vec());
+        self.keys.insert(key.to_vec(), Unrouted {
+            reason: reason,
+            count: 1,
+            last_seen: last_seen,
+            seq: seq,
+        });
+    }
+
+    // Most recently seen first.
+    pub fn recent(&self) -> Vec<(Vec<u8>, Unrouted)> {
+        self.order.values().rev()
+            .filter_map(|k| self.keys.get(k).map(|u| (k.clone(), u.clone())))
+            .collect()
+    }
+}
+
+// Shared by every worker's connection to one node.
+#[derive(Default)]
+pub struct NodeStats {
+    // Admin connections reopened after a failed check, summed over workers.
+    pub reconnects: AtomicUsize,
+    // Unix time in seconds of the last passing check, 0 before the first.
+    pub last_success: AtomicUsize,
+    // Percent of its vnodes a node added by a reload has reached while it
+    // warms up; 100 once it's done, 0 for nodes that never warmed up.
+    pub warmup: AtomicUsize,
+    // Vnodes the node has in its ring now, 0 while it's out of it.
+    pub vnodes: AtomicUsize,
+    // Health score (see `HealthConfig::score_alpha`) in thousandths.
+    pub score: AtomicUsize,
+    // Datagrams sent to the node and sends that failed, over all workers.
+    pub forwarded: AtomicUsize,
+    pub forward_errors: AtomicUsize,
+    // With `forward_checksum`, the wrapping sum of the checksums of those
+    // datagrams, for a receiver to compare with what arrived.
+    pub sent_checksum: AtomicUsize,
+    // Datagrams in the node's retry queues now, summed over workers, and the
+    // most there were since /stats last read it.
+    pub queue_depth: AtomicUsize,
+    pub queue_depth_max: AtomicUsize,
+    // TCP forwarding connections reopened after a failed write.
+    pub forward_reconnects: AtomicUsize,
+    // Health checks answered, by `CHECK_LATENCY_BUCKETS`, and the sum of
+    // their latencies in microseconds.
+    pub check_latency: [AtomicUsize; 9],
+    pub check_latency_sum: AtomicUsize,
+}
+
+impl NodeStats {
+    // Grows a worker's share of `queue_depth` by `n`.
+    pub fn queue_grew(&self, n: usize) {
+        let depth = self.queue_depth.fetch_add(n, Ordering::Relaxed) + n;
+        let mut max = self.queue_depth_max.load(Ordering::Relaxed);
+        while depth > max {
+            match self.queue_depth_max.compare_exchange(max, depth, Ordering::Relaxed,
+                                                        Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(seen) => max = seen,
+            }
+        }
+    }
+
+    // The high-watermark since the last call, starting the next one from
+    // the depth now.
+    pub fn take_queue_depth_max(&self) -> usize {
+        let depth = self.queue_depth.load(Ordering::Relaxed);
+        cmp::max(self.queue_depth_max.swap(depth, Ordering::Relaxed), depth)
+    }
+
+    pub fn record_check_latency(&self, elapsed: Duration) {
+        let us = elapsed.as_secs() as usize * 1000000 + elapsed.subsec_nanos() as usize / 1000;
+        let bucket = CHECK_LATENCY_BUCKETS.iter().position(|max| us <= *max)
+            .unwrap_or(CHECK_LATENCY_BUCKETS.len());
+        self.check_latency[bucket].fetch_add(1, Ordering::Relaxed);
+        self.check_latency_sum.fetch_add(us, Ordering::Relaxed);
+    }
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats::default()
+    }
+
+    // Reserves room for one more queued packet, or returns false once
+    // `max` packets are outstanding across all retry queues.
+    pub fn reserve(&self, max: Option<usize>) -> bool {
+        if let Some(max) = max {
+            if self.outstanding.load(Ordering::Relaxed) >= max {
+                self.backpressure_active.store(true, Ordering::Relaxed);
+                self.dropped_backpressure.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+        }
+
+        self.outstanding.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    pub fn forward_seq(&self, node: &str) -> Arc<AtomicUsize> {
+        let mut seqs = self.forward_seqs.lock().unwrap();
+        seqs.entry(node.to_owned()).or_insert_with(|| Arc::new(AtomicUsize::new(0))).clone()
+    }
+
+    // Records `members` (sorted) as the ring, returning the change when it
+    // differs from the last recorded one.
+    pub fn record_ring(&self, reason: &'static str, members: Vec<String>) -> Option<RingEvent> {
+        let mut current = self.ring_members.lock().unwrap();
+        if *current == members {
+            return None;
+        }
+
+        let ts = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() * 1000 + (d.subsec_nanos() / 1000000) as u64)
+            .unwrap_or(0);
+        let event = RingEvent {
+            ts: ts,
+            reason: reason,
+            before: mem::replace(&mut *current, members.clone()),
+            after: members,
+        };
+        let mut events = self.ring_events.lock().unwrap();
+        if events.len() >= RING_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(event.clone());
+        Some(event)
+    }
+
+    pub fn node(&self, node: &str) -> Arc<NodeStats> {
+        let mut nodes = self.nodes.lock().unwrap();
+        nodes.entry(node.to_owned()).or_insert_with(|| Arc::new(NodeStats::default())).clone()
+    }
+
+    pub fn record_key_length(&self, len: usize) {
+        let bucket = KEY_LENGTH_BUCKETS.iter().position(|max| len <= *max)
+            .unwrap_or(KEY_LENGTH_BUCKETS.len());
+        self.key_lengths[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_latency(&self, elapsed: Duration) {
+        let us = elapsed.as_secs() as usize * 1000000 + elapsed.subsec_nanos() as usize / 1000;
+        let bucket = LATENCY_BUCKETS.iter().position(|max| us <= *max)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.forward_latency[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Upper bound of the bucket holding the `p`th percentile, or None when
+    // nothing was measured yet or it falls in the overflow bucket.
+    pub fn latency_percentile(&self, p: f64) -> Option<usize> {
+        let counts: Vec<usize> = self.forward_latency.iter()
+            .map(|c| c.load(Ordering::Relaxed))
+            .collect();
+        let total: usize = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+
+        let rank = cmp::max(1, (total as f64 * p / 100.0).ceil() as usize);
+        let mut seen = 0;
+        for (i, n) in counts.iter().enumerate() {
+            seen += *n;
+            if seen >= rank {
+                return LATENCY_BUCKETS.get(i).cloned();
+            }
+        }
+        None
+    }
+
+    pub fn release(&self, n: usize, max: Option<usize>) {
+        if n == 0 {
+            return;
+        }
+
+        let left = self.outstanding.fetch_sub(n, Ordering::Relaxed) - n;
+        if let Some(max) = max {
+            if left < max {
+                self.backpressure_active.store(false, Ordering::Relaxed);
+            }
+        }
+    }
+}
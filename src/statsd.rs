@@ -0,0 +1,179 @@
+#![allow(dead_code)]
+
+use std::str;
+
+// A single statsd line: `name:value|type[|@rate][|#tags]`.
+pub struct Metric<'a> {
+    pub name: &'a [u8],
+    pub value: &'a [u8],
+    pub kind: &'a [u8],
+    pub sample_rate: Option<&'a [u8]>,
+    pub tags: Option<&'a [u8]>,
+}
+
+impl<'a> Metric<'a> {
+    // `name:+3|g` and `name:-3|g` adjust the current value instead of setting
+    // it (an absolute negative gauge needs a `0` first).
+    pub fn is_relative_gauge(&self) -> bool {
+        self.kind == b"g" && (self.value[0] == b'+' || self.value[0] == b'-')
+    }
+
+    // Whether the line can be sent to more than one backend. Applying the
+    // same gauge delta on two backends that each hold the gauge double counts
+    // it, so relative gauges must only go to the primary owner. Absolute
+    // gauges, counters, timers and sets are all fine to copy.
+    pub fn replicable(&self) -> bool {
+        !self.is_relative_gauge()
+    }
+
+    // The `|@` sample rate, 1 without one; None unless it's in (0, 1].
+    pub fn rate(&self) -> Option<f64> {
+        let rate = match self.sample_rate {
+            Some(r) => r,
+            None => return Some(1.0),
+        };
+        match str::from_utf8(rate).ok().and_then(|r| r.parse::<f64>().ok()) {
+            Some(r) if r > 0.0 && r <= 1.0 => Some(r),
+            _ => None,
+        }
+    }
+}
+
+// Printable ASCII other than `/`. Spaces, control characters and anything
+// that isn't ASCII (including invalid UTF-8) trip up some backends.
+pub fn name_byte_allowed(b: u8) -> bool {
+    b > b' ' && b < 0x7f && b != b'/'
+}
+
+// `name` without leading or trailing spaces, tabs and CRs.
+pub fn trim(name: &[u8]) -> &[u8] {
+    let blank = |b: &u8| *b == b' ' || *b == b'\t' || *b == b'\r';
+    let start = name.iter().position(|b| !blank(b)).unwrap_or(name.len());
+    let end = name.iter().rposition(|b| !blank(b)).map_or(start, |i| i + 1);
+    &name[start..end]
+}
+
+pub fn parse(line: &[u8]) -> Option<Metric> {
+    parse_with(line, b':')
+}
+
+// For dialects that put something other than `:` between name and value.
+pub fn parse_with(line: &[u8], delimiter: u8) -> Option<Metric> {
+    let colon = match line.iter().position(|x| *x == delimiter) {
+        Some(0) | None => return None,
+        Some(i) => i,
+    };
+
+    let mut fields = line[colon + 1..].split(|x| *x == b'|');
+    let value = match fields.next() {
+        Some(v) if !v.is_empty() => v,
+        _ => return None,
+    };
+    let kind = match fields.next() {
+        Some(k) if !k.is_empty() => k,
+        _ => return None,
+    };
+
+    let mut metric = Metric {
+        name: &line[0..colon],
+        value: value,
+        kind: kind,
+        sample_rate: None,
+        tags: None,
+    };
+
+    for field in fields {
+        match field.first() {
+            Some(&b'@') => metric.sample_rate = Some(&field[1..]),
+            Some(&b'#') => metric.tags = Some(&field[1..]),
+            _ => return None,
+        }
+    }
+    Some(metric)
+}
+
+// Writes `line` to `out` with `tags` (comma separated) added to its `|#`
+// section, creating the section when there is none. Anything without a `|`
+// isn't a metric and is copied unchanged.
+pub fn append_tags(line: &[u8], tags: &[u8], out: &mut Vec<u8>) {
+    let first = match line.iter().position(|x| *x == b'|') {
+        Some(i) => i,
+        None => {
+            out.extend_from_slice(line);
+            return;
+        }
+    };
+
+    match line[first..].windows(2).position(|w| w == b"|#") {
+        Some(i) => {
+            let start = first + i + 2;
+            let end = line[start..].iter().position(|x| *x == b'|')
+                .map_or(line.len(), |j| start + j);
+            out.extend_from_slice(&line[0..end]);
+            if end > start {
+                out.push(b',');
+            }
+            out.extend_from_slice(tags);
+            out.extend_from_slice(&line[end..]);
+        }
+        None => {
+            out.extend_from_slice(line);
+            out.extend_from_slice(b"|#");
+            out.extend_from_slice(tags);
+        }
+    }
+}
+
+// Writes `line` to `out` with its `|@` sample rate set to `rate`, added
+// right after the type when there is none. As with `append_tags`, anything
+// without a `|` is copied unchanged.
+pub fn set_sample_rate(line: &[u8], rate: f64, out: &mut Vec<u8>) {
+    let first = match line.iter().position(|x| *x == b'|') {
+        Some(i) => i,
+        None => {
+            out.extend_from_slice(line);
+            return;
+        }
+    };
+
+    let mut fields = line[first + 1..].split(|x| *x == b'|');
+    out.extend_from_slice(&line[0..first + 1]);
+    if let Some(kind) = fields.next() {
+        out.extend_from_slice(kind);
+    }
+    out.extend_from_slice(format!("|@{}", rate).as_bytes());
+    for field in fields {
+        if field.first() != Some(&b'@') {
+            out.push(b'|');
+            out.extend_from_slice(field);
+        }
+    }
+}
+
+// Iterates the non-empty lines of a datagram.
+pub struct Lines<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        while !self.rest.is_empty() {
+            let (line, rest) = match self.rest.iter().position(|x| *x == b'\n') {
+                Some(i) => (&self.rest[0..i], &self.rest[i + 1..]),
+                None => (self.rest, &self.rest[self.rest.len()..]),
+            };
+            self.rest = rest;
+
+            if !line.is_empty() {
+                return Some(line);
+            }
+        }
+        None
+    }
+}
+
+pub fn lines(packet: &[u8]) -> Lines {
+    Lines { rest: packet }
+}
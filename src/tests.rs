@@ -0,0 +1,1937 @@
+use std::env;
+use std::fs::{self, File};
+use std::mem;
+use std::ptr;
+use std::io::{self, Read, Write};
+use std::net::{self, SocketAddr, TcpListener, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use libc;
+use md5;
+use mio;
+use regex::bytes::Regex;
+
+use {build_nodes, Manager};
+use config::{self, Config, ConfigError};
+use event_loop::{checksum, AllowList, ForwardConfig, HealthType, OverflowPolicy, Proxy,
+                 HealthConfig, RewriteRule, RouteConfig, RouteMode, Sanitize, BinaryKeys};
+use forwarder::Transport;
+use admin;
+use capture::{self, Capture, CaptureConfig, Record};
+use self_metrics::SelfMetrics;
+use hash::{self, ConsistentHash, Node, ServerNode, VnodeKey};
+use logging::{self, Level, LogFormat};
+use socket::{self, AddressFamily, InetAddr, MtuDiscover, SockAddr, SockType, Socket, UdpListener,
+             UdpStream};
+use stats::{NodeStats, RingEvent, Stats, TopKey, TopKeys, UnroutedKeys, RING_EVENTS,
+            UNROUTED_KEYS, UNROUTED_KEY_LEN};
+use statsd;
+
+struct Backend {
+    udp: UdpSocket,
+    healthy: Arc<AtomicBool>,
+    node: ServerNode,
+}
+
+impl Backend {
+    fn new(id: usize) -> Backend {
+        Backend::with_health(id, HealthType::StatsdAdmin)
+    }
+
+    fn with_health(id: usize, health_type: HealthType) -> Backend {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        udp.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+
+        let admin = TcpListener::bind("127.0.0.1:0").unwrap();
+        let healthy = Arc::new(AtomicBool::new(true));
+
+        let node = ServerNode::new(id, "127.0.0.1", udp.local_addr().unwrap().port(),
+                                   admin.local_addr().unwrap().port(), 20, health_type, None)
+            .unwrap();
+
+        let flag = healthy.clone();
+        thread::spawn(move || {
+            for stream in admin.incoming() {
+                let mut stream = match stream {
+                    Ok(s) => s,
+                    Err(_) => return,
+                };
+                let flag = flag.clone();
+                thread::spawn(move || {
+                    let mut buf = [0; 128];
+                    loop {
+                        match stream.read(&mut buf) {
+                            Ok(0) | Err(_) => return,
+                            Ok(_) => {}
+                        }
+                        let up = flag.load(Ordering::SeqCst);
+                        let reply: &[u8] = match (health_type, up) {
+                            (HealthType::StatsdAdmin, true) => b"health: up\n",
+                            (HealthType::StatsdAdmin, false) => b"health: down\n",
+                            (HealthType::Http, true) => b"HTTP/1.0 200 OK\r\n\r\n",
+                            (HealthType::Http, false) =>
+                                b"HTTP/1.0 503 Service Unavailable\r\n\r\n",
+                        };
+                        if stream.write_all(reply).is_err() {
+                            return;
+                        }
+                        if health_type == HealthType::Http {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        Backend { udp: udp, healthy: healthy, node: node }
+    }
+
+    fn recv(&self) -> Option<Vec<u8>> {
+        let mut buf = [0; 4096];
+        match self.udp.recv_from(&mut buf) {
+            Ok((n, _)) => Some(buf[0..n].to_vec()),
+            Err(_) => None,
+        }
+    }
+}
+
+struct Harness {
+    event_loop: mio::EventLoop<Proxy>,
+    proxy: Proxy,
+    client: UdpSocket,
+    addr: SocketAddr,
+    backends: Vec<Backend>,
+    stats: Arc<Stats>,
+}
+
+impl Harness {
+    fn new(n: usize, health: HealthConfig) -> Harness {
+        Harness::with_backends((0..n).map(Backend::new).collect(), health)
+    }
+
+    fn with_backends(backends: Vec<Backend>, health: HealthConfig) -> Harness {
+        Harness::with_route(backends, health, route())
+    }
+
+    fn with_route(backends: Vec<Backend>, health: HealthConfig, route: RouteConfig) -> Harness {
+        Harness::with_config(backends, health, route, forward())
+    }
+
+    fn with_config(backends: Vec<Backend>, health: HealthConfig, route: RouteConfig,
+                   forward: ForwardConfig) -> Harness {
+        let server = UdpListener::bind(("127.0.0.1", 0)).unwrap();
+        let mut h = Harness::unstarted(server, backends, health, route, forward);
+        h.start();
+        h
+    }
+
+    // The proxy on `server`, not yet registered, for tests that set it up
+    // further before calling `start`.
+    fn unstarted(server: UdpListener, backends: Vec<Backend>, health: HealthConfig,
+                 route: RouteConfig, forward: ForwardConfig) -> Harness {
+        let nodes = backends.iter().map(|b| b.node.clone()).collect();
+        let addr = server.local_addr().unwrap();
+
+        let event_loop = mio::EventLoop::new().unwrap();
+        let stats = Arc::new(Stats::new());
+        let proxy = Proxy::new(server, nodes, health, route, forward, stats.clone());
+
+        Harness {
+            event_loop: event_loop,
+            proxy: proxy,
+            client: UdpSocket::bind("127.0.0.1:0").unwrap(),
+            addr: addr,
+            backends: backends,
+            stats: stats,
+        }
+    }
+
+    fn start(&mut self) {
+        self.proxy.start(&mut self.event_loop);
+    }
+
+    fn turn(&mut self) {
+        self.event_loop.run_once(&mut self.proxy, Some(5)).unwrap();
+    }
+
+    fn run_for(&mut self, turns: usize) {
+        for _ in 0..turns {
+            self.turn();
+        }
+    }
+
+    fn send(&self, packet: &[u8]) {
+        self.client.send_to(packet, self.addr).unwrap();
+    }
+
+    // Returns the index of the backend that received `packet`.
+    fn forward(&mut self, packet: &[u8]) -> Option<usize> {
+        self.send(packet);
+
+        for _ in 0..100 {
+            self.turn();
+            for (i, b) in self.backends.iter().enumerate() {
+                if let Some(data) = b.recv() {
+                    assert_eq!(&data[..], packet);
+                    return Some(i);
+                }
+            }
+        }
+        None
+    }
+
+    fn recv_all(&mut self) -> Vec<Option<Vec<u8>>> {
+        let mut got = vec![None; self.backends.len()];
+        for _ in 0..50 {
+            self.turn();
+            for (i, b) in self.backends.iter().enumerate() {
+                if let Some(data) = b.recv() {
+                    got[i] = Some(data);
+                }
+            }
+        }
+        got
+    }
+
+    fn key_for(&self, backend: usize) -> String {
+        (0..).map(|i| format!("metric.{}", i))
+            .find(|k| self.expected(k.as_bytes()) == backend)
+            .unwrap()
+    }
+
+    fn expected(&self, key: &[u8]) -> usize {
+        let mut ring = ConsistentHash::with_vnode_key(VnodeKey::default());
+        for b in self.backends.iter() {
+            ring.add(&b.node, b.node.replicas);
+        }
+        let node = ring.get(key).unwrap();
+        self.backends.iter().position(|b| b.node == *node).unwrap()
+    }
+}
+
+fn route() -> RouteConfig {
+    RouteConfig {
+        mode: RouteMode::Name,
+        key_segments: None,
+        key_delimiter: b'.',
+        prefix: Vec::new(),
+        broadcast_prefixes: Vec::new(),
+        pools: vec!["default".to_owned()],
+        type_pools: Vec::new(),
+        value_delimiter: b':',
+        sanitize: Sanitize::Off,
+        sanitize_replacement: b'_',
+        binary_keys: BinaryKeys::Forward,
+        dirty_key_check: false,
+        dirty_key_sample: 0,
+        counter_sample_factor: 1,
+        counter_sample_prefixes: Vec::new(),
+        track_top_keys: false,
+        top_keys_size: 100,
+        top_keys_sample: 1,
+        hash_salt: Vec::new(),
+        vnode_key: VnodeKey::default(),
+        rewrite_rules: Vec::new(),
+        rewrite_first_match: true,
+        trace_timestamps: false,
+    }
+}
+
+fn forward() -> ForwardConfig {
+    ForwardConfig {
+        retry_queue_size: 16,
+        overflow_policy: OverflowPolicy::DropNewest,
+        max_outstanding_packets: None,
+        drain_timeout: Duration::from_millis(100),
+        transparent: false,
+        dead_letter_node: None,
+        source_addr: None,
+        latency_sample: 1,
+        allow_sources: AllowList::default(),
+        forward_seq: false,
+        backend_mtu: 1432,
+        forward_retries: 0,
+        mtu_discover: None,
+        pause_buffer: 0,
+        checksum: false,
+    }
+}
+
+fn health() -> HealthConfig {
+    HealthConfig {
+        enabled: true,
+        probe_interval: 10,
+        probe_jitter: 0,
+        eval_interval: 10,
+        failure_threshold: 1,
+        failure_window: Duration::from_secs(60),
+        success_threshold: 1,
+        degraded_response: None,
+        degraded_weight: 50,
+        max_concurrent_checks: None,
+        warmup: Duration::from_millis(0),
+        failure_weights: Vec::new(),
+        score_alpha: 0.3,
+        score_eviction: None,
+        min_healthy_fraction: None,
+    }
+}
+
+#[test]
+fn forwards_to_hashed_node() {
+    let mut h = Harness::new(2, health());
+
+    for i in 0..20 {
+        let key = format!("metric.{}", i);
+        let packet = format!("{}:1|c", key);
+        let expected = h.expected(key.as_bytes());
+        assert_eq!(h.forward(packet.as_bytes()), Some(expected));
+    }
+}
+
+#[test]
+fn unhealthy_node_is_evicted() {
+    let mut h = Harness::new(2, health());
+
+    // Find a key owned by the first backend, then take that backend down.
+    let key = h.key_for(0);
+    let packet = format!("{}:1|c", key);
+    assert_eq!(h.forward(packet.as_bytes()), Some(0));
+
+    h.backends[0].healthy.store(false, Ordering::SeqCst);
+    h.run_for(50);
+    assert_eq!(h.forward(packet.as_bytes()), Some(1));
+
+    h.backends[0].healthy.store(true, Ordering::SeqCst);
+    h.run_for(50);
+    assert_eq!(h.forward(packet.as_bytes()), Some(0));
+}
+
+#[test]
+fn splits_multi_metric_packets_by_name() {
+    let mut h = Harness::new(2, health());
+
+    let a = format!("{}:1|ms", h.key_for(0));
+    let b = format!("{}:2|ms", h.key_for(1));
+    let a2 = format!("{}:3|ms", h.key_for(0));
+    h.send(format!("{}\n{}\n{}\n", a, b, a2).as_bytes());
+
+    let got = h.recv_all();
+    assert_eq!(got[0], Some(format!("{}\n{}", a, a2).into_bytes()));
+    assert_eq!(got[1], Some(b.into_bytes()));
+}
+
+#[test]
+fn http_health_checks_follow_status() {
+    let backends = (0..2).map(|id| Backend::with_health(id, HealthType::Http)).collect();
+    let mut h = Harness::with_backends(backends, health());
+
+    let key = h.key_for(0);
+    let packet = format!("{}:1|c", key);
+    h.run_for(20);
+    assert_eq!(h.forward(packet.as_bytes()), Some(0));
+
+    // 503 evicts the node, 200 brings it back.
+    h.backends[0].healthy.store(false, Ordering::SeqCst);
+    h.run_for(50);
+    assert_eq!(h.forward(packet.as_bytes()), Some(1));
+
+    h.backends[0].healthy.store(true, Ordering::SeqCst);
+    h.run_for(50);
+    assert_eq!(h.forward(packet.as_bytes()), Some(0));
+}
+
+fn nodes_yaml(backends: &[Backend], disabled: Option<usize>) -> String {
+    let mut s = "nodes:\n".to_owned();
+    for (i, b) in backends.iter().enumerate() {
+        s.push_str(&format!("  b{}: {{host: 127.0.0.1, port: {}, adminport: {}, enabled: {}}}\n",
+                            i, b.node.port, b.node.adminport, disabled != Some(i)));
+    }
+    s
+}
+
+#[test]
+fn reload_toggles_disabled_nodes() {
+    let mut h = Harness::new(2, health());
+    let key = h.key_for(0);
+    let packet = format!("{}:1|c", key);
+    assert_eq!(h.forward(packet.as_bytes()), Some(0));
+
+    let config = Config::from_str(&nodes_yaml(&h.backends, Some(0))).unwrap();
+    let nodes = build_nodes(&config.listeners[0]);
+    assert_eq!(nodes.len(), 1);
+    h.proxy.reload(&mut h.event_loop, nodes);
+    assert_eq!(h.forward(packet.as_bytes()), Some(1));
+
+    let config = Config::from_str(&nodes_yaml(&h.backends, None)).unwrap();
+    h.proxy.reload(&mut h.event_loop, build_nodes(&config.listeners[0]));
+    assert_eq!(h.forward(packet.as_bytes()), Some(0));
+}
+
+#[test]
+fn reload_moves_checks_to_a_new_adminport() {
+    let mut h = Harness::new(1, health());
+    h.run_for(20);
+
+    let admin = TcpListener::bind("127.0.0.1:0").unwrap();
+    admin.set_nonblocking(true).unwrap();
+    let mut node = h.backends[0].node.clone();
+    node.adminport = admin.local_addr().unwrap().port();
+    h.proxy.reload(&mut h.event_loop, vec![node]);
+
+    let start = Instant::now();
+    while admin.accept().is_err() {
+        assert!(start.elapsed() < Duration::from_secs(2), "still checking the old adminport");
+        h.turn();
+    }
+}
+
+fn open_fds() -> usize {
+    fs::read_dir("/proc/self/fd").unwrap().count()
+}
+
+#[test]
+fn reconnects_do_not_leak_fds() {
+    // Every probe of a closed adminport drops the admin connection and the
+    // next one opens a new one.
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut backend = Backend::new(0);
+    backend.node.adminport = closed;
+    let mut h = Harness::with_backends(vec![backend], health());
+    h.run_for(10);
+
+    let before = open_fds();
+    let node = h.stats.node(&h.backends[0].node.name());
+    let start = node.reconnects.load(Ordering::Relaxed);
+    for _ in 0..2000 {
+        if node.reconnects.load(Ordering::Relaxed) >= start + 200 {
+            break;
+        }
+        h.turn();
+    }
+    assert!(node.reconnects.load(Ordering::Relaxed) >= start + 200);
+
+    // Other tests run concurrently, so allow some slack.
+    assert!(open_fds() < before + 50);
+}
+
+#[test]
+fn dropping_a_clone_keeps_the_socket_open() {
+    let backend = UdpSocket::bind("127.0.0.1:0").unwrap();
+    backend.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+    let stream = UdpStream::new(backend.local_addr().unwrap()).unwrap();
+    drop(stream.clone());
+
+    stream.write(b"foo:1|c").unwrap();
+    let mut buf = [0; 16];
+    let (n, _) = backend.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[0..n], b"foo:1|c");
+}
+
+fn ring_from(yaml: &str) -> (Vec<String>, Vec<String>) {
+    let config = Config::from_str(yaml).unwrap();
+    let mut ring = ConsistentHash::with_vnode_key(VnodeKey::default());
+    let mut names = Vec::new();
+    for (id, spec) in config.listeners[0].nodes.iter().enumerate() {
+        let node = ServerNode::new(id, &spec.host, spec.port, spec.adminport, spec.replicas,
+                                   spec.health_type, spec.source_addr).unwrap();
+        names.push(node.name());
+        ring.add(&node, node.replicas);
+    }
+
+    let owners = (0..200).map(|i| ring.get(format!("metric.{}", i).as_bytes()).unwrap().name())
+        .collect();
+    (names, owners)
+}
+
+#[test]
+fn ring_is_independent_of_declaration_order() {
+    let a = ring_from("nodes:\n  \
+                         a: {host: 127.0.0.1, port: 9001, adminport: 9101}\n  \
+                         b: {host: 127.0.0.1, port: 9002, adminport: 9102}\n  \
+                         c: {host: 127.0.0.1, port: 9003, adminport: 9103}\n");
+    let b = ring_from("nodes:\n  \
+                         c: {host: 127.0.0.1, port: 9003, adminport: 9103}\n  \
+                         a: {host: 127.0.0.1, port: 9001, adminport: 9101}\n  \
+                         b: {host: 127.0.0.1, port: 9002, adminport: 9102}\n");
+    assert_eq!(a, b);
+
+    // By name, so port 10 sorts before 9, as the ring sorts them.
+    let yaml = "nodes:\n  \
+                a: {host: 127.0.0.1, port: 9, adminport: 9109}\n  \
+                b: {host: 127.0.0.1, port: 10, adminport: 9110}\n";
+    let (names, _) = ring_from(yaml);
+    assert_eq!(names, vec!["127.0.0.1:10".to_owned(), "127.0.0.1:9".to_owned()]);
+    let config = Config::from_str(yaml).unwrap();
+    let mut nodes = build_nodes(&config.listeners[0]);
+    hash::build_rings(&mut nodes, 1, &VnodeKey::default());
+    let ids: Vec<usize> = nodes.iter().map(|n| n.id).collect();
+    assert_eq!(ids, vec![0, 1]);
+}
+
+extern "C" fn ignore_signal(_: libc::c_int) {}
+
+#[test]
+fn interrupted_recv_is_retried() {
+    // No SA_RESTART, so the blocking recvfrom below sees EINTR.
+    unsafe {
+        let mut sa: libc::sigaction = mem::zeroed();
+        sa.sa_sigaction = ignore_signal as libc::sighandler_t;
+        libc::sigemptyset(&mut sa.sa_mask);
+        libc::sigaction(libc::SIGUSR1, &sa, ptr::null_mut());
+    }
+
+    let sock = Socket::new(AddressFamily::Inet, SockType::Datagram, false).unwrap();
+    sock.bind(&SockAddr::Inet(InetAddr::from_std(&"127.0.0.1:0".parse().unwrap()))).unwrap();
+    let addr = sock.local_addr().unwrap();
+
+    let target = unsafe { libc::pthread_self() };
+    let sender = thread::spawn(move || {
+        for _ in 0..3 {
+            thread::sleep(Duration::from_millis(20));
+            unsafe { libc::pthread_kill(target, libc::SIGUSR1) };
+        }
+        thread::sleep(Duration::from_millis(20));
+        UdpSocket::bind("127.0.0.1:0").unwrap().send_to(b"foo:1|c", addr).unwrap();
+    });
+
+    let mut buf = [0; 16];
+    let (n, _) = sock.recvfrom(&mut buf).unwrap().unwrap();
+    assert_eq!(&buf[0..n], b"foo:1|c");
+    sender.join().unwrap();
+}
+
+#[test]
+fn relative_gauges_are_not_replicable() {
+    for line in [&b"g:+3|g"[..], b"g:-3|g", b"g:-3|g|#env:prod"].iter() {
+        let m = statsd::parse(line).unwrap();
+        assert!(m.is_relative_gauge());
+        assert!(!m.replicable());
+    }
+
+    for line in [&b"g:3|g"[..], b"c:-1|c", b"t:12|ms|@0.5", b"s:-1|s"].iter() {
+        let m = statsd::parse(line).unwrap();
+        assert!(!m.is_relative_gauge());
+        assert!(m.replicable());
+    }
+}
+
+#[test]
+fn unknown_node_keys_are_ignored() {
+    let config = Config::from_str("nodes:\n  \
+                                     a:\n    \
+                                       host: 127.0.0.1\n    \
+                                       port: 8127\n    \
+                                       adminport: 8128\n    \
+                                       description: primary aggregator\n    \
+                                       team: metrics\n    \
+                                       tags: [a, b]\n    \
+                                       owner: {name: ops, pager: 123}\n").unwrap();
+    let node = &config.listeners[0].nodes[0];
+    assert_eq!(node.port, 8127);
+    assert_eq!(node.description, Some("primary aggregator".to_owned()));
+}
+
+#[test]
+fn proxy_takes_more_nodes_than_the_default_slab() {
+    let nodes: Vec<ServerNode> = (0..1500).map(|i| {
+        ServerNode::new(i, "127.0.0.1", 20000 + i as u16, 0, 1, HealthType::StatsdAdmin, None)
+            .unwrap()
+    }).collect();
+    let mut health = health();
+    health.enabled = false;
+    let stats = Arc::new(Stats::new());
+
+    let server = UdpListener::bind(("127.0.0.1", 0)).unwrap();
+    let mut event_loop = mio::EventLoop::new().unwrap();
+    let mut proxy = Proxy::new(server, nodes.clone(), health, route(), forward(), stats.clone());
+    proxy.start(&mut event_loop);
+    assert_eq!(stats.ring_nodes.load(Ordering::Relaxed), 1500);
+
+    let mut more = nodes;
+    for i in 1500..3500 {
+        more.push(ServerNode::new(i, "127.0.0.1", 20000 + i as u16, 0, 1,
+                                  HealthType::StatsdAdmin, None).unwrap());
+    }
+    proxy.reload(&mut event_loop, more);
+    assert_eq!(stats.ring_nodes.load(Ordering::Relaxed), 3500);
+}
+
+#[test]
+fn readding_a_node_restores_the_ring() {
+    // Weighted nodes, as the config stores them: weight times the base.
+    let mut nodes: Vec<ServerNode> = (0..50).map(|i| {
+        ServerNode::new(i, "127.0.0.1", 30000 + i as u16, 0, 20 * (1 + i % 3),
+                        HealthType::StatsdAdmin, None).unwrap()
+    }).collect();
+    let mut ring = hash::build_rings(&mut nodes, 1, &VnodeKey::default()).remove(0);
+    let keys: Vec<String> = (0..5000).map(|i| format!("metric.{}", i)).collect();
+    let owners = |ring: &ConsistentHash<ServerNode>| -> Vec<String> {
+        keys.iter().map(|k| ring.get(k.as_bytes()).unwrap().name()).collect()
+    };
+    let before = owners(&ring);
+
+    for node in nodes.iter().take(10) {
+        ring.add(node, node.replicas);
+        assert_eq!(owners(&ring), before);
+        ring.remove(node);
+        assert!(owners(&ring) != before);
+        ring.add(node, node.replicas);
+        assert_eq!(owners(&ring), before);
+    }
+}
+
+#[test]
+fn node_diff_only_moves_keys_of_changed_nodes() {
+    let mut nodes: Vec<ServerNode> = (0..1000).map(|i| {
+        ServerNode::new(i, "127.0.0.1", 30000 + i as u16, 0, 20, HealthType::StatsdAdmin, None)
+            .unwrap()
+    }).collect();
+    let mut ring = hash::build_rings(&mut nodes, 1, &VnodeKey::default()).remove(0);
+    let keys: Vec<String> = (0..5000).map(|i| format!("metric.{}", i)).collect();
+    let before: Vec<String> = keys.iter().map(|k| ring.get(k.as_bytes()).unwrap().name()).collect();
+
+    let gone = nodes[500].clone();
+    assert_eq!(hash::apply_node_diff(&mut ring, &[], &[gone.clone()]), 20);
+    for (key, owner) in keys.iter().zip(before.iter()) {
+        let now = ring.get(key.as_bytes()).unwrap().name();
+        if *owner == gone.name() {
+            assert!(now != gone.name());
+        } else {
+            assert_eq!(now, *owner);
+        }
+    }
+
+    assert_eq!(hash::apply_node_diff(&mut ring, &[gone.clone()], &[]), 20);
+    let after: Vec<String> = keys.iter().map(|k| ring.get(k.as_bytes()).unwrap().name()).collect();
+    assert_eq!(after, before);
+
+    // A replica change takes out the node's old vnodes and places the new.
+    let mut heavier = gone.clone();
+    heavier.replicas = 30;
+    assert_eq!(hash::apply_node_diff(&mut ring, &[heavier], &[gone]), 50);
+}
+
+#[test]
+fn config_defaults_and_validation() {
+    let config = Config::from_str("nodes:\n  a: {host: 127.0.0.1, port: 8127, adminport: 8128}\n")
+        .unwrap();
+    assert_eq!(config.threads, config::available_cpus());
+    assert!(config.threads >= 1);
+    assert!(config.cpu_affinity.is_empty());
+    assert_eq!(config.stats_port, None);
+    assert!(!config.admin_shutdown);
+    assert_eq!(config.listeners.len(), 1);
+
+    let l = &config.listeners[0];
+    assert_eq!(l.name, "default");
+    assert_eq!(l.bind, 8977);
+    assert_eq!(l.replicas, 20);
+    assert!(l.health.enabled);
+    assert_eq!(l.health.probe_interval, 1000);
+    assert_eq!(l.health.failure_threshold, 3);
+    assert_eq!(l.health.success_threshold, 3);
+    assert!(l.route.mode == RouteMode::Name);
+    assert_eq!(l.forward.retry_queue_size, 1024);
+    assert_eq!(l.forward.drain_timeout, Duration::from_millis(5000));
+    assert_eq!(l.nodes[0].replicas, 20);
+
+    for yaml in ["bind: 8125\n",
+                 "route_mode: random\nnodes:\n  a: {host: 127.0.0.1, port: 1, adminport: 2}\n",
+                 "nodes:\n  a: {host: 127.0.0.1, port: 70000, adminport: 2}\n",
+                 "nodes:\n  a: {host: 127.0.0.1, port: 1, health_type: http}\n",
+                 "admin_shutdown: true\nnodes:\n  a: {host: 127.0.0.1, port: 1, adminport: 2}\n",
+                 "admin_pause: true\nnodes:\n  a: {host: 127.0.0.1, port: 1, adminport: 2}\n",
+                 "threads: 0\nnodes:\n  a: {host: 127.0.0.1, port: 1, adminport: 2}\n",
+                 "threads: -2\nnodes:\n  a: {host: 127.0.0.1, port: 1, adminport: 2}\n",
+                 "threads: 2\ncpu_affinity: [0]\n\
+                  nodes:\n  a: {host: 127.0.0.1, port: 1, adminport: 2}\n",
+                 "threads: 1\ncpu_affinity: [100000]\n\
+                  nodes:\n  a: {host: 127.0.0.1, port: 1, adminport: 2}\n"]
+                .iter() {
+        assert!(Config::from_str(yaml).is_err(), "accepted {:?}", yaml);
+    }
+    // Values of the wrong type fail to deserialize rather than fall back to
+    // the default.
+    match Config::from_str("bind: x\nnodes:\n  a: {host: 127.0.0.1, port: 1, adminport: 2}\n") {
+        Err(ConfigError::Yaml(_)) => {}
+        other => panic!("bad bind accepted: {:?}", other.map(|c| c.threads)),
+    }
+}
+
+#[test]
+fn type_pools_route_to_their_own_ring() {
+    let config = Config::from_str("nodes:\n  \
+                                     a: {host: 127.0.0.1, port: 9001, adminport: 9101}\n\
+                                   pools:\n  \
+                                     timers:\n    \
+                                       nodes:\n      \
+                                         t: {host: 127.0.0.1, port: 9002, adminport: 9102}\n\
+                                   type_pools:\n  \
+                                     ms: timers\n").unwrap();
+    let route = &config.listeners[0].route;
+    assert_eq!(route.pools, vec!["default".to_owned(), "timers".to_owned()]);
+    assert_eq!(route.pool(b"ms"), 1);
+    assert_eq!(route.pool(b"c"), 0);
+
+    let mut nodes = build_nodes(&config.listeners[0]);
+    let rings = hash::build_rings(&mut nodes, route.pools.len(), &route.vnode_key);
+    for i in 0..50 {
+        let name = format!("metric.{}", i);
+        assert_eq!(rings[route.pool(b"ms")].get(name.as_bytes()).unwrap().port, 9002);
+        assert_eq!(rings[route.pool(b"c")].get(name.as_bytes()).unwrap().port, 9001);
+    }
+
+    assert!(Config::from_str("nodes:\n  a: {host: 127.0.0.1, port: 1, adminport: 2}\n\
+                              type_pools:\n  ms: timers\n").is_err());
+}
+
+#[test]
+fn bind_to_a_taken_port_is_reported() {
+    // std doesn't set SO_REUSEPORT, so the proxy can't share the port.
+    let taken = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let port = taken.local_addr().unwrap().port();
+
+    match UdpListener::bind(("127.0.0.1", port)) {
+        Err(e) => assert_eq!(e.kind(), io::ErrorKind::AddrInUse),
+        Ok(_) => panic!("bound a port in use"),
+    }
+
+    let config = Config::from_str(&format!("bind: {}\nhealth_checks: false\nnodes:\n  \
+                                            a: {{host: 127.0.0.1, port: 9001}}\n", port))
+        .unwrap();
+    let listener = config.listeners[0].clone();
+    let nodes = build_nodes(&listener);
+    let mut m = Manager::new("127.0.0.1", listener, nodes, Arc::new(Stats::new()));
+    assert!(m.run(None).is_err());
+    assert!(m.threads.is_empty());
+}
+
+#[test]
+fn bind_zero_shares_one_chosen_port() {
+    let config = Config::from_str("bind: 0\nthreads: 2\nhealth_checks: false\nnodes:\n  \
+                                   a: {host: 127.0.0.1, port: 9001}\n").unwrap();
+    let listener = config.listeners[0].clone();
+    let nodes = build_nodes(&listener);
+    let mut m = Manager::new("127.0.0.1", listener, nodes, Arc::new(Stats::new()));
+    m.run(None).unwrap();
+    let port = m.config.bind;
+    assert!(port != 0);
+    m.run(None).unwrap();
+    assert_eq!(m.config.bind, port);
+    assert_eq!(m.threads.len(), 2);
+
+    // Both workers hold it; a socket without SO_REUSEPORT can't join.
+    assert!(UdpSocket::bind(("127.0.0.1", port)).is_err());
+    m.shutdown();
+    m.join();
+}
+
+#[test]
+fn single_worker_binds_without_reuse_port() {
+    let config = Config::from_str("bind: 0\nthreads: 1\nreuse_port: false\nhealth_checks: false\n\
+                                   nodes:\n  a: {host: 127.0.0.1, port: 9001}\n").unwrap();
+    let listener = config.listeners[0].clone();
+    assert!(!listener.listen.reuse_port);
+    let nodes = build_nodes(&listener);
+    let mut m = Manager::new("127.0.0.1", listener, nodes, Arc::new(Stats::new()));
+    m.run(None).unwrap();
+    let port = m.config.bind;
+
+    // Not even a socket that does set SO_REUSEPORT can share it now.
+    assert!(UdpListener::bind(("127.0.0.1", port)).is_err());
+    m.shutdown();
+    m.join();
+
+    assert!(Config::from_str("threads: 2\nreuse_port: false\nhealth_checks: false\nnodes:\n  \
+                              a: {host: 127.0.0.1, port: 9001}\n").is_err());
+}
+
+#[test]
+fn ring_churn_while_forwarding() {
+    let mut h = Harness::new(3, health());
+    let keys: Vec<String> = (0..3).map(|i| h.key_for(i)).collect();
+    let packet = keys.iter().map(|k| format!("{}:1|c", k)).collect::<Vec<_>>().join("\n");
+
+    for round in 0..8 {
+        let disabled = round % 3;
+        let down = if round % 2 == 1 { Some((round + 1) % 3) } else { None };
+        for (i, b) in h.backends.iter().enumerate() {
+            b.healthy.store(down != Some(i), Ordering::SeqCst);
+        }
+        let config = Config::from_str(&nodes_yaml(&h.backends, Some(disabled))).unwrap();
+        h.proxy.reload(&mut h.event_loop, build_nodes(&config.listeners[0]));
+
+        // Forward while evictions and readmissions land between datagrams.
+        for _ in 0..30 {
+            h.send(packet.as_bytes());
+            h.turn();
+        }
+        h.run_for(50);
+        for b in h.backends.iter() {
+            while b.recv().is_some() {}
+        }
+
+        h.send(packet.as_bytes());
+        let got = h.recv_all();
+        assert_eq!(got[disabled], None);
+        if let Some(down) = down {
+            assert_eq!(got[down], None);
+        }
+        let lines: usize = got.iter()
+            .map(|g| g.as_ref().map_or(0, |d| d.split(|&b| b == b'\n').count()))
+            .sum();
+        assert_eq!(lines, keys.len());
+    }
+}
+
+fn sanitizing(sanitize: Sanitize) -> Harness {
+    let mut route = route();
+    route.sanitize = sanitize;
+    Harness::with_route((0..2).map(Backend::new).collect(), health(), route)
+}
+
+#[test]
+fn sanitize_replaces_disallowed_name_bytes() {
+    let mut h = sanitizing(Sanitize::Replace);
+
+    // Routed on the cleaned name, which is also what the backend gets.
+    for &(sent, clean) in [(&b"bad\tkey:1|c"[..], &b"bad_key:1|c"[..]),
+                          (&b"caf\xc3\xa9/x:1|c"[..], &b"caf___x:1|c"[..]),
+                          (&b"raw\xff:1|c"[..], &b"raw_:1|c"[..])].iter() {
+        h.send(sent);
+        let got = h.recv_all();
+        let expected = h.expected(&clean[0..clean.iter().position(|x| *x == b':').unwrap()]);
+        assert_eq!(got[expected].as_ref().map(|d| &d[..]), Some(clean));
+    }
+}
+
+#[test]
+fn sanitize_rejects_disallowed_names() {
+    let mut h = sanitizing(Sanitize::Reject);
+
+    let ok = format!("{}:1|c", h.key_for(0));
+    let mut packet = format!("bad key:1|c\n{}\n", ok).into_bytes();
+    packet.extend_from_slice(b"raw\xff:1|c");
+    h.send(&packet);
+    let got = h.recv_all();
+    assert_eq!(got[0], Some(ok.clone().into_bytes()));
+    assert_eq!(got[1], None);
+    assert_eq!(h.stats.rejected_keys.load(Ordering::Relaxed), 2);
+
+    // A newline always ends the line, so it can't smuggle a second metric
+    // into a name: `foo` is malformed on its own and never forwarded.
+    h.send(format!("foo\n{}", ok).as_bytes());
+    let got = h.recv_all();
+    assert_eq!(got[0], Some(ok.into_bytes()));
+    assert_eq!(got[1], None);
+}
+
+#[test]
+fn binary_keys_are_forwarded_or_rejected() {
+    let mut h = Harness::new(1, health());
+    for &packet in [&b"raw\xff\xfe:1|c"[..], &b"caf\xc3:1|c"[..]].iter() {
+        h.send(packet);
+        assert_eq!(h.recv_all()[0].as_ref().map(|d| &d[..]), Some(packet));
+    }
+
+    let mut route = route();
+    route.binary_keys = BinaryKeys::Reject;
+    let mut h = Harness::with_route(vec![Backend::new(0)], health(), route);
+    h.send(b"raw\xff:1|c\ncaf\xc3\xa9:1|c\nbad\xc3(:1|c\nok:2|g\xff");
+    assert_eq!(h.recv_all()[0], Some(b"caf\xc3\xa9:1|c\nok:2|g\xff".to_vec()));
+    assert_eq!(h.stats.binary_keys.load(Ordering::Relaxed), 2);
+
+    let nodes = "nodes:\n  a: {host: 127.0.0.1, port: 8127, adminport: 8128}\n";
+    let config = Config::from_str(&format!("binary_key_policy: reject\n{}", nodes)).unwrap();
+    assert_eq!(config.listeners[0].route.binary_keys, BinaryKeys::Reject);
+    assert!(Config::from_str(&format!("binary_key_policy: drop\n{}", nodes)).is_err());
+}
+
+#[test]
+fn counters_are_downsampled_with_composed_rates() {
+    let mut out = Vec::new();
+    statsd::set_sample_rate(b":1|c", 0.25, &mut out);
+    statsd::set_sample_rate(b":1|c|#a:b|@0.5", 0.125, &mut out);
+    assert_eq!(&out[..], &b":1|c|@0.25:1|c|@0.125|#a:b"[..]);
+    assert_eq!(statsd::parse(b"a:1|c|@0.5").unwrap().rate(), Some(0.5));
+    assert_eq!(statsd::parse(b"a:1|c").unwrap().rate(), Some(1.0));
+    assert_eq!(statsd::parse(b"a:1|c|@2").unwrap().rate(), None);
+
+    let mut route = route();
+    route.counter_sample_factor = 2;
+    route.counter_sample_prefixes = vec![(b"hot.".to_vec(), 4), (b"hot.x.".to_vec(), 1)];
+    let mut h = Harness::with_route(vec![Backend::new(0)], health(), route);
+    h.send(b"a:1|c\na:1|c|@0.5\nhot.a:3|c|@0.5|#t\nhot.a:3|c\nhot.b:1|c\nhot.c:1|c\n\
+             hot.d:1|c\nhot.x.a:1|c\ng:1|g\nbad:1|c|@x");
+    assert_eq!(h.recv_all()[0], Some(b"a:1|c|@0.5\nhot.a:3|c|@0.125|#t\nhot.d:1|c|@0.25\n\
+                                       hot.x.a:1|c\ng:1|g\nbad:1|c|@x".to_vec()));
+    assert_eq!(h.stats.downsampled_counters.load(Ordering::Relaxed), 4);
+
+    let nodes = "nodes:\n  a: {host: 127.0.0.1, port: 8127, adminport: 8128}\n";
+    let config = Config::from_str(&format!("counter_sample_factor: 5\n\
+                                            counter_sample_prefixes: {{hot.: 10}}\n{}", nodes))
+        .unwrap();
+    assert_eq!(config.listeners[0].route.counter_sample_factor, 5);
+    assert_eq!(config.listeners[0].route.counter_sample_prefixes, vec![(b"hot.".to_vec(), 10)]);
+    for yaml in ["counter_sample_factor: 0\n", "counter_sample_prefixes: {hot.: 0}\n",
+                 "counter_sample_prefixes: [hot.]\n"].iter() {
+        assert!(Config::from_str(&format!("{}{}", yaml, nodes)).is_err(), "accepted {:?}", yaml);
+    }
+}
+
+fn rule(pattern: &str, replacement: &str) -> RewriteRule {
+    RewriteRule {
+        pattern: Regex::new(pattern).unwrap(),
+        replacement: replacement.as_bytes().to_vec(),
+    }
+}
+
+#[test]
+fn rewrite_rules_rename_only_the_name() {
+    let mut route = route();
+    route.rewrite_rules = vec![rule(r"^old\.service\.(.+)$", "new.service.$1"),
+                               rule(r"^new\.", "v2."),
+                               rule(r"service", "svc")];
+    assert_eq!(route.rename(b"old.service.hits"), Some(b"new.service.hits".to_vec()));
+    assert_eq!(route.rename(b"new.x"), Some(b"v2.x".to_vec()));
+    assert_eq!(route.rename(b"other.hits"), None);
+    route.rewrite_first_match = false;
+    assert_eq!(route.rename(b"old.service.hits"), Some(b"v2.svc.hits".to_vec()));
+
+    // Hashed and forwarded under the new name; values, types, rates and
+    // tags are left alone even where a pattern would match them.
+    route.prefix = b"p.".to_vec();
+    let backends: Vec<Backend> = (0..3).map(Backend::new).collect();
+    let mut h = Harness::with_route(backends, health(), route);
+    let key = (0..).map(|i| format!("metric.{}", i))
+        .find(|k| h.expected(format!("p.v2.svc.{}", k).as_bytes()) !=
+                  h.expected(format!("p.old.service.{}", k).as_bytes()))
+        .unwrap();
+    let want = h.expected(format!("p.v2.svc.{}", key).as_bytes());
+    h.send(format!("old.service.{}:1|c|@0.5|#service:old", key).as_bytes());
+    let got = h.recv_all();
+    assert_eq!(got[want], Some(format!("p.v2.svc.{}:1|c|@0.5|#service:old", key).into_bytes()));
+    h.send(b"untouched:1|ms");
+    assert!(h.recv_all().iter().any(|d| d.as_ref().map(|d| &d[..]) == Some(b"p.untouched:1|ms")));
+    assert_eq!(h.stats.renamed_keys.load(Ordering::Relaxed), 1);
+
+    let nodes = "nodes:\n  a: {host: 127.0.0.1, port: 8127, adminport: 8128}\n";
+    let config = Config::from_str(&format!("rewrite_rules:\n  - {{match: '^a\\.(.*)', \
+                                            replace: 'b.$1'}}\nrewrite_first_match: false\n{}",
+                                           nodes)).unwrap();
+    let route = &config.listeners[0].route;
+    assert_eq!(route.rename(b"a.x"), Some(b"b.x".to_vec()));
+    assert!(!route.rewrite_first_match);
+    for yaml in ["rewrite_rules: {'^a': b}\n", "rewrite_rules:\n  - {match: '(', replace: b}\n",
+                 "rewrite_rules:\n  - {match: a, replace: 'b:c'}\n",
+                 "rewrite_rules:\n  - {match: a}\n"].iter() {
+        assert!(Config::from_str(&format!("{}{}", yaml, nodes)).is_err(), "accepted {:?}", yaml);
+    }
+}
+
+#[test]
+fn trace_timestamps_tag_every_line() {
+    let mut route = route();
+    route.trace_timestamps = true;
+    route.counter_sample_factor = 2;
+    let mut h = Harness::with_route(vec![Backend::new(0)], health(), route);
+    let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() * 1000;
+    h.send(b"a:1|ms\nb:2|g|#env:prod\nc:1|c|@0.5|#t\nd:1|c\nnot a metric");
+    let got = h.recv_all()[0].clone().unwrap();
+    let after = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() * 1000 + 1000;
+
+    let text = String::from_utf8(got).unwrap();
+    let lines: Vec<&str> = text.split('\n').collect();
+    assert_eq!(lines.len(), 4, "{}", text);
+    let stamp = &lines[0][lines[0].find("proxy_rx_ms:").unwrap() + 12..];
+    let ms: u64 = stamp.parse().unwrap();
+    assert!(before <= ms && ms <= after);
+    assert_eq!(lines, vec![format!("a:1|ms|#proxy_rx_ms:{}", ms),
+                           format!("b:2|g|#env:prod,proxy_rx_ms:{}", ms),
+                           format!("c:1|c|@0.25|#t,proxy_rx_ms:{}", ms),
+                           "not a metric".to_owned()]);
+    for (line, &(name, kind)) in lines.iter().zip([("a", "ms"), ("b", "g"), ("c", "c")].iter()) {
+        let m = statsd::parse(line.as_bytes()).unwrap();
+        assert_eq!((m.name, m.kind), (name.as_bytes(), kind.as_bytes()));
+        assert!(m.tags.unwrap().ends_with(format!("proxy_rx_ms:{}", ms).as_bytes()));
+    }
+}
+
+#[test]
+fn dirty_keys_are_counted_not_rerouted() {
+    let mut route = route();
+    route.dirty_key_check = true;
+    let mut h = Harness::with_route((0..3).map(Backend::new).collect(), health(), route);
+    assert_eq!(statsd::trim(b" \tkey\r "), b"key");
+
+    let name = (0..).map(|i| format!("metric.{}", i))
+        .find(|k| h.expected(k.as_bytes()) != h.expected(format!("{} ", k).as_bytes()))
+        .unwrap();
+    let dirty = format!("{} ", name);
+    let want = h.expected(dirty.as_bytes());
+    assert_eq!(h.forward(format!("{}:1|c", dirty).as_bytes()), Some(want));
+    assert_eq!(h.stats.dirty_key_packets.load(Ordering::Relaxed), 1);
+
+    // Clean names and dirty ones landing on the same node aren't counted.
+    let same = (0..).map(|i| format!("other.{}", i))
+        .find(|k| h.expected(k.as_bytes()) == h.expected(format!("{}\r", k).as_bytes()))
+        .unwrap();
+    h.send(format!("{}:1|c\n{}\r:1|c", name, same).as_bytes());
+    h.recv_all();
+    assert_eq!(h.stats.dirty_key_packets.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn batches_over_the_backend_mtu_are_split_at_lines() {
+    let mut h = Harness::new(1, health());
+    let lines: Vec<String> = (0..120).map(|i| format!("metric.{}:{}|c", i, i)).collect();
+    let packet = lines.join("\n");
+    assert!(packet.len() > 1432);
+    h.send(packet.as_bytes());
+
+    let mut got = Vec::new();
+    for _ in 0..50 {
+        h.turn();
+        while let Some(data) = h.backends[0].recv() {
+            assert!(data.len() <= 1432);
+            got.push(String::from_utf8(data).unwrap());
+        }
+    }
+    assert!(got.len() >= 2);
+    assert_eq!(got.join("\n"), packet);
+    assert_eq!(h.stats.oversize_forwards.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn nodes_without_adminport_stay_in_the_ring_unchecked() {
+    let config = Config::from_str("nodes:\n  a: {host: 127.0.0.1, port: 8127, adminport: 8128}\n  \
+                                   b: {host: 127.0.0.1, port: 8129}\n  \
+                                   c: {host: 127.0.0.1, port: 8131, adminport: 0}\n").unwrap();
+    let ports: Vec<u16> = config.listeners[0].nodes.iter().map(|n| n.adminport).collect();
+    assert_eq!(ports, vec![8128, 0, 0]);
+    assert!(config.summary().contains("b: 127.0.0.1:8129 (unchecked, "));
+    assert!(Config::from_str("nodes:\n  a: {host: 127.0.0.1, port: 8127, \
+                              health_type: http}\n").is_err());
+    assert!(Config::from_str("nodes:\n  a: {host: 127.0.0.1, port: 8127, \
+                              adminport: x}\n").is_err());
+
+    // Its forwards fail and nothing answers checks for it, yet it's kept.
+    // Over TCP so a send to the closed port fails for sure.
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut unchecked = Backend::new(1);
+    unchecked.node = ServerNode::new(1, "127.0.0.1", closed, 0, 20, HealthType::StatsdAdmin,
+                                     None).unwrap();
+    unchecked.node.transport = Transport::Tcp;
+    let mut h = Harness::with_backends(vec![Backend::new(0), unchecked], health());
+    let key = h.key_for(1);
+    for _ in 0..5 {
+        h.send(format!("{}:1|c", key).as_bytes());
+        h.run_for(10);
+    }
+    let node = h.stats.node(&h.backends[1].node.name());
+    assert!(node.forward_errors.load(Ordering::Relaxed) > 0);
+    assert_eq!(node.reconnects.load(Ordering::Relaxed), 0);
+    assert_eq!(node.last_success.load(Ordering::Relaxed), 0);
+    assert_eq!(node.vnodes.load(Ordering::Relaxed), 20);
+    assert_eq!(h.stats.ring_nodes.load(Ordering::Relaxed), 2);
+}
+
+#[test]
+fn reconnects_after_failed_checks_are_counted() {
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut flaky = Backend::new(1);
+    flaky.node.adminport = closed;
+    let mut h = Harness::with_backends(vec![Backend::new(0), flaky], health());
+    h.run_for(50);
+
+    let good = h.stats.node(&h.backends[0].node.name());
+    assert_eq!(good.reconnects.load(Ordering::Relaxed), 0);
+    assert!(good.last_success.load(Ordering::Relaxed) > 0);
+
+    let bad = h.stats.node(&h.backends[1].node.name());
+    assert!(bad.reconnects.load(Ordering::Relaxed) > 0);
+    assert_eq!(bad.last_success.load(Ordering::Relaxed), 0);
+
+    // HTTP checks close the connection after every reply, which isn't churn.
+    let backends = vec![Backend::with_health(0, HealthType::Http)];
+    let mut h = Harness::with_backends(backends, health());
+    h.run_for(50);
+    let node = h.stats.node(&h.backends[0].node.name());
+    assert_eq!(node.reconnects.load(Ordering::Relaxed), 0);
+    assert!(node.last_success.load(Ordering::Relaxed) > 0);
+}
+
+#[test]
+fn health_check_latency_is_exported_as_a_histogram() {
+    let stats = Arc::new(Stats::new());
+    let node = stats.node("b\"1:2");
+    node.record_check_latency(Duration::from_millis(3));
+    node.record_check_latency(Duration::from_millis(2));
+    node.record_check_latency(Duration::from_secs(2));
+    let text = admin::metrics(&[("l".to_owned(), stats)]);
+    let series = "statsd_proxy_health_check_latency_seconds";
+    for want in ["# TYPE statsd_proxy_health_check_latency_seconds histogram",
+                 "_bucket{listener=\"l\",node=\"b\\\"1:2\",le=\"0.001\"} 0",
+                 "_bucket{listener=\"l\",node=\"b\\\"1:2\",le=\"0.005\"} 2",
+                 "_bucket{listener=\"l\",node=\"b\\\"1:2\",le=\"1\"} 2",
+                 "_bucket{listener=\"l\",node=\"b\\\"1:2\",le=\"+Inf\"} 3",
+                 "_sum{listener=\"l\",node=\"b\\\"1:2\"} 2.005",
+                 "_count{listener=\"l\",node=\"b\\\"1:2\"} 3"].iter() {
+        assert!(text.lines().any(|l| l == *want || l == format!("{}{}", series, want)),
+                "no {} in\n{}", want, text);
+    }
+
+    // Answered checks are timed, up or not.
+    let mut h = Harness::new(1, health());
+    h.backends[0].healthy.store(false, Ordering::SeqCst);
+    h.run_for(50);
+    let node = h.stats.node(&h.backends[0].node.name());
+    let answered: usize = node.check_latency.iter().map(|n| n.load(Ordering::Relaxed)).sum();
+    assert!(answered > 0);
+}
+
+#[test]
+fn custom_key_delimiter_routes_on_the_name() {
+    let mut route = route();
+    route.value_delimiter = b'=';
+    let mut h = Harness::with_route((0..2).map(Backend::new).collect(), health(), route);
+
+    for i in 0..2 {
+        let packet = format!("{}=1|c", h.key_for(i));
+        assert_eq!(h.forward(packet.as_bytes()), Some(i));
+    }
+    // Split per line with the same delimiter, and forwarded untouched.
+    let a = format!("{}=1|ms", h.key_for(0));
+    let b = format!("{}=2|ms", h.key_for(1));
+    h.send(format!("{}\n{}", a, b).as_bytes());
+    let got = h.recv_all();
+    assert_eq!(got[0], Some(a.into_bytes()));
+    assert_eq!(got[1], Some(b.into_bytes()));
+}
+
+#[test]
+fn empty_datagram_does_not_stall_the_listener() {
+    let mut h = Harness::new(1, health());
+    h.send(b"");
+    h.run_for(5);
+    assert_eq!(h.stats.empty_packets.load(Ordering::Relaxed), 1);
+    assert_eq!(h.forward(b"metric.a:1|c"), Some(0));
+}
+
+#[test]
+fn level_triggered_listener_keeps_reading() {
+    let server = UdpListener::bind(("127.0.0.1", 0)).unwrap();
+    let mut h = Harness::unstarted(server, vec![Backend::new(0)], health(), route(), forward());
+    h.proxy.set_level_triggered(true);
+    h.start();
+
+    h.send(b"");
+    for i in 0..5 {
+        h.send(format!("metric.{}:1|c", i).as_bytes());
+    }
+
+    let mut got = 0;
+    for _ in 0..50 {
+        h.turn();
+        while h.backends[0].recv().is_some() {
+            got += 1;
+        }
+    }
+    assert_eq!(got, 5);
+}
+
+#[test]
+fn failed_sends_fail_over_to_another_node() {
+    for &retries in [0, 1].iter() {
+        // Sending to the broadcast address without SO_BROADCAST fails at once.
+        let mut dead = Backend::new(1);
+        dead.node = ServerNode::new(1, "255.255.255.255", 9, 0, 20, HealthType::StatsdAdmin,
+                                    None).unwrap();
+        let mut health = health();
+        health.enabled = false;
+        let mut forward = forward();
+        forward.forward_retries = retries;
+        let mut h = Harness::with_config(vec![Backend::new(0), dead], health, route(), forward);
+
+        let packet = format!("{}:1|c", h.key_for(1));
+        h.send(packet.as_bytes());
+        let mut got = None;
+        for _ in 0..20 {
+            h.turn();
+            if let Some(data) = h.backends[0].recv() {
+                got = Some(data);
+            }
+        }
+
+        if retries == 0 {
+            assert_eq!(got, None);
+        } else {
+            assert_eq!(got, Some(packet.into_bytes()));
+        }
+        assert_eq!(h.stats.failover_forwards.load(Ordering::Relaxed), retries);
+    }
+}
+
+#[test]
+fn paused_forwarding_holds_then_replays() {
+    let mut health = health();
+    health.enabled = false;
+    let mut forward = forward();
+    forward.pause_buffer = 2;
+    let mut h = Harness::with_config(vec![Backend::new(0)], health, route(), forward);
+
+    h.stats.paused.store(true, Ordering::SeqCst);
+    for i in 0..3 {
+        h.send(format!("metric.{}:1|c", i).as_bytes());
+    }
+    h.run_for(20);
+    assert_eq!(h.backends[0].recv(), None);
+    assert_eq!(h.stats.paused_drops.load(Ordering::Relaxed), 1);
+
+    // Replayed in order by the next timer tick, with nothing new arriving.
+    h.stats.paused.store(false, Ordering::SeqCst);
+    let mut got = Vec::new();
+    for _ in 0..20 {
+        h.turn();
+        while let Some(data) = h.backends[0].recv() {
+            got.push(data);
+        }
+    }
+    assert_eq!(got, vec![b"metric.0:1|c".to_vec(), b"metric.1:1|c".to_vec()]);
+}
+
+// The records in a capture file, waiting for the writer thread to get
+// them all there.
+fn read_capture(path: &str, want: usize) -> Vec<Record> {
+    let mut records = Vec::new();
+    for _ in 0..200 {
+        records.clear();
+        if let Ok(file) = File::open(path) {
+            let mut input = io::BufReader::new(file);
+            while let Ok(Some(record)) = Record::read_from(&mut input) {
+                records.push(record);
+            }
+        }
+        if records.len() >= want {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    records
+}
+
+#[test]
+fn capture_records_forwards_rotates_and_replays() {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    let path = env::temp_dir().join(format!("statsd-proxy-capture-{}", nanos));
+    let path = path.to_str().unwrap().to_owned();
+
+    let mut health = health();
+    health.enabled = false;
+    let server = UdpListener::bind(("127.0.0.1", 0)).unwrap();
+    let mut h = Harness::unstarted(server, vec![Backend::new(0)], health, route(), forward());
+    let name = h.backends[0].node.name();
+    // Room for two records a file: the third rotates the first out.
+    let config = CaptureConfig {
+        path: path.clone(),
+        max_bytes: 80,
+        files: 2,
+        max_rate: 100,
+    };
+    h.proxy.set_capture(Capture::start(&config).unwrap());
+    h.start();
+
+    for i in 0..3 {
+        h.send(format!("m.{}:1|c", i).as_bytes());
+        h.run_for(5);
+    }
+    drop(h);
+
+    let current = read_capture(&path, 1);
+    assert_eq!(current.iter().map(|r| &r.data[..]).collect::<Vec<_>>(), vec![&b"m.2:1|c"[..]]);
+    assert_eq!(current[0].node, name);
+    let rotated = read_capture(&format!("{}.1", path), 2);
+    assert_eq!(rotated.iter().map(|r| &r.data[..]).collect::<Vec<_>>(),
+               vec![&b"m.0:1|c"[..], &b"m.1:1|c"[..]]);
+    assert!(rotated[0].ts <= rotated[1].ts && rotated[1].ts <= current[0].ts);
+
+    let target = UdpSocket::bind("127.0.0.1:0").unwrap();
+    target.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let stop = AtomicBool::new(false);
+    let sent = capture::replay(&format!("{}.1", path), &target.local_addr().unwrap().to_string(),
+                               &stop).unwrap();
+    assert_eq!(sent, 2);
+    let mut buf = [0; 64];
+    let n = target.recv(&mut buf).unwrap();
+    assert_eq!(&buf[0..n], b"m.0:1|c");
+
+    // Over the rate, datagrams are left out.
+    let capture = Capture::start(&CaptureConfig { max_rate: 1, ..config }).unwrap();
+    assert!(capture.record("a", b"x:1|c"));
+    assert!(!capture.record("a", b"x:1|c"));
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_file(format!("{}.1", path));
+}
+
+#[test]
+fn forward_checksum_sums_what_each_node_was_sent() {
+    assert_eq!(checksum(b""), 0x811c9dc5);
+    assert_eq!(checksum(b"a"), 0xe40c292c);
+
+    let mut health = health();
+    health.enabled = false;
+    let mut forward = forward();
+    forward.checksum = true;
+    let mut h = Harness::with_config(vec![Backend::new(0)], health, route(), forward);
+
+    h.send(b"a:1|c");
+    h.send(b"b:1|c\nc:2|ms");
+    let mut sum = 0u32;
+    for _ in 0..20 {
+        h.turn();
+        while let Some(data) = h.backends[0].recv() {
+            sum = sum.wrapping_add(checksum(&data));
+        }
+    }
+    let node = h.stats.node(&h.backends[0].node.name());
+    assert_eq!(node.forwarded.load(Ordering::Relaxed), 2);
+    assert_eq!(node.sent_checksum.load(Ordering::Relaxed) as u32, sum);
+    assert_eq!(h.stats.checksum_mismatches.load(Ordering::Relaxed), 0);
+
+    let nodes = "nodes:\n  a: {host: 127.0.0.1, port: 8127, adminport: 8128}\n";
+    let config = Config::from_str(&format!("forward_checksum: true\n{}", nodes)).unwrap();
+    assert!(config.listeners[0].forward.checksum);
+}
+
+#[test]
+fn effective_socket_options_are_published() {
+    let server = UdpListener::bind(("127.0.0.1", 0)).unwrap();
+    let asked = server.set_recv_buffer(65536, false).unwrap();
+    let mut health = health();
+    health.enabled = false;
+    let mut h = Harness::unstarted(server, vec![Backend::new(0)], health, route(), forward());
+    h.start();
+
+    let sockets = h.stats.sockets.lock().unwrap();
+    let names: Vec<&str> = sockets.iter().map(|&(name, _)| name).collect();
+    assert_eq!(names, vec!["udp", "forward"]);
+    let udp = sockets[0].1;
+    assert_eq!(udp.recv_buffer, asked);
+    assert!(udp.reuse_port);
+    assert!(udp.send_buffer > 0);
+    assert_eq!(udp.tos, Some(0));
+}
+
+#[test]
+fn failure_weights_by_check_type() {
+    let config = Config::from_str("failure_weights: {statsd_admin: 3}\nnodes:\n  \
+                                   a: {host: 127.0.0.1, port: 1, adminport: 2}\n").unwrap();
+    let health = &config.listeners[0].health;
+    assert_eq!(health.failure_weights, vec![(HealthType::StatsdAdmin, 3)]);
+
+    for yaml in ["failure_weights: {udp: 2}\n", "failure_weights: {http: 0}\n"].iter() {
+        let yaml = format!("{}nodes:\n  a: {{host: 127.0.0.1, port: 1, adminport: 2}}\n", yaml);
+        assert!(Config::from_str(&yaml).is_err(), "accepted {:?}", yaml);
+    }
+
+    // With a weight of 3 against a threshold of 3, one failed check evicts.
+    let mut health = health();
+    health.failure_threshold = 3;
+    health.failure_weights = vec![(HealthType::StatsdAdmin, 3)];
+    let mut h = Harness::new(2, health);
+    let packet = format!("{}:1|c", h.key_for(0));
+    h.backends[0].healthy.store(false, Ordering::SeqCst);
+    h.run_for(50);
+    assert_eq!(h.forward(packet.as_bytes()), Some(1));
+}
+
+#[test]
+fn reloaded_nodes_warm_up_into_the_ring() {
+    let mut health = health();
+    health.warmup = Duration::from_millis(300);
+    let mut h = Harness::new(2, health);
+    let packet = format!("{}:1|c", h.key_for(1));
+
+    let config = Config::from_str(&nodes_yaml(&h.backends, Some(1))).unwrap();
+    h.proxy.reload(&mut h.event_loop, build_nodes(&config.listeners[0]));
+    let config = Config::from_str(&nodes_yaml(&h.backends, None)).unwrap();
+    h.proxy.reload(&mut h.event_loop, build_nodes(&config.listeners[0]));
+    let warm = h.stats.node(&h.backends[1].node.name());
+    assert_eq!(warm.warmup.load(Ordering::Relaxed), 0);
+
+    let start = Instant::now();
+    let mut ramping = false;
+    while warm.warmup.load(Ordering::Relaxed) < 100 {
+        assert!(start.elapsed() < Duration::from_secs(2), "never warmed up");
+        h.turn();
+        let p = warm.warmup.load(Ordering::Relaxed);
+        ramping |= p > 0 && p < 100;
+    }
+    assert!(ramping);
+    assert!(start.elapsed() >= Duration::from_millis(250));
+    assert_eq!(h.forward(packet.as_bytes()), Some(1));
+}
+
+#[test]
+fn json_log_lines() {
+    let nodes = "nodes:\n  a: {host: 127.0.0.1, port: 1, adminport: 2}\n";
+    assert_eq!(Config::from_str(nodes).unwrap().log_format, LogFormat::Text);
+    let config = Config::from_str(&format!("log_format: json\n{}", nodes)).unwrap();
+    assert_eq!(config.log_format, LogFormat::Json);
+    assert!(Config::from_str(&format!("log_format: xml\n{}", nodes)).is_err());
+    assert_eq!(Config::from_str(nodes).unwrap().log_level, Level::Info);
+    let config = Config::from_str(&format!("log_level: debug\n{}", nodes)).unwrap();
+    assert_eq!(config.log_level, Level::Debug);
+    assert!(Config::from_str(&format!("log_level: verbose\n{}", nodes)).is_err());
+    // Nothing in the tests sets the level, so the per datagram lines are off.
+    assert!(!logging::enabled(Level::Debug));
+    assert!(logging::enabled(Level::Warn));
+
+    let line = logging::json_line(Level::Warn, Some("node_down"), Some("10.0.0.1:8127"),
+                                  "node \"a\" is down");
+    assert!(line.starts_with("{\"ts\":"), "{}", line);
+    assert!(line.ends_with(",\"level\":\"warn\",\"event\":\"node_down\",\
+                            \"node\":\"10.0.0.1:8127\",\"msg\":\"node \\\"a\\\" is down\"}"),
+            "{}", line);
+    let line = logging::json_line(Level::Info, None, None, "reloaded");
+    assert!(line.ends_with(",\"level\":\"info\",\"msg\":\"reloaded\"}"), "{}", line);
+}
+
+#[test]
+fn config_includes_merge_in_order() {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    let dir = env::temp_dir().join(format!("statsd-proxy-include-{}", nanos));
+    fs::create_dir_all(&dir).unwrap();
+    let write = |name: &str, yaml: &str| {
+        File::create(dir.join(name)).unwrap().write_all(yaml.as_bytes()).unwrap();
+    };
+    write("base.yml", "threads: 2\nreplicas: 10\nnodes:\n  \
+                       a: {host: 127.0.0.1, port: 1, adminport: 2}\n");
+    write("nodes.yml", "nodes:\n  b: {host: 127.0.0.1, port: 3, adminport: 4}\n");
+    write("main.yml", "include: [base.yml, nodes.yml]\nthreads: 8\n");
+
+    let config = Config::from_file(dir.join("main.yml").to_str().unwrap()).unwrap();
+    assert_eq!(config.threads, 8);
+    let ports: Vec<u16> = config.listeners[0].nodes.iter().map(|n| n.port).collect();
+    assert_eq!(ports, vec![1, 3]);
+    assert!(config.listeners[0].nodes.iter().all(|n| n.replicas == 10));
+
+    write("loop.yml", "include: main.yml\n");
+    write("main.yml", "include: loop.yml\nnodes: {}\n");
+    match Config::from_file(dir.join("main.yml").to_str().unwrap()) {
+        Err(ConfigError::Invalid(msg)) => assert!(msg.starts_with("include cycle"), "{}", msg),
+        other => panic!("cycle accepted: {:?}", other.map(|c| c.threads)),
+    }
+    assert!(Config::from_str("include: base.yml\n").is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn node_tags_are_appended_to_forwarded_lines() {
+    let cases: [(&[u8], &[u8]); 5] = [
+        (b"a:1|c", b"a:1|c|#shard:3"),
+        (b"a:1|c|#env:prod", b"a:1|c|#env:prod,shard:3"),
+        (b"a:1|c|#env:prod|@0.5", b"a:1|c|#env:prod,shard:3|@0.5"),
+        (b"a:1|c|#", b"a:1|c|#shard:3"),
+        (b"garbage", b"garbage"),
+    ];
+    for &(line, want) in cases.iter() {
+        let mut out = Vec::new();
+        statsd::append_tags(line, b"shard:3", &mut out);
+        assert_eq!(&out[..], want, "{}", String::from_utf8_lossy(line));
+    }
+
+    let mut backends: Vec<Backend> = (0..2).map(Backend::new).collect();
+    backends[0].node.tags = b"shard:0".to_vec();
+    let mut h = Harness::with_backends(backends, health());
+    let key = h.key_for(0);
+    h.send(format!("{}:1|c\n{}:2|ms|#env:prod", key, key).as_bytes());
+    let got = h.recv_all();
+    assert_eq!(got[0].as_ref().map(|d| &d[..]),
+               Some(format!("{}:1|c|#shard:0\n{}:2|ms|#env:prod,shard:0", key, key).as_bytes()));
+}
+
+// What arrives on `stream` until it has `want`, or the read times out.
+fn read_stream(stream: &mut net::TcpStream, want: &[u8]) -> Vec<u8> {
+    stream.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    let mut got = Vec::new();
+    let mut buf = [0; 256];
+    while got.len() < want.len() {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => got.extend_from_slice(&buf[0..n]),
+        }
+    }
+    got
+}
+
+#[test]
+fn tcp_nodes_get_framed_lines_and_reconnect() {
+    let mut backend = Backend::new(0);
+    backend.node.transport = Transport::Tcp;
+    let listener = TcpListener::bind(("127.0.0.1", backend.node.port)).unwrap();
+    let name = backend.node.name();
+    let mut health = health();
+    health.enabled = false;
+    let mut h = Harness::with_backends(vec![backend], health);
+
+    h.send(b"a:1|c");
+    h.send(b"b:2|c\nc:3|ms\n");
+    h.run_for(10);
+    let (mut stream, _) = listener.accept().unwrap();
+    assert_eq!(read_stream(&mut stream, b"a:1|c\nb:2|c\nc:3|ms\n"),
+               b"a:1|c\nb:2|c\nc:3|ms\n".to_vec());
+
+    // The backend goes away: once a write fails the connection is dropped,
+    // and reopened by a send after the reconnect delay.
+    drop(stream);
+    let node = h.stats.node(&name);
+    for _ in 0..50 {
+        h.send(b"lost:1|c");
+        h.run_for(2);
+        if node.forward_errors.load(Ordering::Relaxed) > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(node.forward_errors.load(Ordering::Relaxed) > 0);
+    assert_eq!(node.forward_reconnects.load(Ordering::Relaxed), 0);
+
+    thread::sleep(Duration::from_millis(1100));
+    h.send(b"d:4|c");
+    h.run_for(10);
+    let (mut stream, _) = listener.accept().unwrap();
+    assert_eq!(read_stream(&mut stream, b"d:4|c\n"), b"d:4|c\n".to_vec());
+    assert_eq!(node.forward_reconnects.load(Ordering::Relaxed), 1);
+
+    let config = Config::from_str("nodes:\n  n1: {host: 127.0.0.1, port: 8125, adminport: 8126, \
+                                   transport: tcp}\n").unwrap();
+    assert_eq!(config.listeners[0].nodes[0].transport, Transport::Tcp);
+    assert_eq!(build_nodes(&config.listeners[0])[0].transport, Transport::Tcp);
+    assert!(Config::from_str("nodes:\n  n1: {host: 127.0.0.1, port: 8125, adminport: 8126, \
+                              transport: sctp}\n").is_err());
+}
+
+#[test]
+fn unrouted_keys_are_kept_most_recent_first_and_bounded() {
+    let mut lru = UnroutedKeys::default();
+    lru.record(b"a", "malformed");
+    lru.record(b"b", "no_route");
+    lru.record(b"a", "no_route");
+    let recent = lru.recent();
+    let keys: Vec<&[u8]> = recent.iter().map(|&(ref k, _)| &k[..]).collect();
+    assert_eq!(keys, vec![&b"a"[..], &b"b"[..]]);
+    assert_eq!((recent[0].1.reason, recent[0].1.count), ("no_route", 2));
+
+    for i in 0..UNROUTED_KEYS * 3 {
+        lru.record(format!("key.{}", i).as_bytes(), "no_route");
+    }
+    lru.record(&[b'x'; 1000], "malformed");
+    let recent = lru.recent();
+    assert_eq!(recent.len(), UNROUTED_KEYS);
+    assert_eq!(recent[0].0.len(), UNROUTED_KEY_LEN);
+    assert_eq!(recent[1].0, format!("key.{}", UNROUTED_KEYS * 3 - 1).into_bytes());
+    assert!(recent.iter().all(|&(ref k, _)| &k[..] != &b"a"[..]));
+
+    let mut route = route();
+    route.pools = vec!["default".to_owned(), "timers".to_owned()];
+    route.type_pools = vec![(b"ms".to_vec(), 1)];
+    let mut h = Harness::with_route(vec![Backend::new(0)], health(), route);
+    h.send(b"garbage");
+    h.send(b"t.empty_pool:1|ms");
+    h.send(b"ok:1|c");
+    h.recv_all();
+    let body = admin::unrouted_json(&[("l".to_owned(), h.stats.clone())]);
+    assert!(body.starts_with("{\"listeners\":[{\"name\":\"l\",\"keys\":[\
+                              {\"key\":\"t.empty_pool\",\"reason\":\"no_route\",\"count\":1,"),
+            "{}", body);
+    assert!(body.contains("{\"key\":\"garbage\",\"reason\":\"malformed\",\"count\":1,"),
+            "{}", body);
+    assert!(!body.contains("\"ok\""), "{}", body);
+}
+
+#[test]
+fn hash_salt_moves_placement() {
+    let mut route = route();
+    route.hash_salt = b"cluster-b".to_vec();
+    let backends: Vec<Backend> = (0..3).map(Backend::new).collect();
+    let (key, want) = {
+        let mut ring = ConsistentHash::with_vnode_key(VnodeKey::default());
+        for b in backends.iter() {
+            ring.add(&b.node, b.node.replicas);
+        }
+        let index = |node: &ServerNode| backends.iter().position(|b| b.node == *node).unwrap();
+        (0..).map(|i| format!("metric.{}", i))
+            .map(|k| {
+                let salted = index(route.owner(&ring, k.as_bytes()).unwrap());
+                let plain = index(ring.get(k.as_bytes()).unwrap());
+                (k, salted, plain)
+            })
+            .find(|&(_, salted, plain)| salted != plain)
+            .map(|(k, salted, _)| (k, salted))
+            .unwrap()
+    };
+
+    let mut h = Harness::with_route(backends, health(), route);
+    assert_eq!(h.forward(format!("{}:1|c", key).as_bytes()), Some(want));
+}
+
+#[test]
+fn top_keys_find_the_hottest_keys() {
+    let mut top = TopKeys::default();
+    for key in ["a", "a", "b", "a", "c", "a", "a"].iter() {
+        top.record(key.as_bytes(), "n1", 2);
+    }
+    // `c` took over the least counted `b` and its count.
+    let entry = |count, error| TopKey { count: count, error: error, node: "n1".to_owned() };
+    assert_eq!(top.top(5), vec![(b"a".to_vec(), entry(5, 0)), (b"c".to_vec(), entry(2, 1))]);
+
+    let mut route = route();
+    route.track_top_keys = true;
+    let mut h = Harness::with_route(vec![Backend::new(0)], health(), route);
+    for _ in 0..3 {
+        h.send(b"hot:1|c");
+    }
+    h.send(b"cold:1|c\nhot:2|c");
+    h.recv_all();
+    let top = h.stats.top_keys.lock().unwrap().top(1);
+    assert_eq!(top[0].0, b"hot".to_vec());
+    assert_eq!(top[0].1.count, 4);
+    assert_eq!(top[0].1.node, h.backends[0].node.name());
+
+    let nodes = "nodes:\n  a: {host: 127.0.0.1, port: 8127, adminport: 8128}\n";
+    let stats = vec![("default".to_owned(), h.stats.clone())];
+    let config = Config::from_str(nodes).unwrap();
+    assert_eq!(admin::top_keys_json(&config, &stats, 1), None);
+    let config = Config::from_str(&format!("track_top_keys: true\ntop_keys_sample: 2\n{}", nodes))
+        .unwrap();
+    let body = admin::top_keys_json(&config, &stats, 1).unwrap();
+    assert!(body.contains("{\"key\":\"hot\",\"count\":8,\"error\":0,"), "{}", body);
+}
+
+#[test]
+fn self_metrics_report_changes_to_one_node() {
+    let backend = Backend::new(0);
+    let stats = Arc::new(Stats::new());
+    let listeners = vec![("app".to_owned(), stats.clone())];
+    let node = stats.node("127.0.0.1:9001");
+    let mut m = SelfMetrics::new(&backend.node.name(), "proxy.").unwrap();
+
+    stats.udp_packets.store(5, Ordering::Relaxed);
+    node.forwarded.store(3, Ordering::Relaxed);
+    stats.ring_nodes.store(2, Ordering::Relaxed);
+    m.report(&listeners).unwrap();
+    let mut got = None;
+    for _ in 0..50 {
+        got = backend.recv();
+        if got.is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(2));
+    }
+    let got = String::from_utf8(got.unwrap()).unwrap();
+    for line in ["proxy.app.packets_received:5|c", "proxy.app.nodes.127_0_0_1_9001.forwarded:3|c",
+                 "proxy.app.forwarded:3|c", "proxy.app.ring_nodes:2|g"].iter() {
+        assert!(got.lines().any(|l| l == *line), "{} missing from {:?}", line, got);
+    }
+
+    // Counters are reported as the change since the last report.
+    stats.udp_packets.store(7, Ordering::Relaxed);
+    let lines = m.lines(&listeners);
+    assert!(lines.contains(&"proxy.app.packets_received:2|c".to_owned()));
+    assert!(lines.contains(&"proxy.app.forwarded:0|c".to_owned()));
+    assert!(lines.contains(&"proxy.app.nodes.127_0_0_1_9001.queue_depth:0|g".to_owned()));
+}
+
+#[test]
+fn queue_depth_max_resets_when_read() {
+    let node = NodeStats::default();
+    node.queue_grew(3);
+    node.queue_grew(2);
+    node.queue_depth.fetch_sub(4, Ordering::Relaxed);
+    assert_eq!(node.queue_depth.load(Ordering::Relaxed), 1);
+    assert_eq!(node.take_queue_depth_max(), 5);
+    // The next watermark starts from what is queued now.
+    assert_eq!(node.take_queue_depth_max(), 1);
+    node.queue_grew(1);
+    assert_eq!(node.take_queue_depth_max(), 2);
+}
+
+#[test]
+fn colliding_vnodes_go_to_the_first_name() {
+    // Without the port every vnode of two nodes on one host collides.
+    let vnode_key = VnodeKey::parse("{host}-{i}").unwrap();
+    let nodes: Vec<ServerNode> = (0..2).map(|i| Backend::new(i).node).collect();
+    let mut names: Vec<String> = nodes.iter().map(|n| n.name()).collect();
+    names.sort();
+    let first = names[0].clone();
+    for order in [[0, 1], [1, 0]].iter() {
+        let mut ring = ConsistentHash::with_vnode_key(vnode_key.clone());
+        for &i in order.iter() {
+            ring.add(&nodes[i], nodes[i].replicas);
+        }
+        for i in 0..50 {
+            assert_eq!(ring.get(format!("metric.{}", i).as_bytes()).unwrap().name(), first);
+        }
+
+        let winner = nodes.iter().find(|n| n.name() == first).unwrap();
+        ring.remove(winner);
+        for i in 0..50 {
+            assert!(ring.get(format!("metric.{}", i).as_bytes()).unwrap().name() != first);
+        }
+    }
+}
+
+#[test]
+fn vnode_key_formats_place_vnodes() {
+    assert_eq!(VnodeKey::default().key("10.0.0.1:8125", 0), "10.0.0.1:8125:0");
+    let dashed = VnodeKey::parse("{host}-{port}-{i}").unwrap();
+    assert_eq!(dashed.key("10.0.0.1:8125", 3), "10.0.0.1-8125-3");
+    for bad in ["{name}", "node-{i}", "{name}:{x}", "{name}:{i"].iter() {
+        assert!(VnodeKey::parse(bad).is_err(), "accepted {:?}", bad);
+    }
+
+    // A key belongs to the first vnode point at or after its md5, wrapping.
+    let nodes: Vec<ServerNode> = (0..3).map(|i| Backend::new(i).node).collect();
+    let owner = |vnode_key: &VnodeKey, key: &str| {
+        let mut points: Vec<(Vec<u8>, usize)> = nodes.iter()
+            .flat_map(|n| (0..n.replicas).map(move |i| (n, i)))
+            .map(|(n, i)| (md5::compute(vnode_key.key(&n.name(), i).as_bytes()).to_vec(), n.id))
+            .collect();
+        points.sort();
+        let hashed = md5::compute(key.as_bytes()).to_vec();
+        points.iter().find(|p| p.0 >= hashed).unwrap_or(&points[0]).1
+    };
+
+    let mut moved = 0;
+    for vnode_key in [VnodeKey::default(), dashed].iter() {
+        let mut ring = ConsistentHash::with_vnode_key(vnode_key.clone());
+        for n in nodes.iter() {
+            ring.add(n, n.replicas);
+        }
+        for i in 0..200 {
+            let key = format!("metric.{}", i);
+            assert_eq!(ring.get(key.as_bytes()).unwrap().id, owner(vnode_key, &key));
+            if owner(vnode_key, &key) != owner(&VnodeKey::default(), &key) {
+                moved += 1;
+            }
+        }
+    }
+    assert!(moved > 0);
+
+    let config = Config::from_str("vnode_key: \"{host}-{port}-{i}\"\nnodes:\n  \
+                                   a: {host: 127.0.0.1, port: 8127, adminport: 8128}\n").unwrap();
+    assert_eq!(config.listeners[0].route.vnode_key.format(), "{host}-{port}-{i}");
+}
+
+// Accepts from an edge triggered listener the way a readiness handler has to.
+struct Acceptor {
+    listener: socket::TcpListener,
+    streams: Vec<socket::TcpStream>,
+}
+
+impl mio::Handler for Acceptor {
+    type Timeout = ();
+    type Message = ();
+
+    fn ready(&mut self, _: &mut mio::EventLoop<Acceptor>, _: mio::Token, _: mio::EventSet) {
+        self.listener.accept_pending(&mut self.streams).unwrap();
+    }
+}
+
+#[test]
+fn accept_pending_drains_the_backlog() {
+    let listener = socket::TcpListener::bind(("127.0.0.1", 0), 16).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut event_loop = mio::EventLoop::new().unwrap();
+    event_loop.register_opt(&listener, mio::Token(0), mio::EventSet::readable(),
+                            mio::PollOpt::edge()).unwrap();
+    let mut acceptor = Acceptor { listener: listener, streams: Vec::new() };
+
+    // Queued before the loop runs, they come in as one edge: the wakeup has
+    // to take them all, nothing signals the rest later.
+    let mut clients: Vec<_> = (0..5).map(|_| net::TcpStream::connect(addr).unwrap()).collect();
+    event_loop.run_once(&mut acceptor, Some(100)).unwrap();
+    assert_eq!(acceptor.streams.len(), clients.len());
+    assert!(acceptor.listener.accept().unwrap().is_none());
+
+    // A later batch raises a new edge.
+    clients.extend((0..3).map(|_| net::TcpStream::connect(addr).unwrap()));
+    event_loop.run_once(&mut acceptor, Some(100)).unwrap();
+    assert_eq!(acceptor.streams.len(), clients.len());
+}
+
+#[test]
+fn route_lookup_matches_forwarding() {
+    let mut h = Harness::new(3, health());
+    let key = h.key_for(2);
+    let owner = h.backends[2].node.name();
+    assert_eq!(h.proxy.route_for(&key), Some(owner.clone()));
+    assert_eq!(h.proxy.route_for(&format!("{}:1|c", key)), Some(owner.clone()));
+    assert_eq!(h.forward(format!("{}:1|c", key).as_bytes()), Some(2));
+
+    let config = Config::from_str(&nodes_yaml(&h.backends, None)).unwrap();
+    let stats = vec![("default".to_owned(), h.stats.clone())];
+    let body = admin::route_json(&config, &stats, &key);
+    assert!(body.contains(&format!("\"node\":{{\"name\":\"b2\",\"addr\":\"{}\"}}", owner)),
+            "{}", body);
+
+    // Out of the ring it is no longer routed to.
+    h.backends[2].healthy.store(false, Ordering::SeqCst);
+    h.run_for(50);
+    assert!(h.proxy.route_for(&key) != Some(owner.clone()));
+    let body = admin::route_json(&config, &stats, &key);
+    assert!(!body.contains(&owner), "{}", body);
+
+    // Looked up on the sanitized name, which is what is forwarded.
+    let mut route = route();
+    route.sanitize = Sanitize::Replace;
+    let mut h = Harness::with_route((0..3).map(Backend::new).collect(), health(), route);
+    let i = (0..).find(|i| h.expected(format!("bad key.{}", i).as_bytes()) !=
+                           h.expected(format!("bad_key.{}", i).as_bytes())).unwrap();
+    let owner = h.expected(format!("bad_key.{}", i).as_bytes());
+    assert_eq!(h.proxy.route_for(&format!("bad key.{}:1|c", i)),
+               Some(h.backends[owner].node.name()));
+    h.send(format!("bad key.{}:1|c", i).as_bytes());
+    let got = h.recv_all();
+    assert_eq!(got[owner], Some(format!("bad_key.{}:1|c", i).into_bytes()));
+}
+
+#[test]
+fn ewma_scoring_evicts_and_readmits() {
+    let nodes = "nodes:\n  a: {host: 127.0.0.1, port: 1, adminport: 2}\n";
+    let config = Config::from_str(&format!("health_scoring: ewma\newma_alpha: 0.5\n{}", nodes))
+        .unwrap();
+    assert_eq!(config.listeners[0].health.score_alpha, 0.5);
+    assert_eq!(config.listeners[0].health.score_eviction, Some((0.5, 0.9)));
+    for yaml in ["health_scoring: ewma\newma_evict_below: 0.9\newma_readmit_above: 0.5\n",
+                 "ewma_alpha: 0\n", "health_scoring: average\n"].iter() {
+        assert!(Config::from_str(&format!("{}{}", yaml, nodes)).is_err(), "accepted {:?}", yaml);
+    }
+
+    let mut health = health();
+    health.score_alpha = 0.5;
+    health.score_eviction = Some((0.3, 0.9));
+    let mut h = Harness::new(2, health);
+    let packet = format!("{}:1|c", h.key_for(0));
+    let score = h.stats.node(&h.backends[0].node.name());
+    assert_eq!(score.score.load(Ordering::Relaxed), 1000);
+
+    h.backends[0].healthy.store(false, Ordering::SeqCst);
+    h.run_for(50);
+    assert!(score.score.load(Ordering::Relaxed) < 300);
+    assert_eq!(h.forward(packet.as_bytes()), Some(1));
+
+    h.backends[0].healthy.store(true, Ordering::SeqCst);
+    h.run_for(100);
+    assert!(score.score.load(Ordering::Relaxed) >= 900);
+    assert_eq!(h.forward(packet.as_bytes()), Some(0));
+}
+
+#[test]
+fn ring_changes_are_recorded_once_with_before_and_after() {
+    let mut h = Harness::new(2, health());
+    let names: Vec<String> = h.backends.iter().map(|b| b.node.name()).collect();
+    let mut all = names.clone();
+    all.sort();
+    h.run_for(10);
+    assert_eq!(h.stats.ring_events.lock().unwrap().len(), 1);
+
+    h.backends[0].healthy.store(false, Ordering::SeqCst);
+    h.run_for(50);
+    h.backends[0].healthy.store(true, Ordering::SeqCst);
+    h.run_for(50);
+
+    let events: Vec<RingEvent> = h.stats.ring_events.lock().unwrap().iter().cloned().collect();
+    assert_eq!(events.iter().map(|e| e.reason).collect::<Vec<_>>(),
+               vec!["startup", "health", "health"]);
+    assert_eq!(events[0].after, all);
+    assert_eq!(events[1].removed(), vec![&names[0]]);
+    assert_eq!(events[1].after, vec![names[1].clone()]);
+    assert_eq!(events[2].added(), vec![&names[0]]);
+    assert_eq!(events[2].after, all);
+
+    // A second worker publishing the same ring adds nothing.
+    assert_eq!(h.stats.record_ring("health", all.clone()), None);
+    let body = admin::events_json(&[("l".to_owned(), h.stats.clone())]);
+    assert!(body.starts_with("{\"listeners\":[{\"name\":\"l\",\"events\":[{\"ts\":"), "{}", body);
+    assert!(body.contains(&format!("\"reason\":\"health\",\"added\":[],\"removed\":[\"{}\"]",
+                                   names[0])), "{}", body);
+
+    let stats = Stats::new();
+    for i in 0..RING_EVENTS + 5 {
+        stats.record_ring("reload", vec![i.to_string()]);
+    }
+    let events = stats.ring_events.lock().unwrap();
+    assert_eq!(events.len(), RING_EVENTS);
+    assert_eq!(events[0].before, vec!["4".to_owned()]);
+}
+
+#[test]
+fn below_min_healthy_fraction_drops_everything() {
+    let nodes = "nodes:\n  a: {host: 127.0.0.1, port: 1, adminport: 2}\n";
+    let config = Config::from_str(&format!("min_healthy_fraction: 0.75\n{}", nodes)).unwrap();
+    assert_eq!(config.listeners[0].health.min_healthy_fraction, Some(0.75));
+    for yaml in ["min_healthy_fraction: 1.5\n", "min_healthy_fraction: 0\n"].iter() {
+        assert!(Config::from_str(&format!("{}{}", yaml, nodes)).is_err(), "accepted {:?}", yaml);
+    }
+
+    let mut health = health();
+    health.min_healthy_fraction = Some(0.75);
+    let mut h = Harness::new(2, health);
+    let packet = format!("{}:1|c", h.key_for(1));
+    assert_eq!(h.forward(packet.as_bytes()), Some(1));
+
+    h.backends[0].healthy.store(false, Ordering::SeqCst);
+    h.run_for(50);
+    assert!(h.stats.below_min_healthy.load(Ordering::Relaxed));
+    assert_eq!(h.forward(packet.as_bytes()), None);
+    assert_eq!(h.stats.min_healthy_drops.load(Ordering::Relaxed), 1);
+
+    h.backends[0].healthy.store(true, Ordering::SeqCst);
+    h.run_for(50);
+    assert!(!h.stats.below_min_healthy.load(Ordering::Relaxed));
+    assert_eq!(h.forward(packet.as_bytes()), Some(1));
+}
+
+#[test]
+fn mtu_discover_is_set_on_forwarding_sockets() {
+    let sock = Socket::new(AddressFamily::Inet, SockType::Datagram, true).unwrap();
+    for &mode in [MtuDiscover::Dont, MtuDiscover::Want, MtuDiscover::Do].iter() {
+        sock.set_mtu_discover(mode).unwrap();
+        assert_eq!(sock.mtu_discover().unwrap(), Some(mode));
+        assert_eq!(sock.options().unwrap().mtu_discover, Some(mode));
+    }
+
+    let nodes = "nodes:\n  a: {host: 127.0.0.1, port: 1, adminport: 2}\n";
+    let config = Config::from_str(&format!("mtu_discover: do\n{}", nodes)).unwrap();
+    assert_eq!(config.listeners[0].forward.mtu_discover, Some(MtuDiscover::Do));
+    assert!(Config::from_str(&format!("mtu_discover: probe\n{}", nodes)).is_err());
+}
+
+#[test]
+fn env_expansion_keeps_the_document_shape() {
+    env::set_var("STATSD_PROXY_TEST_TOKEN", "s3cr3t: #x, {y}");
+    let nodes = "nodes:\n  a: {host: 127.0.0.1, port: 1, adminport: 2}\n";
+
+    // Nothing is expanded in comments, but a quoted `#` is not one.
+    let config = Config::from_str(&format!("admin_token: 'a #${{STATSD_PROXY_TEST_PORT:-b}}' \
+                                            # ${{STATSD_PROXY_TEST_UNSET}}\n{}", nodes))
+        .unwrap();
+    assert_eq!(config.admin_token, Some("a #b".to_owned()));
+
+    let config = Config::from_str(&format!("admin_token: ${{STATSD_PROXY_TEST_TOKEN}}\n{}",
+                                           nodes))
+        .unwrap();
+    assert_eq!(config.admin_token, Some("s3cr3t: #x, {y}".to_owned()));
+    for yaml in &["admin_token: x${STATSD_PROXY_TEST_TOKEN}\n",
+                  "admin_token: ${STATSD_PROXY_TEST_TOKEN} x\n",
+                  "admin_token: [${STATSD_PROXY_TEST_TOKEN}]\n"] {
+        assert!(Config::from_str(&format!("{}{}", yaml, nodes)).is_err(), "accepted {:?}", yaml);
+    }
+}